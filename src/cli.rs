@@ -0,0 +1,293 @@
+use std::path::PathBuf;
+
+use rustikgb::hardware::video::gpu_constants::palette_by_name;
+use rustikgb::hardware::video::gpu_constants::PALETTE_NAMES;
+
+pub const DEFAULT_BIOS_PATH: &'static str = "assets/BIOS.gb";
+
+pub const USAGE: &'static str = "Usage: RustikGB [OPTIONS] <ROM>
+
+Options:
+    --bios <file>           Boot ROM to run before the cartridge (default: assets/BIOS.gb if present)
+    --no-bios               Start straight at the cartridge entry point
+    --scale <n>             Window scale factor (default: 2)
+    --keymap <file>         Key bindings to use (default: keys.cfg if present)
+    --palette <name>        Screen colors: original, gray or pinku
+    --block-opposing        Ignore Left+Right and Up+Down pressed at the same time
+    --turbo-rate <n>        Frames a turbo button stays pressed and then released (default: 2)
+    --macros <file>         Input macros that can be bound to keys with macro:<name>
+    --rewind <seconds>      How far back the rewind key can go, 0 turns it off (default: 60)
+    --headless              Run without a window, needs --frames or --play
+    --frames <n>            Number of frames to run in headless mode
+    --screenshot <file>     Save the last frame to a PNG after a headless run
+    --screenshot-scale <n>  Scale factor for screenshots (default: 1)
+    --video <file>          Record the emulated frames to an animated GIF
+    --video-step <n>        Only capture every nth frame (default: 1)
+    --video-keep-duplicates Store repeated frames instead of lengthening the previous one
+    --debug                 Stop in the debugger before the first instruction
+    --gdb <port>            Wait for GDB on localhost:<port> and let it drive the debugger
+    --trace <file>          Log every instruction in gameboy-doctor format
+    --trace-last <n>        Only keep the last n instructions of --trace, written on a panic or breakpoint
+    --serial-test           Run a test ROM that reports through the serial port
+    --link-host <addr>      Wait for another emulator on host:port or unix:/path
+    --link-join <addr>      Connect to an emulator started with --link-host
+    --printer <dir>         Plug in a Game Boy Printer that saves to <dir>
+    --record <file>         Record the joypad from power on into a movie file
+    --play <file>           Play back a movie recorded with --record
+    -h, --help              Show this message";
+
+pub struct Options {
+    pub rom: PathBuf,
+    // None means the default boot ROM, if there is one
+    pub bios: Option<PathBuf>,
+    pub no_bios: bool,
+    pub scale: u32,
+    // None means the default bindings file, if there is one
+    pub keymap: Option<PathBuf>,
+    pub palette: Option<[[u8; 4]; 4]>,
+    pub block_opposing: bool,
+    pub turbo_rate: Option<u32>,
+    pub macros: Option<PathBuf>,
+    pub rewind_seconds: u32,
+    pub headless: bool,
+    pub frames: Option<u32>,
+    pub screenshot: Option<PathBuf>,
+    pub screenshot_scale: u32,
+    pub video: Option<PathBuf>,
+    pub video_step: u32,
+    pub video_keep_duplicates: bool,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    pub trace: Option<PathBuf>,
+    pub trace_last: Option<u32>,
+    pub serial_test: bool,
+    pub link_host: Option<String>,
+    pub link_join: Option<String>,
+    pub printer: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub play: Option<PathBuf>,
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        bios: None,
+        no_bios: false,
+        scale: 2,
+        keymap: None,
+        palette: None,
+        block_opposing: false,
+        turbo_rate: None,
+        macros: None,
+        rewind_seconds: 60,
+        headless: false,
+        frames: None,
+        screenshot: None,
+        screenshot_scale: 1,
+        video: None,
+        video_step: 1,
+        video_keep_duplicates: false,
+        debug: false,
+        gdb_port: None,
+        trace: None,
+        trace_last: None,
+        serial_test: false,
+        link_host: None,
+        link_join: None,
+        printer: None,
+        record: None,
+        play: None,
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-h" | "--help" => {return Ok(Command::Help);}
+            "--bios"        => {options.bios = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--no-bios"     => {options.no_bios = true;}
+            "--scale"       => {
+                options.scale = number_of(&arg, &mut args)?;
+                if options.scale == 0 {
+                    return Err(String::from("--scale must be at least 1"));
+                }
+            }
+            "--keymap"      => {options.keymap = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--palette"     => {
+                let name = value_of(&arg, &mut args)?;
+                options.palette = Some(palette_by_name(&name).ok_or_else(|| {
+                    format!("unknown palette '{}', try one of: {}", name, PALETTE_NAMES.join(", "))
+                })?);
+            }
+            "--block-opposing" => {options.block_opposing = true;}
+            "--turbo-rate"  => {
+                let rate = number_of(&arg, &mut args)?;
+                if rate == 0 {
+                    return Err(String::from("--turbo-rate must be at least 1"));
+                }
+                options.turbo_rate = Some(rate);
+            }
+            "--macros"      => {options.macros = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--rewind"      => {options.rewind_seconds = number_of(&arg, &mut args)?;}
+            "--headless"    => {options.headless = true;}
+            "--frames"      => {options.frames = Some(number_of(&arg, &mut args)?);}
+            "--screenshot"  => {options.screenshot = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--screenshot-scale" => {
+                options.screenshot_scale = number_of(&arg, &mut args)?;
+                if options.screenshot_scale == 0 {
+                    return Err(String::from("--screenshot-scale must be at least 1"));
+                }
+            }
+            "--video"       => {options.video = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--video-step"  => {
+                options.video_step = number_of(&arg, &mut args)?;
+                if options.video_step == 0 {
+                    return Err(String::from("--video-step must be at least 1"));
+                }
+            }
+            "--video-keep-duplicates" => {options.video_keep_duplicates = true;}
+            "--debug"       => {options.debug = true;}
+            "--gdb"         => {
+                let port = number_of(&arg, &mut args)?;
+                if port == 0 || port > 0xFFFF {
+                    return Err(format!("--gdb expects a port between 1 and 65535, got {}", port));
+                }
+                options.gdb_port = Some(port as u16);
+            }
+            "--trace"       => {options.trace = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--trace-last"  => {
+                let length = number_of(&arg, &mut args)?;
+                if length == 0 {
+                    return Err(String::from("--trace-last must be at least 1"));
+                }
+                options.trace_last = Some(length);
+            }
+            "--serial-test" => {options.serial_test = true;}
+            "--link-host"   => {options.link_host = Some(value_of(&arg, &mut args)?);}
+            "--link-join"   => {options.link_join = Some(value_of(&arg, &mut args)?);}
+            "--printer"     => {options.printer = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--record"      => {options.record = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            "--play"        => {options.play = Some(PathBuf::from(value_of(&arg, &mut args)?));}
+            _ => {
+                if arg.starts_with("-") {
+                    return Err(format!("unknown option '{}'", arg));
+                }
+                if rom.is_some() {
+                    return Err(format!("only one ROM can be given, got an extra '{}'", arg));
+                }
+                rom = Some(PathBuf::from(arg));
+            }
+        }
+    }
+
+    options.rom = rom.ok_or_else(|| String::from("no ROM given"))?;
+    if options.no_bios && options.bios.is_some() {
+        return Err(String::from("--bios and --no-bios can't be used together"));
+    }
+    if options.headless && options.frames.is_none() && !options.serial_test && options.play.is_none() {
+        return Err(String::from("--headless needs --frames <n>"));
+    }
+    if options.record.is_some() && options.play.is_some() {
+        return Err(String::from("--record and --play can't be used together"));
+    }
+    if options.screenshot.is_some() && !options.headless {
+        return Err(String::from("--screenshot only works together with --headless"));
+    }
+    if options.trace_last.is_some() && options.trace.is_none() {
+        return Err(String::from("--trace-last needs --trace <file>"));
+    }
+    let serial_devices = [options.link_host.is_some(), options.link_join.is_some(), options.printer.is_some()];
+    if serial_devices.iter().filter(|used| **used).count() > 1 {
+        return Err(String::from("only one of --link-host, --link-join and --printer can be used"));
+    }
+    Ok(Command::Run(options))
+}
+
+fn value_of<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", option))
+}
+
+fn number_of<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<u32, String> {
+    let value = value_of(option, args)?;
+    value.parse().map_err(|_| format!("{} expects a number, got '{}'", option, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::parse;
+    use super::Command;
+    use super::Options;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    fn options(line: &str) -> Options {
+        match parse_line(line) {
+            Ok(Command::Run(options)) => options,
+            Ok(Command::Help) => panic!("'{}' asked for help", line),
+            Err(e) => panic!("'{}': {}", line, e)
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match parse_line(line) {
+            Err(e) => e,
+            Ok(_) => panic!("'{}' parsed", line)
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let options = options("game.gb");
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.scale, 2);
+        assert_eq!(options.rewind_seconds, 60);
+        assert!(options.bios.is_none() && !options.no_bios && !options.headless);
+    }
+
+    #[test]
+    fn options_go_anywhere_around_the_rom() {
+        let parsed = options("--scale 3 game.gb --headless --frames 100 --screenshot out.png --palette gray");
+        assert_eq!(parsed.rom, PathBuf::from("game.gb"));
+        assert_eq!(parsed.scale, 3);
+        assert_eq!(parsed.frames, Some(100));
+        assert_eq!(parsed.screenshot, Some(PathBuf::from("out.png")));
+        assert!(parsed.palette.is_some());
+        assert_eq!(options("game.gb --gdb 2345").gdb_port, Some(2345));
+        match parse_line("game.gb --scale 0 -h") {
+            Err(_) => {}
+            _ => panic!("options are read in order")
+        }
+        match parse_line("game.gb -h") {
+            Ok(Command::Help) => {}
+            _ => panic!("-h asks for help")
+        }
+    }
+
+    #[test]
+    fn bad_command_lines() {
+        assert_eq!(error(""), "no ROM given");
+        assert_eq!(error("a.gb b.gb"), "only one ROM can be given, got an extra 'b.gb'");
+        assert_eq!(error("game.gb --fast"), "unknown option '--fast'");
+        assert_eq!(error("game.gb --scale"), "--scale needs a value");
+        assert_eq!(error("game.gb --scale big"), "--scale expects a number, got 'big'");
+        assert_eq!(error("game.gb --scale 0"), "--scale must be at least 1");
+        assert_eq!(error("game.gb --gdb 70000"), "--gdb expects a port between 1 and 65535, got 70000");
+        assert!(error("game.gb --palette neon").starts_with("unknown palette 'neon', try one of: "));
+        assert_eq!(error("game.gb --bios b.bin --no-bios"), "--bios and --no-bios can't be used together");
+        assert_eq!(error("game.gb --headless"), "--headless needs --frames <n>");
+        assert_eq!(error("game.gb --screenshot a.png"), "--screenshot only works together with --headless");
+        assert_eq!(error("game.gb --record a.rgbm --play b.rgbm"), "--record and --play can't be used together");
+        assert_eq!(error("game.gb --trace-last 10"), "--trace-last needs --trace <file>");
+        assert_eq!(error("game.gb --printer out --link-join localhost:2000"),
+                   "only one of --link-host, --link-join and --printer can be used");
+    }
+}
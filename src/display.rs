@@ -0,0 +1,52 @@
+use piston_window::*;
+use texture::*;
+
+use rustikgb::hardware::video::screen::Screen;
+use rustikgb::hardware::video::screen::SCREEN_DIMS;
+
+/// Presents the emulated `Screen` in a piston window.
+pub struct Display {
+    texture: G2dTexture,
+    scale: f64,
+}
+
+impl Display {
+    pub fn new(window: &mut PistonWindow, screen: &Screen, scale: u32) -> Self {
+        let ts = TextureSettings::new().filter(Filter::Nearest).compress(false).generate_mipmap(false);
+        let texture = Texture::create(
+            &mut window.factory,
+            Format::Rgba8,
+            screen.framebuffer(),
+            SCREEN_DIMS,
+            &ts
+        ).unwrap_or_else(|err|{panic!("Failed to create the screen texture: {}", err)});
+
+        Display {
+            texture: texture,
+            scale: scale as f64,
+        }
+    }
+
+    pub fn update(&mut self, window: &mut PistonWindow, evt: Event, screen: &Screen) {
+        if !screen.is_on() {
+            UpdateTexture::update(
+                &mut self.texture,
+                &mut window.encoder, 
+                Format::Rgba8,
+                screen.framebuffer(),
+                [0; 2],
+                SCREEN_DIMS
+            ).unwrap();    
+
+            let texture = &self.texture;
+            let scale = self.scale;
+            window.draw_2d(&evt, |c, g| { 
+                image(texture, c.transform.scale(scale, scale), g);
+            });
+        } else {
+            window.draw_2d(&evt, |c, g| { 
+                clear([0.0, 0.0, 0.0, 1.0], g);
+            });
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use hardware::cpu::CPU;
+use hardware::cartridge::Cartridge;
+use hardware::debugger::Debugger;
+use hardware::debugger::symbols::SymbolTable;
+use hardware::debugger::watchpoints::Watchpoints;
+use hardware::instructions::InstructionSet;
+use hardware::joypad::GbButton;
+use hardware::memory::bus::BUS;
+use hardware::serial::SerialDevice;
+use hardware::test_runner;
+use hardware::test_runner::TestResult;
+use hardware::video::screen::Screen;
+use hardware::state::*;
+use rewind::RewindBuffer;
+use rewind::RewindConfig;
+use movie::Movie;
+use movie::MovieStart;
+use input::InputLayer;
+use input::InputMacro;
+use recorder::GifRecorder;
+use recorder::RecorderConfig;
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub const BOOT_ROM_SIZE: usize = 0x100;
+
+/// Entry point for embedding the emulator.
+/// Owns the whole machine plus the instruction set and debugger that drive it.
+pub struct GameBoy {
+    cpu: CPU,
+    debugger: Debugger,
+    instructions: InstructionSet<'static>,
+    // Without a boot ROM the machine starts straight at the cartridge entry point
+    boot_rom: Option<Box<[u8]>>,
+    rewind: Option<RewindBuffer>,
+    input: InputLayer,
+    recording: Option<Movie>,
+    playback: Option<Playback>,
+    video: Option<GifRecorder>,
+}
+
+struct Playback {
+    movie: Movie,
+    position: usize,
+}
+
+impl GameBoy {
+    pub fn new(boot_rom: Option<Box<[u8]>>, rom: &[u8]) -> Result<Self, String> {
+        if let Some(ref data) = boot_rom {
+            if data.len() != BOOT_ROM_SIZE {
+                return Err(format!("the boot ROM should be {} bytes long, but it is {} bytes", BOOT_ROM_SIZE, data.len()));
+            }
+        }
+        Cartridge::check_rom(rom)?;
+        Ok(GameBoy {
+            cpu: power_on(&boot_rom, rom),
+            debugger: Debugger::new(),
+            instructions: InstructionSet::new(),
+            boot_rom: boot_rom,
+            rewind: None,
+            input: InputLayer::new(),
+            recording: None,
+            playback: None,
+            video: None,
+        })
+    }
+
+    /// Swaps the cartridge and powers the machine back on.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), String> {
+        Cartridge::check_rom(rom)?;
+        self.replace_machine(rom);
+        Ok(())
+    }
+
+    /// Powers the machine off and on again with the same cartridge.
+    pub fn reset(&mut self) {
+        let rom = self.cartridge().data().to_vec();
+        self.replace_machine(&rom);
+    }
+
+    // Frontend settings and the serial device survive, the history doesn't
+    fn replace_machine(&mut self, rom: &[u8]) {
+        let palette = self.cpu.bus.gpu.palette();
+        let block_opposing = self.cpu.bus.joypad.blocks_opposing_directions();
+        let device = self.cpu.bus.serial.disconnect();
+        let watchpoints = mem::replace(&mut self.cpu.bus.watchpoints, Watchpoints::new());
+        self.cpu = power_on(&self.boot_rom, rom);
+        self.cpu.bus.watchpoints = watchpoints;
+        self.cpu.bus.gpu.set_palette(palette);
+        self.cpu.bus.joypad.set_block_opposing_directions(block_opposing);
+        if let Some(device) = device {
+            self.cpu.bus.serial.connect(device);
+        }
+        if let Some(ref mut history) = self.rewind {
+            history.clear();
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        let mut movie_ended = false;
+        if let Some(ref mut playback) = self.playback {
+            if let Some(mask) = playback.movie.frames.get(playback.position) {
+                self.cpu.bus.joypad.set_buttons(*mask);
+                playback.position += 1;
+            }
+            movie_ended = playback.position >= playback.movie.len();
+        } else {
+            let buttons = self.input.next_frame();
+            self.cpu.bus.joypad.set_buttons(buttons);
+        }
+        if let Some(ref mut movie) = self.recording {
+            movie.frames.push(self.cpu.bus.joypad.buttons());
+        }
+
+        if self.rewind.is_some() {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        }
+        self.cpu.run_frame(&mut self.debugger, &self.instructions);
+
+        let mut video_failed = false;
+        if let Some(ref mut video) = self.video {
+            if let Err(e) = video.add_frame(self.cpu.bus.screen.framebuffer()) {
+                eprintln!("Error: {}, the video recording stopped", e);
+                video_failed = true;
+            }
+        }
+        if video_failed {
+            self.video = None;
+        }
+
+        if movie_ended {
+            self.playback = None;
+            self.cpu.bus.joypad.set_buttons(0);
+        }
+    }
+
+    /// Starts recording a state per frame so that `rewind_frame` can go back.
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        self.rewind = Some(RewindBuffer::new(config));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Undoes the last frame. Returns false when there is no history left.
+    pub fn rewind_frame(&mut self) -> bool {
+        let state = match self.rewind {
+            Some(ref mut history) => history.pop(),
+            None => None
+        };
+        match state {
+            Some(state) => {
+                self.restore_state(&state).expect("Failed to restore a state from the rewind history");
+                // Keep movies in sync with the rewound timeline
+                if let Some(ref mut movie) = self.recording {
+                    movie.frames.pop();
+                }
+                if let Some(ref mut playback) = self.playback {
+                    playback.position = playback.position.saturating_sub(1);
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Number of frames `rewind_frame` can still undo.
+    pub fn rewind_frames(&self) -> usize {
+        self.rewind.as_ref().map_or(0, |history| history.len())
+    }
+
+    /// Starts recording the joypad every frame, either from a fresh power on
+    /// or from the current state of the machine.
+    pub fn start_recording(&mut self, from_power_on: bool) {
+        let start = if from_power_on {
+            self.reset();
+            MovieStart::PowerOn
+        } else {
+            MovieStart::SaveState(self.save_state())
+        };
+        self.recording = Some(Movie::new(self.cartridge().global_checksum(), start));
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Puts the machine at the start of the movie and feeds the joypad from it
+    /// until it runs out of frames.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if movie.rom_checksum != self.cartridge().global_checksum() {
+            return Err(String::from("the movie was recorded with a different game"));
+        }
+        match movie.start {
+            MovieStart::PowerOn => {self.reset();}
+            MovieStart::SaveState(ref state) => {self.load_state(state)?;}
+        }
+        self.playback = Some(Playback { movie: movie, position: 0 });
+        Ok(())
+    }
+
+    pub fn stop_movie(&mut self) {
+        self.playback = None;
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    /// Starts capturing the emulated frames into an animated GIF.
+    pub fn start_video(&mut self, path: &Path, config: RecorderConfig) -> Result<(), String> {
+        self.video = Some(GifRecorder::create(path, config)?);
+        Ok(())
+    }
+
+    /// Finishes the GIF and returns how many frames it has.
+    pub fn stop_video(&mut self) -> Result<u32, String> {
+        match self.video.take() {
+            Some(video) => video.finish(),
+            None => Ok(0)
+        }
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    /// Snapshot of the whole machine. Connected serial devices are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for byte in STATE_MAGIC {
+            writer.write_u8(*byte);
+        }
+        writer.write_u16(STATE_VERSION);
+        writer.write_u16(self.cartridge().global_checksum());
+        self.cpu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Restores a snapshot taken with `save_state`.
+    /// If the snapshot is broken the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        if let Err(e) = self.restore_state(data) {
+            self.restore_state(&backup).expect("Failed to roll back a broken save state");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn save_state_file(&self, path: &Path) -> Result<(), String> {
+        File::create(path)
+            .and_then(|mut file| file.write_all(&self.save_state()))
+            .map_err(|e| format!("can't write the save state {}: {}", path.display(), e))
+    }
+
+    pub fn load_state_file(&mut self, path: &Path) -> Result<(), String> {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("can't read the save state {}: {}", path.display(), e))?;
+        self.load_state(&data)
+            .map_err(|e| format!("can't load the save state {}: {}", path.display(), e))
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        for byte in STATE_MAGIC {
+            if reader.read_u8()? != *byte {
+                return Err(String::from("not a RustikGB save state"));
+            }
+        }
+        let version = reader.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(format!("the save state has version {}, but only version {} is supported", version, STATE_VERSION));
+        }
+        let checksum = reader.read_u16()?;
+        if checksum != self.cartridge().global_checksum() {
+            return Err(String::from("the save state belongs to a different game"));
+        }
+        self.cpu.load_state(&mut reader)?;
+        if !reader.is_at_end() {
+            return Err(String::from("the save state has unexpected trailing data"));
+        }
+        Ok(())
+    }
+
+    /// Buttons are applied at the start of the next frame, together with
+    /// turbo and macros. They are ignored while a movie plays.
+    pub fn set_button(&mut self, button: GbButton, pressed: bool) {
+        self.input.set_button(button, pressed);
+    }
+
+    pub fn set_turbo(&mut self, button: GbButton, pressed: bool) {
+        self.input.set_turbo(button, pressed);
+    }
+
+    pub fn set_turbo_rate(&mut self, button: GbButton, frames: u32) {
+        self.input.set_turbo_rate(button, frames);
+    }
+
+    pub fn play_macro(&mut self, input_macro: &InputMacro) {
+        self.input.play_macro(input_macro);
+    }
+
+    pub fn stop_macro(&mut self) {
+        self.input.stop_macro();
+    }
+
+    pub fn is_playing_macro(&self) -> bool {
+        self.input.is_playing_macro()
+    }
+
+    /// Ignores Left+Right and Up+Down when they are held together.
+    pub fn set_block_opposing_directions(&mut self, block: bool) {
+        self.cpu.bus.joypad.set_block_opposing_directions(block);
+    }
+
+    /// The last rendered frame, as 160x144 RGBA pixels.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.bus.screen.framebuffer()
+    }
+
+    /// Saves the current frame as a PNG, every pixel blown up to `scale` x `scale`.
+    pub fn save_screenshot(&self, path: &Path, scale: u32) -> Result<(), String> {
+        self.screen().save_png_scaled(path, scale)
+            .map_err(|e| format!("can't save the screenshot to {}: {}", path.display(), e))
+    }
+
+    pub fn set_palette(&mut self, palette: [[u8; 4]; 4]) {
+        self.cpu.bus.gpu.set_palette(palette);
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.cpu.bus.screen
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        self.cpu.bus.cartridge()
+    }
+
+    pub fn connect_serial(&mut self, device: Box<SerialDevice>) {
+        self.cpu.bus.serial.connect(device);
+    }
+
+    pub fn run_serial_test(&mut self, max_frames: u32) -> TestResult {
+        test_runner::run_serial_test(&mut self.cpu, &mut self.debugger, &self.instructions, max_frames)
+    }
+
+    pub fn enter_debug_mode(&mut self) {
+        self.debugger.enter_debug_mode();
+    }
+
+    /// Logs every instruction in gameboy-doctor format, or only the last ones, see `Debugger::start_trace`.
+    pub fn start_trace(&mut self, path: &Path, last: Option<usize>) -> Result<(), String> {
+        self.debugger.start_trace(path, last)
+    }
+
+    /// True once GDB killed the program, the frontend should then stop running frames.
+    pub fn quit_requested(&self) -> bool {
+        self.debugger.quit_requested()
+    }
+
+    /// Blocks until GDB connects on `addr`, which then controls the debugger.
+    pub fn listen_gdb(&mut self, addr: &str) -> Result<(), String> {
+        self.debugger.listen_gdb(addr)
+    }
+
+    /// Gives the debugger the labels of an RGBDS .sym or .map file, returns how many there are.
+    pub fn load_symbols(&mut self, path: &Path) -> Result<usize, String> {
+        let symbols = SymbolTable::load(path)?;
+        let count = symbols.len();
+        self.debugger.set_symbols(symbols);
+        Ok(count)
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+}
+
+/// Slot files live next to the ROM: "game.gb" gets "game.ss1", "game.ss2"...
+pub fn slot_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+/// Screenshots also go next to the ROM, named after the time they are taken:
+/// "game.gb" gets "game_20170131-235959.png", plus a counter if that one exists.
+pub fn screenshot_path(rom: &Path) -> PathBuf {
+    timestamped_path(rom, "png")
+}
+
+/// Same as `screenshot_path`, for GIF videos.
+pub fn video_path(rom: &Path) -> PathBuf {
+    timestamped_path(rom, "gif")
+}
+
+fn timestamped_path(rom: &Path, extension: &str) -> PathBuf {
+    let stem = rom.file_stem()
+        .map_or(String::from("capture"), |stem| stem.to_string_lossy().into_owned());
+    let name = format!("{}_{}", stem, timestamp(SystemTime::now()));
+    let mut path = rom.with_file_name(format!("{}.{}", name, extension));
+    let mut count = 1;
+    while path.exists() {
+        path = rom.with_file_name(format!("{}_{}.{}", name, count, extension));
+        count += 1;
+    }
+    path
+}
+
+// UTC time as YYYYMMDD-HHMMSS
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let time_of_day = secs % 86400;
+
+    // Days since the epoch to a civil date, see Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms"
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {shifted_month + 3} else {shifted_month - 9};
+    let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60)
+}
+
+fn power_on(boot_rom: &Option<Box<[u8]>>, rom: &[u8]) -> CPU {
+    let cartridge = Cartridge::new(rom);
+    match *boot_rom {
+        Some(ref data) => CPU::new(BUS::new(data.clone(), cartridge)),
+        None => {
+            let blank = vec![0; BOOT_ROM_SIZE].into_boxed_slice();
+            let mut cpu = CPU::new(BUS::new(blank, cartridge));
+            cpu.skip_boot_rom();
+            cpu
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    use hardware::joypad::GbButton;
+    use movie::Movie;
+    use test_rom;
+    use test_rom::ANIMATION;
+    use super::screenshot_path;
+    use super::slot_path;
+    use super::timestamp;
+    use super::video_path;
+    use GameBoy;
+
+    fn run_frames(gameboy: &mut GameBoy, count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|_| {
+            gameboy.run_frame();
+            gameboy.framebuffer().to_vec()
+        }).collect()
+    }
+
+    #[test]
+    fn restored_state_replays_the_same_frames() {
+        let mut gameboy = test_rom::gameboy(ANIMATION);
+        run_frames(&mut gameboy, 30);
+        let state = gameboy.save_state();
+        let first_run = run_frames(&mut gameboy, 20);
+        assert!(first_run[0] != first_run[19], "the frames should change for the test to mean anything");
+
+        gameboy.load_state(&state).unwrap();
+        assert!(gameboy.save_state() == state);
+        let second_run = run_frames(&mut gameboy, 20);
+        assert!(first_run == second_run);
+    }
+
+    fn assert_rejected(gameboy: &mut GameBoy, state: &[u8], error: &str) {
+        let before = gameboy.save_state();
+        match gameboy.load_state(state) {
+            Ok(()) => panic!("the state should have been rejected with '{}'", error),
+            Err(e) => assert!(e.contains(error), "'{}' doesn't mention '{}'", e, error)
+        }
+        assert!(gameboy.save_state() == before, "a rejected state changed the machine");
+    }
+
+    #[test]
+    fn broken_states_leave_the_machine_alone() {
+        let mut gameboy = test_rom::gameboy(ANIMATION);
+        let state = gameboy.save_state();
+        run_frames(&mut gameboy, 10);
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert_rejected(&mut gameboy, &bad_magic, "not a RustikGB save state");
+
+        let mut bad_version = state.clone();
+        bad_version[4] = bad_version[4].wrapping_add(1);
+        assert_rejected(&mut gameboy, &bad_version, "version");
+
+        let other_game = GameBoy::new(None, &test_rom::rom(ANIMATION, 0x1234)).unwrap();
+        assert_rejected(&mut gameboy, &other_game.save_state(), "different game");
+
+        // Fails halfway through the CPU, after some of it was already loaded
+        assert_rejected(&mut gameboy, &state[..state.len() / 2], "");
+        let mut trailing = state.clone();
+        trailing.push(0);
+        assert_rejected(&mut gameboy, &trailing, "trailing data");
+    }
+
+    #[test]
+    fn boot_roms_must_be_256_bytes() {
+        let rom = test_rom::rom(ANIMATION, 0);
+        match GameBoy::new(Some(vec![0; 512].into_boxed_slice()), &rom) {
+            Ok(_) => panic!("a 512 byte boot ROM was accepted"),
+            Err(e) => assert_eq!(e, "the boot ROM should be 256 bytes long, but it is 512 bytes")
+        }
+        assert!(GameBoy::new(Some(vec![0; 256].into_boxed_slice()), &rom).is_ok());
+    }
+
+    // Adds the direction keys into VRAM from 0x8000 on, so the input shows up on screen
+    const READ_JOYPAD : &'static [u8] = &[
+        0x21, 0x00, 0x80,   // LD HL,0x8000
+        0x3E, 0x20,         // LD A,0x20
+        0xE0, 0x00,         // LDH (P1),A
+        0xF0, 0x00,         // LDH A,(P1)
+        0x86,               // ADD A,(HL)
+        0x77,               // LD (HL),A
+        0x2C,               // INC L
+        0x18, 0xF5,         // JR -11
+    ];
+
+    #[test]
+    fn movies_replay_the_recorded_run() {
+        let mut gameboy = test_rom::gameboy(READ_JOYPAD);
+        gameboy.start_recording(true);
+        let mut recorded = Vec::new();
+        for frame in 0..40 {
+            gameboy.set_button(GbButton::Right, frame % 8 < 3);
+            gameboy.set_button(GbButton::Up, frame % 5 == 0);
+            gameboy.run_frame();
+            recorded.push(gameboy.framebuffer().to_vec());
+        }
+        let movie = gameboy.stop_recording().unwrap();
+        assert_eq!(movie.len(), 40);
+
+        // Pressing nothing gives a different run, so the input matters
+        let mut idle = test_rom::gameboy(READ_JOYPAD);
+        assert!(run_frames(&mut idle, 40) != recorded);
+
+        let mut replay = test_rom::gameboy(READ_JOYPAD);
+        replay.run_frame();
+        replay.play_movie(Movie::from_bytes(&movie.to_bytes()).unwrap()).unwrap();
+        assert!(run_frames(&mut replay, 40) == recorded);
+        assert!(!replay.is_playing_movie());
+
+        let mut other_game = GameBoy::new(None, &test_rom::rom(READ_JOYPAD, 0x1234)).unwrap();
+        assert_eq!(other_game.play_movie(movie).err(), Some(String::from("the movie was recorded with a different game")));
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        let at = |secs| timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "19700101-000000");
+        assert_eq!(at(951782400), "20000229-000000");
+        assert_eq!(at(1485907199), "20170131-235959");
+        assert_eq!(at(4107542400), "21000301-000000");
+    }
+
+    #[test]
+    fn captures_go_next_to_the_rom_without_overwriting() {
+        let dir = env::temp_dir().join(format!("rustikgb-captures-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.gb");
+        assert_eq!(slot_path(&rom, 3), dir.join("game.ss3"));
+
+        let first = screenshot_path(&rom);
+        let name = first.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("game_") && name.ends_with(".png") && name.len() == "game_YYYYMMDD-HHMMSS.png".len(), "{}", name);
+        fs::write(&first, b"").unwrap();
+        let second = screenshot_path(&rom);
+        fs::remove_dir_all(&dir).unwrap();
+        // Within the same second the name gets a counter
+        assert!(second != first);
+        assert_eq!(second.extension().unwrap(), "png");
+        assert_eq!(video_path(&rom).extension().unwrap(), "gif");
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+
+use hardware::interrupts::InterruptType;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt(InterruptType),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    // Address of the CALL or RST, or the PC the interrupt stopped
+    pub from: u16,
+    pub target: u16,
+    pub return_addr: u16,
+    // SP right after the return address was pushed
+    pub sp: u16,
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            CallKind::Call => write!(fmt, "call to 0x{:04X} from 0x{:04X}", self.target, self.from),
+            CallKind::Rst => write!(fmt, "RST 0x{:02X} from 0x{:04X}", self.target, self.from),
+            CallKind::Interrupt(interrupt) =>
+                write!(fmt, "{:?} interrupt at 0x{:04X} while at 0x{:04X}", interrupt, self.target, self.from),
+        }
+    }
+}
+
+/// A return that doesn't match the calls we saw, which usually means
+/// the stack was corrupted.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StackImbalance {
+    // Address of the RET or RETI
+    pub pc: u16,
+    pub description: String,
+}
+
+impl fmt::Display for StackImbalance {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "0x{:04X}: {}", self.pc, self.description)
+    }
+}
+
+/// Calls, RSTs and interrupts that haven't returned yet, innermost last.
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    // Newest imbalance the debugger hasn't reported yet
+    pending: Option<StackImbalance>,
+    last_imbalance: Option<StackImbalance>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack {
+            frames: Vec::new(),
+            pending: None,
+            last_imbalance: None,
+        }
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        // Calls whose return address is at or below the new one were left without
+        // a RET, like a POP HL / JP HL, and are gone
+        while self.frames.last().map_or(false, |last| last.sp <= frame.sp) {
+            self.frames.pop();
+        }
+        self.frames.push(frame);
+    }
+
+    /// Matches a return from `pc` with the frame that pushed the return address.
+    /// `sp` points to the return address about to be popped.
+    pub fn pop(&mut self, pc: u16, sp: u16, return_addr: u16) {
+        let description = match self.frames.iter().rposition(|frame| frame.sp == sp) {
+            Some(index) => {
+                let frame = self.frames[index];
+                let skipped = self.frames.len() - 1 - index;
+                self.frames.truncate(index);
+                if skipped > 0 {
+                    format!("return from the {} skips {} unfinished call(s)", frame, skipped)
+                } else if frame.return_addr != return_addr {
+                    format!("return from the {} goes to 0x{:04X} instead of 0x{:04X}", frame, return_addr, frame.return_addr)
+                } else {
+                    return;
+                }
+            }
+            None => match self.frames.last().cloned() {
+                // Something pushed is returned to, the call can still return later
+                Some(frame) if sp < frame.sp =>
+                    format!("return to 0x{:04X} with SP at 0x{:04X}, the {} left it at 0x{:04X}", return_addr, sp, frame, frame.sp),
+                Some(frame) => {
+                    // Return addresses below SP were popped by hand
+                    while self.frames.last().map_or(false, |frame| frame.sp < sp) {
+                        self.frames.pop();
+                    }
+                    format!("return to 0x{:04X} with SP at 0x{:04X}, past the {} that left it at 0x{:04X}", return_addr, sp, frame, frame.sp)
+                }
+                None => format!("RET to 0x{:04X} without a matching CALL", return_addr)
+            }
+        };
+        let imbalance = StackImbalance {
+            pc: pc,
+            description: description,
+        };
+        self.pending = Some(imbalance.clone());
+        self.last_imbalance = Some(imbalance);
+    }
+
+    pub fn take_imbalance(&mut self) -> Option<StackImbalance> {
+        self.pending.take()
+    }
+
+    pub fn last_imbalance(&self) -> Option<&StackImbalance> {
+        self.last_imbalance.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CallFrame;
+    use super::CallKind;
+    use super::CallStack;
+    use hardware::interrupts::InterruptType;
+
+    fn call(from: u16, sp: u16) -> CallFrame {
+        CallFrame {kind: CallKind::Call, from: from, target: 0x4000, return_addr: from + 3, sp: sp}
+    }
+
+    fn imbalance(stack: &mut CallStack) -> Option<String> {
+        stack.take_imbalance().map(|imbalance| imbalance.to_string())
+    }
+
+    #[test]
+    fn matching_returns_are_balanced() {
+        let mut stack = CallStack::new();
+        stack.push(call(0x0150, 0xFFFC));
+        stack.push(CallFrame {kind: CallKind::Interrupt(InterruptType::VBlank), from: 0x4010, target: 0x0040, return_addr: 0x4010, sp: 0xFFFA});
+        assert_eq!(stack.depth(), 2);
+        stack.pop(0x0050, 0xFFFA, 0x4010);
+        stack.pop(0x4020, 0xFFFC, 0x0153);
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(imbalance(&mut stack), None);
+        assert!(stack.last_imbalance().is_none());
+    }
+
+    #[test]
+    fn unbalanced_returns_are_classified() {
+        let mut stack = CallStack::new();
+        stack.pop(0x0200, 0xFFFE, 0x1234);
+        assert_eq!(imbalance(&mut stack), Some(String::from("0x0200: RET to 0x1234 without a matching CALL")));
+
+        // Returning to a different address than the one pushed
+        stack.push(call(0x0150, 0xFFFC));
+        stack.pop(0x4020, 0xFFFC, 0x0160);
+        assert_eq!(imbalance(&mut stack), Some(String::from(
+            "0x4020: return from the call to 0x4000 from 0x0150 goes to 0x0160 instead of 0x0153")));
+        assert_eq!(stack.depth(), 0);
+
+        // Returning from the outer call while the inner one is still open
+        stack.push(call(0x0150, 0xFFFC));
+        stack.push(call(0x4000, 0xFFFA));
+        stack.pop(0x4020, 0xFFFC, 0x0153);
+        assert_eq!(imbalance(&mut stack), Some(String::from(
+            "0x4020: return from the call to 0x4000 from 0x0150 skips 1 unfinished call(s)")));
+        assert_eq!(stack.depth(), 0);
+
+        // Returning to something pushed after the call keeps the call open
+        stack.push(call(0x0150, 0xFFFC));
+        stack.pop(0x4020, 0xFFFA, 0x5000);
+        assert_eq!(imbalance(&mut stack), Some(String::from(
+            "0x4020: return to 0x5000 with SP at 0xFFFA, the call to 0x4000 from 0x0150 left it at 0xFFFC")));
+        assert_eq!(stack.depth(), 1);
+
+        // Returning past the call drops it
+        stack.pop(0x4020, 0xFFFE, 0x5000);
+        assert_eq!(imbalance(&mut stack), Some(String::from(
+            "0x4020: return to 0x5000 with SP at 0xFFFE, past the call to 0x4000 from 0x0150 that left it at 0xFFFC")));
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(imbalance(&mut stack), None);
+        assert_eq!(stack.last_imbalance().map(|imbalance| imbalance.pc), Some(0x4020));
+    }
+
+    #[test]
+    fn calls_abandoned_without_a_return_are_dropped() {
+        let mut stack = CallStack::new();
+        stack.push(call(0x0150, 0xFFFC));
+        stack.push(call(0x4000, 0xFFFA));
+        // POP HL / JP HL, then a call from the same stack level
+        stack.push(call(0x0160, 0xFFFC));
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.frames()[0].from, 0x0160);
+    }
+}
@@ -1,10 +1,12 @@
 use std::fmt;
-use std::str;
 use hardware::memory::memory_region::MemoryRegion;
 
 const ROM_START                 : u16 = 0x0000;
 const ROM_END                   : u16 = 0x7FFF;
 
+const HEADER_CHECKSUM_ADDR      : usize = 0x014D;
+const GLOBAL_CHECKSUM_ADDR      : usize = 0x014E;
+
 pub enum CartridgeType {
     ROM_ONLY, // 00
     UNKNOWN
@@ -41,7 +43,7 @@ pub struct CartridgeHeader {
 }
 
 impl CartridgeHeader {
-    pub fn new(raw_rom: &Box<[u8]>) -> Self {
+    pub fn new(raw_rom: &[u8]) -> Self {
         CartridgeHeader {
             entry_point: CartridgeHeader::read_entry_point(raw_rom),
             nintendo_graphic: CartridgeHeader::read_nintendo_graphic(raw_rom),
@@ -50,18 +52,18 @@ impl CartridgeHeader {
         }
     }
 
-    fn read_entry_point(raw_rom: &Box<[u8]>) -> [u16; 2] {        
+    fn read_entry_point(raw_rom: &[u8]) -> [u16; 2] {        
         [raw_rom[0x0100] as u16, raw_rom[0x0101] as u16]
     }
 
-    fn read_nintendo_graphic(raw_rom: &Box<[u8]>) -> [u8; 47] {
+    fn read_nintendo_graphic(raw_rom: &[u8]) -> [u8; 47] {
         let mut graphic : [u8; 47] = [0; 47];
         graphic.copy_from_slice(&raw_rom[0x0104..0x0133]);
         graphic
     }
 
-    fn read_game_title(raw_rom: &Box<[u8]>) -> String {
-        let mut decoded = String::from(str::from_utf8(&(raw_rom[0x0134..0x0142])).unwrap());
+    fn read_game_title(raw_rom: &[u8]) -> String {
+        let mut decoded = String::from_utf8_lossy(&(raw_rom[0x0134..0x0142])).into_owned();
         while decoded.ends_with('\0') {
             let len = decoded.len();
             let new_len = len.saturating_sub(1);
@@ -70,7 +72,7 @@ impl CartridgeHeader {
         decoded
     }
 
-    fn read_cartridge_type(raw_rom: &Box<[u8]>) -> CartridgeType {
+    fn read_cartridge_type(raw_rom: &[u8]) -> CartridgeType {
         match raw_rom[0x0147] {
             0x00 => CartridgeType::ROM_ONLY,
             _ => CartridgeType::UNKNOWN
@@ -92,15 +94,46 @@ pub struct Cartridge {
 }
 
 impl Cartridge {
-    pub fn new(raw_rom: &Box<[u8]>) -> Self {
+    pub fn new(raw_rom: &[u8]) -> Self {
         Cartridge {
             header: CartridgeHeader::new(raw_rom),
             data: Cartridge::copy_raw_rom(raw_rom)
         }
     }
 
-    fn copy_raw_rom(raw_rom: &Box<[u8]>) -> Box<[u8]> {
-        raw_rom.clone()
+    /// Checks that `raw_rom` looks like a Game Boy ROM before building a cartridge out of it.
+    pub fn check_rom(raw_rom: &[u8]) -> Result<(), String> {
+        if raw_rom.len() <= ROM_END as usize {
+            return Err(format!("the ROM is too small ({} bytes), a cartridge has at least {} bytes",
+                raw_rom.len(), ROM_END as usize + 1));
+        }
+        let mut checksum: u8 = 0;
+        for byte in &raw_rom[0x0134..HEADER_CHECKSUM_ADDR] {
+            checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        if checksum != raw_rom[HEADER_CHECKSUM_ADDR] {
+            return Err(format!("the header checksum is {:02X} but the header adds up to {:02X}, the ROM is probably corrupt",
+                raw_rom[HEADER_CHECKSUM_ADDR], checksum));
+        }
+        Ok(())
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Checksum of the whole ROM, as stored in the header
+    pub fn global_checksum(&self) -> u16 {
+        (self.data[GLOBAL_CHECKSUM_ADDR] as u16) << 8 | self.data[GLOBAL_CHECKSUM_ADDR + 1] as u16
+    }
+
+    /// Changes a byte of the ROM itself, for the debugger to patch code.
+    pub fn patch_byte(&mut self, addr: u16, val: u8) {
+        self.data[addr as usize] = val;
+    }
+
+    fn copy_raw_rom(raw_rom: &[u8]) -> Box<[u8]> {
+        raw_rom.to_vec().into_boxed_slice()
     }
 
     /// Read the bytes in [addr, addr + 1]
@@ -140,4 +173,21 @@ impl MemoryRegion for Cartridge {
         ROM_END
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::Cartridge;
+    use test_rom;
+
+    #[test]
+    fn roms_are_checked_before_loading() {
+        let rom = test_rom::rom(&[], 0);
+        assert_eq!(Cartridge::check_rom(&rom), Ok(()));
+        assert_eq!(Cartridge::check_rom(&rom[..0x4000]),
+                   Err(String::from("the ROM is too small (16384 bytes), a cartridge has at least 32768 bytes")));
+        let mut corrupt = rom.to_vec();
+        corrupt[0x0134] = b'X';
+        assert_eq!(Cartridge::check_rom(&corrupt),
+                   Err(String::from("the header checksum is E7 but the header adds up to 8F, the ROM is probably corrupt")));
+    }
+}
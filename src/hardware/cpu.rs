@@ -2,6 +2,9 @@ use std::fmt;
 use hardware::instructions;
 use hardware::memory::bus;
 use hardware::debugger;
+use hardware::call_stack::CallFrame;
+use hardware::call_stack::CallKind;
+use hardware::call_stack::CallStack;
 use hardware::registers::Register;
 use hardware::video::screen::Screen;
 use hardware::debugger::Debugger;
@@ -9,8 +12,13 @@ use hardware::instructions::InstructionSet;
 use hardware::interrupts::InterruptType;
 use hardware::interrupts::Interrupts;
 use hardware::interrupts::*;
+use hardware::memory::ioregs::BOOT_ROM_ENABLE;
+use hardware::video::gpu_constants::*;
+use hardware::state::*;
 
-const CYCLES_PER_FRAME: u32 = 70244;
+pub const CLOCK_SPEED      : u32 = 4194304;
+// 154 lines of 456 cycles, so that run_frame stays in step with the GPU
+pub const CYCLES_PER_FRAME : u32 = CYCLES_PER_LINE * VBLANK_END_LINE as u32;
 
 pub struct RegBank {
     pub a : Register<u8>,
@@ -91,8 +99,10 @@ pub struct CPU {
     pub regs : RegBank,
     pub sp : Register<u16>,
     pub pc : Register<u16>,
+    // Calls, RSTs and interrupts not returned from yet, for the debugger
+    pub call_stack: CallStack,
 
-    cycles: u32
+    cycles: u32,
 }
 
 impl fmt::Display for CPU {
@@ -123,19 +133,39 @@ impl CPU {
             regs : Default::default(),
             sp : Register::new(0x0000),            
             pc : Register::new(0x0000),
-            cycles: 0
+            call_stack: CallStack::new(),
+            cycles: 0,
         }
     }
 
+    /// Leaves the machine as the DMG boot ROM does when it hands over to the cartridge.
+    pub fn skip_boot_rom(&mut self) {
+        self.regs.af_w(0x01B0);
+        self.regs.bc_w(0x0013);
+        self.regs.de_w(0x00D8);
+        self.regs.hl_w(0x014D);
+        self.sp.w(0xFFFE);
+        self.pc.w(0x0100);
+
+        self.write_byte(LCD_CONTROL_ADDR, 0x91);
+        self.write_byte(BG_PALLETE_ADDR, 0xFC);
+        self.write_byte(OBJECT_PALETTE_1_ADDR, 0xFF);
+        self.write_byte(OBJECT_PALETTE_2_ADDR, 0xFF);
+        self.write_byte(BOOT_ROM_ENABLE, 0x01);
+    }
+
     pub fn run_frame(&mut self, debugger: &mut Debugger, instr_set: &InstructionSet) {
+        debugger.start_frame(self);
         while self.cycles < CYCLES_PER_FRAME {
+            let pc = self.pc.r();
+            debugger.stop_if_needed(pc, self);
+
+            // The debugger may have moved the PC
             let mut bitwise = false;
             let old_pc = self.pc.r();
             let mut opcode = self.fetch_byte_immediate();
             if opcode == 0xCB {bitwise = true; opcode = self.fetch_byte_immediate();}
 
-            debugger.stop_if_needed(old_pc, self, &instr_set);              
-             
             if !instr_set.is_implemented(opcode, bitwise) {
                 println!("Unimplemented instruction {}0x{:0X}\nProcessor state:\n{}", 
                         if bitwise {"(CB)"} else {""},
@@ -145,6 +175,7 @@ impl CPU {
             } 
 
             self.cycles += self.step(&instr_set, opcode, bitwise);
+            debugger.after_step(old_pc, self);
         }
 
         self.cycles -= CYCLES_PER_FRAME;
@@ -209,6 +240,27 @@ impl CPU {
         }
     }
 
+    /// Called once a CALL, RST or interrupt pushed its return address and jumped.
+    /// `from` is the address of the instruction, or the PC the interrupt stopped.
+    pub fn enter_call(&mut self, kind: CallKind, from: u16, return_addr: u16) {
+        let frame = CallFrame {
+            kind: kind,
+            from: from,
+            target: self.pc.r(),
+            return_addr: return_addr,
+            sp: self.sp.r(),
+        };
+        self.call_stack.push(frame);
+    }
+
+    /// Called by the RET or RETI at `from` before it pops the return address.
+    pub fn leave_call(&mut self, from: u16) {
+        let sp = self.sp.r();
+        // Peeking, so watchpoints only see the pop itself
+        let return_addr = (self.bus.peek_byte(sp.wrapping_add(1)) as u16) << 8 | self.bus.peek_byte(sp) as u16;
+        self.call_stack.pop(from, sp, return_addr);
+    }
+
     pub fn disable_interrupts_delayed(&mut self) {
         self.bus.disable_in_next_step();        
     }
@@ -246,31 +298,49 @@ impl CPU {
         
         // We read in order of priority
         if self.bus.interrupt_handler.read_and_clear(InterruptType::VBlank) {
-            self.bus.interrupt_handler.disable();
-            let pc = self.pc.r();
-            self.push_word(pc);
-            self.pc.w(VBLANK_ISR_START);
+            self.service_interrupt(InterruptType::VBlank, VBLANK_ISR_START);
         } else if self.bus.interrupt_handler.read_and_clear(InterruptType::LCDC) {
-            self.bus.interrupt_handler.disable();
-            let pc = self.pc.r();
-            self.push_word(pc);
-            self.pc.w(LCDC_ISR_START);
+            self.service_interrupt(InterruptType::LCDC, LCDC_ISR_START);
         } else if self.bus.interrupt_handler.read_and_clear(InterruptType::Timer) {
-            self.bus.interrupt_handler.disable();
-            let pc = self.pc.r();
-            self.push_word(pc);
-            self.pc.w(TIMER_ISR_START);
+            self.service_interrupt(InterruptType::Timer, TIMER_ISR_START);
         } else if self.bus.interrupt_handler.read_and_clear(InterruptType::Serial) {
-            self.bus.interrupt_handler.disable();
-            let pc = self.pc.r();
-            self.push_word(pc);
-            self.pc.w(SERIAL_ISR_START);
+            self.service_interrupt(InterruptType::Serial, SERIAL_ISR_START);
         } else if self.bus.interrupt_handler.read_and_clear(InterruptType::Pad) {
-            self.bus.interrupt_handler.disable();
-            let pc = self.pc.r();
-            self.push_word(pc);
-            self.pc.w(JOYPAD_ISR_START);
+            self.service_interrupt(InterruptType::Pad, JOYPAD_ISR_START);
         }
     }
+
+    fn service_interrupt(&mut self, interrupt: InterruptType, isr_start: u16) {
+        self.bus.interrupt_handler.disable();
+        let pc = self.pc.r();
+        self.push_word(pc);
+        self.pc.w(isr_start);
+        self.enter_call(CallKind::Interrupt(interrupt), pc, pc);
+    }
 }
 
+impl Savestate for CPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.regs.af());
+        writer.write_u16(self.regs.bc());
+        writer.write_u16(self.regs.de());
+        writer.write_u16(self.regs.hl());
+        writer.write_u16(self.sp.r());
+        writer.write_u16(self.pc.r());
+        writer.write_u32(self.cycles);
+        self.bus.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.regs.af_w(reader.read_u16()?);
+        self.regs.bc_w(reader.read_u16()?);
+        self.regs.de_w(reader.read_u16()?);
+        self.regs.hl_w(reader.read_u16()?);
+        self.sp.w(reader.read_u16()?);
+        self.pc.w(reader.read_u16()?);
+        self.cycles = reader.read_u32()?;
+        // The calls we saw don't lead to the loaded state
+        self.call_stack = CallStack::new();
+        self.bus.load_state(reader)
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+use hardware::cpu::CPU;
+use hardware::debugger::expression::Context;
+use hardware::debugger::expression::Expression;
+
+pub struct Breakpoint {
+    pub addr: u16,
+    pub condition: Option<Expression>,
+    // The breakpoint only stops from this hit on
+    pub from_hit: u32,
+    // Times the PC got to the address with the condition holding
+    pub hits: u32,
+    pub enabled: bool,
+}
+
+impl Breakpoint {
+    /// Like Display, with the address followed by the name `describe` gives it.
+    pub fn format_with<F: Fn(u16) -> Option<String>>(&self, describe: F) -> String {
+        let mut text = format!("0x{:04X}", self.addr);
+        if let Some(name) = describe(self.addr) {
+            text.push_str(&format!(" <{}>", name));
+        }
+        if self.from_hit > 1 {
+            text.push_str(&format!(" hit {}", self.from_hit));
+        }
+        if let Some(ref condition) = self.condition {
+            text.push_str(&format!(" if {}", condition));
+        }
+        text
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.format_with(|_| None))
+    }
+}
+
+pub struct Breakpoints {
+    // Ids stay the same when other breakpoints are deleted
+    list: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints {
+            list: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Returns the id of the new breakpoint.
+    pub fn add(&mut self, addr: u16, condition: Option<Expression>, from_hit: u32) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push((id, Breakpoint {
+            addr: addr,
+            condition: condition,
+            from_hit: from_hit,
+            hits: 0,
+            enabled: true,
+        }));
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        let index = self.list.iter().position(|entry| entry.0 == id)?;
+        Some(self.list.remove(index).1)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|entry| entry.0 == id).map(|entry| &mut entry.1)
+    }
+
+    pub fn list(&self) -> &[(usize, Breakpoint)] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Counts the hits of the enabled breakpoints at `pc` and says which ones stop
+    /// the program. A condition that can't be evaluated stops it too, with the error.
+    pub fn check(&mut self, pc: u16, cpu: &CPU) -> Vec<(usize, Result<(), String>)> {
+        let mut stops = Vec::new();
+        for &mut (id, ref mut breakpoint) in self.list.iter_mut() {
+            if !breakpoint.enabled || breakpoint.addr != pc {
+                continue;
+            }
+            let holds = match breakpoint.condition {
+                Some(ref condition) => {
+                    let context = Context { cpu: cpu, pc: pc, hits: breakpoint.hits };
+                    condition.eval(&context).map(|value| value != 0)
+                }
+                None => Ok(true)
+            };
+            match holds {
+                Ok(false) => {}
+                Ok(true) => {
+                    breakpoint.hits += 1;
+                    if breakpoint.hits >= breakpoint.from_hit {
+                        stops.push((id, Ok(())));
+                    }
+                }
+                Err(e) => stops.push((id, Err(e)))
+            }
+        }
+        stops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Breakpoints;
+    use hardware::debugger::expression::Expression;
+    use hardware::debugger::symbols::SymbolTable;
+    use test_rom;
+
+    #[test]
+    fn hit_counts_start_stopping_at_their_hit() {
+        let gameboy = test_rom::gameboy(&[]);
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0x0150, None, 3);
+        let stops: Vec<bool> = (0..5).map(|_| !breakpoints.check(0x0150, gameboy.cpu()).is_empty()).collect();
+        assert_eq!(stops, vec![false, false, true, true, true]);
+        assert!(breakpoints.check(0x0151, gameboy.cpu()).is_empty());
+        assert_eq!(breakpoints.get_mut(id).unwrap().hits, 5);
+    }
+
+    #[test]
+    fn hits_only_count_when_the_condition_holds() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let condition = Expression::parse("a == 1", &SymbolTable::new()).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0x0150, Some(condition), 2);
+        gameboy.cpu_mut().regs.a.w(0);
+        assert!(breakpoints.check(0x0150, gameboy.cpu()).is_empty());
+        gameboy.cpu_mut().regs.a.w(1);
+        assert!(breakpoints.check(0x0150, gameboy.cpu()).is_empty());
+        assert_eq!(breakpoints.check(0x0150, gameboy.cpu()), vec![(id, Ok(()))]);
+        assert_eq!(breakpoints.get_mut(id).unwrap().hits, 2);
+    }
+
+    #[test]
+    fn hits_is_the_count_before_this_one() {
+        let gameboy = test_rom::gameboy(&[]);
+        let condition = Expression::parse("hits % 2 == 1", &SymbolTable::new()).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add(0x0150, Some(condition), 1);
+        let stops: Vec<bool> = (0..4).map(|_| !breakpoints.check(0x0150, gameboy.cpu()).is_empty()).collect();
+        // Failing conditions don't count, so hits stays even
+        assert_eq!(stops, vec![false, false, false, false]);
+    }
+
+    #[test]
+    fn disabled_breakpoints_and_failing_conditions() {
+        let gameboy = test_rom::gameboy(&[]);
+        let condition = Expression::parse("1 / (hits - hits)", &SymbolTable::new()).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let disabled = breakpoints.add(0x0150, None, 1);
+        let failing = breakpoints.add(0x0150, Some(condition), 1);
+        breakpoints.get_mut(disabled).unwrap().enabled = false;
+        assert_eq!(breakpoints.check(0x0150, gameboy.cpu()), vec![(failing, Err(String::from("division by zero")))]);
+        assert_eq!(format!("{}", breakpoints.remove(failing).unwrap()), "0x0150 if 1 / (hits - hits)");
+        assert!(breakpoints.remove(failing).is_none());
+    }
+}
@@ -0,0 +1,486 @@
+// Debugger command line:
+//  <command> [arguments...]
+// Numbers are decimal unless prefixed with 0x or $, which makes them hex.
+// Register names (a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc) stand for their current value.
+
+use hardware::cpu::CPU;
+use hardware::debugger::expression::Context;
+use hardware::debugger::expression::Expression;
+use hardware::debugger::expression::Variable;
+use hardware::debugger::symbols::MAPPED_ROM_BANK;
+use hardware::debugger::symbols::SymbolTable;
+use hardware::debugger::watchpoints::WatchKind;
+use hardware::debugger::watchpoints::Watchpoint;
+
+pub enum Command {
+    Step(u32),
+    StepOver,
+    StepOut,
+    RunTo(u16),
+    NextFrame,
+    NextVBlank,
+    Continue,
+    Print(u16),
+    Dump(u16, u16),
+    Registers,
+    Disassemble(Option<Location>, usize),
+    Tiles,
+    Break(u16, Option<Expression>, u32),
+    Breakpoints,
+    Set(Variable, u16),
+    Jump(u16),
+    Write(u16, Vec<u8>),
+    Fill(u16, u16, u8),
+    Load(String, u16, Option<u16>),
+    Enable(usize),
+    Disable(usize),
+    Delete(usize),
+    Watch(Watchpoint),
+    Watches,
+    Unwatch(usize),
+    Backtrace,
+    StackCheck(bool),
+    History,
+    Help(Option<String>),
+}
+
+pub enum Location {
+    Memory(u16),
+    // A ROM bank and an address where that bank is mapped
+    Bank(u16, u16),
+}
+
+pub struct CommandHelp {
+    pub names: &'static [&'static str],
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMMANDS: &'static [CommandHelp] = &[
+    CommandHelp { names: &["step", "s", "n"],           usage: "step [count]",          description: "Run one instruction, or count of them" },
+    CommandHelp { names: &["over", "o"],                usage: "over",                  description: "Run one instruction, or a whole call when it is a CALL or RST" },
+    CommandHelp { names: &["out", "finish"],            usage: "out",                   description: "Run until the current call returns" },
+    CommandHelp { names: &["until", "u"],               usage: "until <addr>",          description: "Run until the PC gets to an address" },
+    CommandHelp { names: &["frame", "f"],               usage: "frame",                 description: "Run until the next frame starts" },
+    CommandHelp { names: &["vblank", "vb"],             usage: "vblank",                description: "Run until the next VBlank starts" },
+    CommandHelp { names: &["continue", "c"],            usage: "continue",              description: "Run until the next breakpoint" },
+    CommandHelp { names: &["print", "p", "x"],          usage: "print <addr>",          description: "Show the byte at an address" },
+    CommandHelp { names: &["dump", "d"],                usage: "dump <start> [end]",    description: "Show the bytes from start to end, both included (64 bytes by default)" },
+    CommandHelp { names: &["disassemble", "da", "list"], usage: "disassemble [addr|bank:addr] [count]",
+                                                        description: "Show the instructions around the PC, or count of them from an address of memory or of a ROM bank (10 by default)" },
+    CommandHelp { names: &["registers", "regs", "r"],   usage: "registers",             description: "Show the processor state" },
+    CommandHelp { names: &["tiles", "t"],               usage: "tiles",                 description: "Save the tile set to logs/tile_dump.png" },
+    CommandHelp { names: &["break", "b"],               usage: "break <addr> [hit <n>] [if <condition>]",
+                                                        description: "Stop when the PC gets to an address, from the nth time on and only if the condition holds, e.g. 'b 0x0150 if A==0x3 && [HL]>0x10'" },
+    CommandHelp { names: &["set"],                      usage: "set <register|flag> <value>",
+                                                        description: "Change a register, or a flag (zf, nf, hf, cf) to 0 or 1" },
+    CommandHelp { names: &["jump", "j"],                usage: "jump <addr>",           description: "Move the PC, the program goes on from there" },
+    CommandHelp { names: &["write", "poke"],            usage: "write <addr> <byte> [bytes...]",
+                                                        description: "Write bytes to memory from an address. Writes to the cartridge patch the ROM" },
+    CommandHelp { names: &["fill"],                     usage: "fill <start> <end> <byte>",
+                                                        description: "Write a byte to every address from start to end, both included" },
+    CommandHelp { names: &["load"],                     usage: "load <file> <start> [end]",
+                                                        description: "Write the contents of a file to memory from an address, which must fit before end if given" },
+    CommandHelp { names: &["breakpoints", "bl"],        usage: "breakpoints",           description: "List the breakpoints and their hit counts" },
+    CommandHelp { names: &["enable", "en"],             usage: "enable <n>",            description: "Enable a breakpoint" },
+    CommandHelp { names: &["disable", "dis"],           usage: "disable <n>",           description: "Disable a breakpoint, it keeps its hit count" },
+    CommandHelp { names: &["delete", "del"],            usage: "delete <n>",            description: "Delete a breakpoint" },
+    CommandHelp { names: &["watch", "w"],               usage: "watch <kind> <start> [end] [== <value>]",
+                                                        description: "Stop after an access to an address or range. Kinds: read, write, access (both) and change (writes of a different value)" },
+    CommandHelp { names: &["watches", "wl"],            usage: "watches",               description: "List the watchpoints" },
+    CommandHelp { names: &["unwatch", "uw"],            usage: "unwatch <n>",           description: "Delete a watchpoint" },
+    CommandHelp { names: &["backtrace", "bt", "where"], usage: "backtrace",             description: "Show the calls and interrupts not returned from yet, with their return addresses" },
+    CommandHelp { names: &["stackcheck", "sc"],         usage: "stackcheck <on|off>",   description: "Stop when a RET doesn't match the call that pushed its return address" },
+    CommandHelp { names: &["history"],                  usage: "history",               description: "List the previous commands, !<n> runs one again" },
+    CommandHelp { names: &["help", "h", "?"],           usage: "help [command]",        description: "Show this list, or the help of one command" },
+];
+
+// Bytes shown by "dump" without an end address
+const DEFAULT_DUMP_LENGTH           : u16 = 64;
+// Instructions shown by "disassemble" without a count
+const DEFAULT_DISASSEMBLY_LENGTH    : u16 = 10;
+
+pub fn find_help(name: &str) -> Option<&'static CommandHelp> {
+    COMMANDS.iter().find(|help| help.names.contains(&name))
+}
+
+/// Parses a command line. `pc` is the address of the instruction we stopped at,
+/// which is what "pc" means in arguments.
+pub fn parse(line: &str, cpu: &CPU, pc: u16, symbols: &SymbolTable) -> Result<Command, String> {
+    let mut args = Arguments {
+        tokens: line.split_whitespace().collect(),
+        next: 1,
+        cpu: cpu,
+        pc: pc,
+        symbols: symbols,
+    };
+    let name = match args.tokens.first() {
+        Some(name) => name.to_lowercase(),
+        None => return Err(String::from("empty command"))
+    };
+    let help = find_help(&name)
+        .ok_or_else(|| format!("unknown command '{}'", name))?;
+
+    let command = match help.names[0] {
+        "step"      => {
+            let count = args.optional_value()?.unwrap_or(1);
+            if count == 0 {
+                return Err(String::from("the count must be at least 1"));
+            }
+            Command::Step(count as u32)
+        }
+        "over"      => Command::StepOver,
+        "out"       => Command::StepOut,
+        "until"     => Command::RunTo(args.value(help)?),
+        "frame"     => Command::NextFrame,
+        "vblank"    => Command::NextVBlank,
+        "continue"  => Command::Continue,
+        "print"     => Command::Print(args.value(help)?),
+        "dump"      => {
+            let start = args.value(help)?;
+            let end = match args.optional_value()? {
+                Some(end) => end,
+                None => start.saturating_add(DEFAULT_DUMP_LENGTH - 1)
+            };
+            if end < start {
+                return Err(format!("the end of the range (0x{:04X}) is before its start (0x{:04X})", end, start));
+            }
+            Command::Dump(start, end)
+        }
+        "disassemble" => {
+            let location = match args.word() {
+                Some(word) => Some(args.parse_location(word)?),
+                None => None
+            };
+            let count = args.optional_value()?.unwrap_or(DEFAULT_DISASSEMBLY_LENGTH);
+            Command::Disassemble(location, count as usize)
+        }
+        "registers" => Command::Registers,
+        "tiles"     => Command::Tiles,
+        "break"     => parse_breakpoint(&mut args, help)?,
+        "breakpoints" => Command::Breakpoints,
+        "set"       => {
+            let name = args.word().ok_or_else(|| format!("missing argument, usage: {}", help.usage))?;
+            let variable = match Variable::by_name(name) {
+                Some(Variable::Hits) | None => return Err(format!("'{}' is not a register or a flag", name)),
+                Some(variable) => variable
+            };
+            Command::Set(variable, args.value(help)?)
+        }
+        "jump"      => Command::Jump(args.value(help)?),
+        "write"     => {
+            let start = args.value(help)?;
+            let mut bytes = vec![args.byte(help)?];
+            while let Some(byte) = args.optional_value()? {
+                bytes.push(to_byte(byte)?);
+            }
+            if start as usize + bytes.len() > 0x10000 {
+                return Err(String::from("the bytes go past the end of memory"));
+            }
+            Command::Write(start, bytes)
+        }
+        "fill"      => {
+            let start = args.value(help)?;
+            let end = args.value(help)?;
+            if end < start {
+                return Err(format!("the end of the range (0x{:04X}) is before its start (0x{:04X})", end, start));
+            }
+            Command::Fill(start, end, args.byte(help)?)
+        }
+        "load"      => {
+            let path = args.word().ok_or_else(|| format!("missing argument, usage: {}", help.usage))?;
+            let start = args.value(help)?;
+            let end = args.optional_value()?;
+            if end.map_or(false, |end| end < start) {
+                return Err(format!("the end of the range (0x{:04X}) is before its start (0x{:04X})", end.unwrap(), start));
+            }
+            Command::Load(String::from(path), start, end)
+        }
+        "enable"    => Command::Enable(args.value(help)? as usize),
+        "disable"   => Command::Disable(args.value(help)? as usize),
+        "delete"    => Command::Delete(args.value(help)? as usize),
+        "watch"     => Command::Watch(parse_watchpoint(&mut args, help)?),
+        "watches"   => Command::Watches,
+        "unwatch"   => Command::Unwatch(args.value(help)? as usize),
+        "backtrace" => Command::Backtrace,
+        "stackcheck" => match args.word().map(|word| word.to_lowercase()) {
+            Some(ref word) if word == "on"  => Command::StackCheck(true),
+            Some(ref word) if word == "off" => Command::StackCheck(false),
+            _ => return Err(format!("usage: {}", help.usage))
+        },
+        "history"   => Command::History,
+        "help"      => Command::Help(args.word().map(|word| word.to_lowercase())),
+        _           => unreachable!()
+    };
+    args.finish(help)?;
+    Ok(command)
+}
+
+// <addr> [hit <n>] [if <condition>]
+fn parse_breakpoint(args: &mut Arguments, help: &CommandHelp) -> Result<Command, String> {
+    let addr = args.value(help)?;
+    let mut from_hit = 1;
+    let mut condition = None;
+    while let Some(word) = args.word() {
+        match &word.to_lowercase()[..] {
+            "hit" if condition.is_none() => {
+                from_hit = parse_number(args.word().unwrap_or(""))
+                    .map_err(|_| format!("'hit' needs a number, usage: {}", help.usage))? as u32;
+                if from_hit == 0 {
+                    return Err(String::from("hits are counted from 1"));
+                }
+            }
+            "if" => {
+                // The condition has its own syntax, so it takes the rest of the line
+                let rest = args.rest().join(" ");
+                condition = Some(Expression::parse(&rest, args.symbols)?);
+            }
+            _ => return Err(format!("unexpected '{}', usage: {}", word, help.usage))
+        }
+    }
+    Ok(Command::Break(addr, condition, from_hit))
+}
+
+// <kind> <start> [end] [== <value>]
+fn parse_watchpoint(args: &mut Arguments, help: &CommandHelp) -> Result<Watchpoint, String> {
+    let kind = match args.word().map(|word| word.to_lowercase()) {
+        Some(ref kind) if kind == "read" || kind == "r"                     => WatchKind::Read,
+        Some(ref kind) if kind == "write" || kind == "w"                    => WatchKind::Write,
+        Some(ref kind) if kind == "access" || kind == "a" || kind == "rw"   => WatchKind::Access,
+        Some(ref kind) if kind == "change" || kind == "c"                   => WatchKind::Change,
+        Some(kind) => return Err(format!("unknown watchpoint kind '{}', use read, write, access or change", kind)),
+        None => return Err(format!("missing argument, usage: {}", help.usage))
+    };
+    let start = args.value(help)?;
+    let mut end = None;
+    let mut value = None;
+    while let Some(word) = args.word() {
+        if word == "==" && value.is_none() {
+            value = Some(args.byte(help)?);
+        } else if end.is_none() && value.is_none() {
+            end = Some(args.parse_value(word)?);
+        } else {
+            return Err(format!("unexpected '{}', usage: {}", word, help.usage));
+        }
+    }
+    let end = end.unwrap_or(start);
+    if end < start {
+        return Err(format!("the end of the range (0x{:04X}) is before its start (0x{:04X})", end, start));
+    }
+    Ok(Watchpoint { kind: kind, start: start, end: end, value: value })
+}
+
+struct Arguments<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+    cpu: &'a CPU,
+    pc: u16,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Arguments<'a> {
+    fn word(&mut self) -> Option<&'a str> {
+        let word = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        word
+    }
+
+    fn value(&mut self, help: &CommandHelp) -> Result<u16, String> {
+        match self.optional_value()? {
+            Some(value) => Ok(value),
+            None => Err(format!("missing argument, usage: {}", help.usage))
+        }
+    }
+
+    fn byte(&mut self, help: &CommandHelp) -> Result<u8, String> {
+        to_byte(self.value(help)?)
+    }
+
+    fn optional_value(&mut self) -> Result<Option<u16>, String> {
+        match self.word() {
+            Some(word) => self.parse_value(word).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn rest(&mut self) -> Vec<&'a str> {
+        let rest = self.tokens.get(self.next..).map(|rest| rest.to_vec()).unwrap_or(Vec::new());
+        self.next = self.tokens.len();
+        rest
+    }
+
+    fn finish(&self, help: &CommandHelp) -> Result<(), String> {
+        if self.next < self.tokens.len() {
+            return Err(format!("too many arguments, usage: {}", help.usage));
+        }
+        Ok(())
+    }
+
+    // Registers first, then labels and then numbers
+    fn parse_value(&self, word: &str) -> Result<u16, String> {
+        if let Some(value) = register_value(word, self.cpu, self.pc) {
+            return Ok(value);
+        }
+        if let Some((_, addr)) = self.symbols.resolve(word) {
+            return Ok(addr);
+        }
+        // Label+offset, as the debugger prints addresses
+        if let Some(plus) = word.find('+') {
+            if let Some((_, addr)) = self.symbols.resolve(&word[..plus]) {
+                return Ok(addr.wrapping_add(parse_number(&word[plus + 1..])?));
+            }
+        }
+        parse_number(word)
+    }
+
+    // <addr> or <bank>:<addr>. Labels in a bank that isn't mapped are read from the ROM.
+    fn parse_location(&self, word: &str) -> Result<Location, String> {
+        if let Some(colon) = word.find(':') {
+            return Ok(Location::Bank(parse_number(&word[..colon])?, self.parse_value(&word[colon + 1..])?));
+        }
+        match self.symbols.resolve(word) {
+            Some((bank, addr)) if addr >= 0x4000 && addr <= 0x7FFF && bank != MAPPED_ROM_BANK => Ok(Location::Bank(bank, addr)),
+            _ => Ok(Location::Memory(self.parse_value(word)?))
+        }
+    }
+}
+
+fn to_byte(value: u16) -> Result<u8, String> {
+    if value > 0xFF {
+        return Err(format!("0x{:X} doesn't fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+pub fn parse_number(word: &str) -> Result<u16, String> {
+    let lower = word.to_lowercase();
+    let (digits, radix) = if lower.starts_with("0x") {
+        (&lower[2..], 16)
+    } else if lower.starts_with("$") {
+        (&lower[1..], 16)
+    } else {
+        (&lower[..], 10)
+    };
+    u16::from_str_radix(digits, radix)
+        .map_err(|_| format!("'{}' is not a 16 bit number, a register or a label", word))
+}
+
+fn register_value(name: &str, cpu: &CPU, pc: u16) -> Option<u16> {
+    let context = Context { cpu: cpu, pc: pc, hits: 0 };
+    Variable::register_by_name(name).map(|register| register.value(&context) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use super::Command;
+    use super::Location;
+    use super::COMMANDS;
+    use hardware::cpu::CPU;
+    use hardware::debugger::expression::Variable;
+    use hardware::debugger::symbols::SymbolTable;
+    use hardware::debugger::watchpoints::WatchKind;
+    use hardware::debugger::watchpoints::Watchpoint;
+    use test_rom;
+
+    const PC : u16 = 0x0150;
+
+    fn parse_with(line: &str, cpu: &CPU) -> Result<Command, String> {
+        let symbols = SymbolTable::parse_sym("00:0150 Main\n02:4000 Music\n").unwrap();
+        parse(line, cpu, PC, &symbols)
+    }
+
+    fn error(line: &str) -> String {
+        let gameboy = test_rom::gameboy(&[]);
+        match parse_with(line, gameboy.cpu()) {
+            Ok(_) => panic!("'{}' parsed", line),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn aliases_are_unique() {
+        let mut names: Vec<&str> = COMMANDS.iter().flat_map(|help| help.names.iter().cloned()).collect();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn aliases_parse_like_their_command() {
+        let gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu();
+        for line in &["step", "s", "n", "STEP"] {
+            match parse_with(line, cpu) { Ok(Command::Step(1)) => {}, _ => panic!("{}", line) }
+        }
+        for line in &["s 5", "n $05", "step 0x5"] {
+            match parse_with(line, cpu) { Ok(Command::Step(5)) => {}, _ => panic!("{}", line) }
+        }
+        for line in &["over", "o"] {
+            match parse_with(line, cpu) { Ok(Command::StepOver) => {}, _ => panic!("{}", line) }
+        }
+        for line in &["out", "finish"] {
+            match parse_with(line, cpu) { Ok(Command::StepOut) => {}, _ => panic!("{}", line) }
+        }
+        for line in &["bt", "where", "backtrace"] {
+            match parse_with(line, cpu) { Ok(Command::Backtrace) => {}, _ => panic!("{}", line) }
+        }
+    }
+
+    #[test]
+    fn arguments_take_registers_labels_and_numbers() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        gameboy.cpu_mut().regs.hl_w(0xC123);
+        let cpu = gameboy.cpu();
+        match parse_with("print hl", cpu) { Ok(Command::Print(0xC123)) => {}, _ => panic!() }
+        match parse_with("p pc", cpu) { Ok(Command::Print(PC)) => {}, _ => panic!() }
+        match parse_with("until Main+3", cpu) { Ok(Command::RunTo(0x0153)) => {}, _ => panic!() }
+        match parse_with("d 0xC000", cpu) { Ok(Command::Dump(0xC000, 0xC03F)) => {}, _ => panic!() }
+        match parse_with("d 0xFFF0", cpu) { Ok(Command::Dump(0xFFF0, 0xFFFF)) => {}, _ => panic!() }
+        match parse_with("da Music", cpu) { Ok(Command::Disassemble(Some(Location::Bank(2, 0x4000)), 10)) => {}, _ => panic!() }
+        match parse_with("da 3:0x4000 4", cpu) { Ok(Command::Disassemble(Some(Location::Bank(3, 0x4000)), 4)) => {}, _ => panic!() }
+        match parse_with("set zf 1", cpu) { Ok(Command::Set(Variable::FlagZ, 1)) => {}, _ => panic!() }
+        match parse_with("write 0xC000 1 2 $ff", cpu) {
+            Ok(Command::Write(0xC000, ref bytes)) => assert_eq!(bytes, &[1, 2, 0xFF]),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu();
+        match parse_with("b Main hit 3 if a == 1 && [hl] > 2", cpu) {
+            Ok(Command::Break(0x0150, Some(ref condition), 3)) => assert_eq!(condition.to_string(), "a == 1 && [hl] > 2"),
+            _ => panic!()
+        }
+        match parse_with("w change 0xC000 0xC00F == 5", cpu) {
+            Ok(Command::Watch(watchpoint)) => assert_eq!(watchpoint, Watchpoint { kind: WatchKind::Change, start: 0xC000, end: 0xC00F, value: Some(5) }),
+            _ => panic!()
+        }
+        match parse_with("w rw 0xFF40", cpu) {
+            Ok(Command::Watch(watchpoint)) => assert_eq!(watchpoint, Watchpoint { kind: WatchKind::Access, start: 0xFF40, end: 0xFF40, value: None }),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn errors_explain_what_is_wrong() {
+        assert_eq!(error(""), "empty command");
+        assert_eq!(error("jumpp 1"), "unknown command 'jumpp'");
+        assert_eq!(error("p"), "missing argument, usage: print <addr>");
+        assert_eq!(error("p 1 2"), "too many arguments, usage: print <addr>");
+        assert_eq!(error("p 0xZZ"), "'0xZZ' is not a 16 bit number, a register or a label");
+        assert_eq!(error("p 65536"), "'65536' is not a 16 bit number, a register or a label");
+        assert_eq!(error("step 0"), "the count must be at least 1");
+        assert_eq!(error("b 0x150 hit 0"), "hits are counted from 1");
+        assert_eq!(error("b 0x150 hit x"), "'hit' needs a number, usage: break <addr> [hit <n>] [if <condition>]");
+        assert_eq!(error("d 0x200 0x100"), "the end of the range (0x0100) is before its start (0x0200)");
+        assert_eq!(error("write 0xFFFF 1 2"), "the bytes go past the end of memory");
+        assert_eq!(error("fill 0xC000 0xC001 0x100"), "0x100 doesn't fit in a byte");
+        assert_eq!(error("set hits 1"), "'hits' is not a register or a flag");
+        assert_eq!(error("w sometimes 0xC000"), "unknown watchpoint kind 'sometimes', use read, write, access or change");
+        assert_eq!(error("sc maybe"), "usage: stackcheck <on|off>");
+    }
+}
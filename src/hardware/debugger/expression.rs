@@ -0,0 +1,484 @@
+// Breakpoint conditions, e.g. "A==0x3 && [HL]>0x10":
+//  values    numbers (decimal, or hex with 0x or $), registers, flags (zf, nf, hf, cf), labels,
+//            hits (times the breakpoint was hit before) and [addr] for the byte at an address
+//  operators ! ~ (unary), * / %, + -, << >>, &, ^, |, == != < <= > >=, &&, ||
+// Operators bind like they do in Rust. Comparisons and logical operators give 1 or 0.
+
+use std::fmt;
+
+use hardware::cpu::CPU;
+use hardware::cpu::CPUFlags;
+use hardware::debugger::command::parse_number;
+use hardware::debugger::symbols::SymbolTable;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Variable {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+    FlagZ, FlagN, FlagH, FlagC,
+    Hits,
+}
+
+const VARIABLE_NAMES: &'static [(&'static str, Variable)] = &[
+    ("a", Variable::A), ("f", Variable::F), ("b", Variable::B), ("c", Variable::C),
+    ("d", Variable::D), ("e", Variable::E), ("h", Variable::H), ("l", Variable::L),
+    ("af", Variable::AF), ("bc", Variable::BC), ("de", Variable::DE), ("hl", Variable::HL),
+    ("sp", Variable::SP), ("pc", Variable::PC),
+    ("zf", Variable::FlagZ), ("nf", Variable::FlagN), ("hf", Variable::FlagH), ("cf", Variable::FlagC),
+    ("hits", Variable::Hits),
+];
+
+/// What the variables of an expression are read from.
+pub struct Context<'a> {
+    pub cpu: &'a CPU,
+    // Address of the instruction we stopped at
+    pub pc: u16,
+    pub hits: u32,
+}
+
+impl Variable {
+    pub fn by_name(name: &str) -> Option<Variable> {
+        let name = name.to_lowercase();
+        VARIABLE_NAMES.iter().find(|entry| entry.0 == name).map(|entry| entry.1)
+    }
+
+    /// Only the CPU registers, for arguments that are addresses or bytes.
+    pub fn register_by_name(name: &str) -> Option<Variable> {
+        match Variable::by_name(name) {
+            Some(Variable::FlagZ) | Some(Variable::FlagN) |
+            Some(Variable::FlagH) | Some(Variable::FlagC) |
+            Some(Variable::Hits) | None => None,
+            register => register
+        }
+    }
+
+    pub fn value(&self, context: &Context) -> u32 {
+        let cpu = context.cpu;
+        let flag = |flag: CPUFlags| (cpu.regs.f.r() & flag as u8 != 0) as u32;
+        match *self {
+            Variable::A     => cpu.regs.a.r() as u32,
+            Variable::F     => cpu.regs.f.r() as u32,
+            Variable::B     => cpu.regs.b.r() as u32,
+            Variable::C     => cpu.regs.c.r() as u32,
+            Variable::D     => cpu.regs.d.r() as u32,
+            Variable::E     => cpu.regs.e.r() as u32,
+            Variable::H     => cpu.regs.h.r() as u32,
+            Variable::L     => cpu.regs.l.r() as u32,
+            Variable::AF    => cpu.regs.af() as u32,
+            Variable::BC    => cpu.regs.bc() as u32,
+            Variable::DE    => cpu.regs.de() as u32,
+            Variable::HL    => cpu.regs.hl() as u32,
+            Variable::SP    => cpu.sp.r() as u32,
+            Variable::PC    => context.pc as u32,
+            Variable::FlagZ => flag(CPUFlags::Z),
+            Variable::FlagN => flag(CPUFlags::N),
+            Variable::FlagH => flag(CPUFlags::H),
+            Variable::FlagC => flag(CPUFlags::C),
+            Variable::Hits  => context.hits,
+        }
+    }
+
+    pub fn set(&self, cpu: &mut CPU, value: u16) -> Result<(), String> {
+        let byte = || if value <= 0xFF {Ok(value as u8)} else {Err(format!("0x{:X} doesn't fit in a byte", value))};
+        let bit = || if value <= 1 {Ok(value == 1)} else {Err(format!("a flag can only be 0 or 1, not {}", value))};
+        match *self {
+            Variable::A     => cpu.regs.a.w(byte()?),
+            // The low nibble of F is always 0
+            Variable::F     => cpu.regs.f.w(byte()? & 0xF0),
+            Variable::B     => cpu.regs.b.w(byte()?),
+            Variable::C     => cpu.regs.c.w(byte()?),
+            Variable::D     => cpu.regs.d.w(byte()?),
+            Variable::E     => cpu.regs.e.w(byte()?),
+            Variable::H     => cpu.regs.h.w(byte()?),
+            Variable::L     => cpu.regs.l.w(byte()?),
+            Variable::AF    => cpu.regs.af_w(value & 0xFFF0),
+            Variable::BC    => cpu.regs.bc_w(value),
+            Variable::DE    => cpu.regs.de_w(value),
+            Variable::HL    => cpu.regs.hl_w(value),
+            Variable::SP    => cpu.sp.w(value),
+            Variable::PC    => cpu.pc.w(value),
+            Variable::FlagZ => cpu.set_flag(CPUFlags::Z, bit()?),
+            Variable::FlagN => cpu.set_flag(CPUFlags::N, bit()?),
+            Variable::FlagH => cpu.set_flag(CPUFlags::H, bit()?),
+            Variable::FlagC => cpu.set_flag(CPUFlags::C, bit()?),
+            Variable::Hits  => return Err(String::from("hits can't be set")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum UnaryOp {
+    Not,
+    Complement,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum BinaryOp {
+    Mul, Div, Rem,
+    Add, Sub,
+    Shl, Shr,
+    BitAnd, BitXor, BitOr,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    And, Or,
+}
+
+// Operators of each precedence level, from the loosest to the tightest
+const BINARY_LEVELS: &'static [&'static [(&'static str, BinaryOp)]] = &[
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne), ("<=", BinaryOp::Le),
+      (">=", BinaryOp::Ge), ("<", BinaryOp::Lt), (">", BinaryOp::Gt)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+];
+
+#[derive(Clone, PartialEq, Debug)]
+enum Node {
+    Number(u32),
+    Variable(Variable),
+    // Byte at the address given by the inner expression
+    Memory(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Expression {
+    root: Node,
+    // As the user typed it, to show it back
+    source: String,
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.source)
+    }
+}
+
+impl Expression {
+    /// Labels are replaced by their addresses when the expression is parsed.
+    pub fn parse(source: &str, symbols: &SymbolTable) -> Result<Expression, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+            symbols: symbols,
+        };
+        if parser.tokens.is_empty() {
+            return Err(String::from("the condition is empty"));
+        }
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}' in the condition", token));
+        }
+        Ok(Expression {
+            root: root,
+            source: String::from(source.trim()),
+        })
+    }
+
+    pub fn eval(&self, context: &Context) -> Result<u32, String> {
+        eval(&self.root, context)
+    }
+}
+
+fn eval(node: &Node, context: &Context) -> Result<u32, String> {
+    let value = match *node {
+        Node::Number(value) => value,
+        Node::Variable(variable) => variable.value(context),
+        Node::Memory(ref addr) => {
+            let addr = eval(addr, context)?;
+            if addr > 0xFFFF {
+                return Err(format!("0x{:X} is not an address", addr));
+            }
+            // Conditions must not trigger watchpoints
+            context.cpu.bus.peek_byte(addr as u16) as u32
+        }
+        Node::Unary(op, ref operand) => {
+            let operand = eval(operand, context)?;
+            match op {
+                UnaryOp::Not        => (operand == 0) as u32,
+                UnaryOp::Complement => !operand,
+            }
+        }
+        // Only evaluate the right side when it matters
+        Node::Binary(BinaryOp::And, ref left, ref right) =>
+            (eval(left, context)? != 0 && eval(right, context)? != 0) as u32,
+        Node::Binary(BinaryOp::Or, ref left, ref right) =>
+            (eval(left, context)? != 0 || eval(right, context)? != 0) as u32,
+        Node::Binary(op, ref left, ref right) => {
+            let left = eval(left, context)?;
+            let right = eval(right, context)?;
+            match op {
+                BinaryOp::Mul       => left.wrapping_mul(right),
+                BinaryOp::Div       => left.checked_div(right).ok_or("division by zero")?,
+                BinaryOp::Rem       => left.checked_rem(right).ok_or("division by zero")?,
+                BinaryOp::Add       => left.wrapping_add(right),
+                BinaryOp::Sub       => left.wrapping_sub(right),
+                BinaryOp::Shl       => left.checked_shl(right).unwrap_or(0),
+                BinaryOp::Shr       => left.checked_shr(right).unwrap_or(0),
+                BinaryOp::BitAnd    => left & right,
+                BinaryOp::BitXor    => left ^ right,
+                BinaryOp::BitOr     => left | right,
+                BinaryOp::Eq        => (left == right) as u32,
+                BinaryOp::Ne        => (left != right) as u32,
+                BinaryOp::Lt        => (left < right) as u32,
+                BinaryOp::Le        => (left <= right) as u32,
+                BinaryOp::Gt        => (left > right) as u32,
+                BinaryOp::Ge        => (left >= right) as u32,
+                BinaryOp::And | BinaryOp::Or => unreachable!()
+            }
+        }
+    };
+    Ok(value)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    // Numbers and names
+    Word(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Word(ref word) => write!(fmt, "{}", word),
+            Token::Symbol(symbol) => write!(fmt, "{}", symbol),
+        }
+    }
+}
+
+// Longest first, so "<<" isn't read as two "<"
+const SYMBOLS: &'static [&'static str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "&", "^", "<", ">", "+", "-", "*", "/", "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(*symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            // Labels can have . @ and # in them
+            let length = rest.find(|c: char| !(c.is_alphanumeric() || "_$.@#".contains(c)))
+                .unwrap_or(rest.len());
+            if length == 0 {
+                return Err(format!("unexpected '{}' in the condition", rest.chars().next().unwrap()));
+            }
+            tokens.push(Token::Word(String::from(&rest[..length])));
+            rest = &rest[length..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    next: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn take_symbol(&mut self, symbol: &str) -> bool {
+        let found = match self.peek() {
+            Some(&Token::Symbol(s)) => s == symbol,
+            _ => false
+        };
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.take_symbol(symbol) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(token) => Err(format!("expected '{}' but found '{}' in the condition", symbol, token)),
+                None => Err(format!("expected '{}' at the end of the condition", symbol))
+            }
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == BINARY_LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = BINARY_LEVELS[level].iter()
+                .find(|&&(symbol, _)| self.take_symbol(symbol))
+                .map(|&(_, op)| op);
+            match op {
+                Some(op) => {
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(op, Box::new(left), Box::new(right));
+                }
+                None => return Ok(left)
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.take_symbol("!") {
+            return Ok(Node::Unary(UnaryOp::Not, Box::new(self.unary()?)));
+        }
+        if self.take_symbol("~") {
+            return Ok(Node::Unary(UnaryOp::Complement, Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        if self.take_symbol("(") {
+            let node = self.binary(0)?;
+            self.expect_symbol(")")?;
+            return Ok(node);
+        }
+        if self.take_symbol("[") {
+            let addr = self.binary(0)?;
+            self.expect_symbol("]")?;
+            return Ok(Node::Memory(Box::new(addr)));
+        }
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        match token {
+            Some(Token::Word(word)) => match (Variable::by_name(&word), self.symbols.resolve(&word)) {
+                (Some(variable), _) => Ok(Node::Variable(variable)),
+                (None, Some((_, addr))) => Ok(Node::Number(addr as u32)),
+                (None, None) => parse_number(&word).map(|value| Node::Number(value as u32))
+            },
+            Some(token) => Err(format!("unexpected '{}' in the condition", token)),
+            None => Err(String::from("the condition ends too early"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+    use super::Expression;
+    use super::Variable;
+    use hardware::cpu::CPU;
+    use hardware::debugger::symbols::SymbolTable;
+    use test_rom;
+
+    fn eval(source: &str, cpu: &CPU) -> Result<u32, String> {
+        let symbols = SymbolTable::parse_sym("00:0150 Main\n00:c000 wCounter\n").unwrap();
+        let context = Context { cpu: cpu, pc: 0x0150, hits: 3 };
+        Expression::parse(source, &symbols)?.eval(&context)
+    }
+
+    #[test]
+    fn operators_bind_like_rust() {
+        let gameboy = test_rom::gameboy(&[]);
+        let cases: &[(&str, u32)] = &[
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("1 << 2 + 1", 8),
+            ("0x0F & 0x3C | 0x40", 0x4C),
+            ("6 ^ 3 & 1", 7),
+            ("1 + 1 == 2", 1),
+            ("2 > 1 == 1", 1),
+            ("!0 + (~0xFFFE & 0xFFFF)", 2),
+            ("$ff % 16", 15),
+            ("pc == Main", 1),
+            ("hits", 3),
+        ];
+        for &(source, value) in cases {
+            assert_eq!(eval(source, gameboy.cpu()), Ok(value), "{}", source);
+        }
+    }
+
+    #[test]
+    fn brackets_read_memory() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        {
+            let cpu = gameboy.cpu_mut();
+            cpu.regs.hl_w(0xC001);
+            cpu.bus.poke_byte(0xC000, 0x12);
+            cpu.bus.poke_byte(0xC001, 0x34);
+        }
+        let cpu = gameboy.cpu();
+        assert_eq!(eval("[HL]", cpu), Ok(0x34));
+        assert_eq!(eval("[hl - 1] == 0x12", cpu), Ok(1));
+        assert_eq!(eval("[wCounter] + [wCounter + 1]", cpu), Ok(0x46));
+        assert_eq!(eval("[0xFFFF + 1]", cpu), Err(String::from("0x10000 is not an address")));
+    }
+
+    #[test]
+    fn any_address_can_be_read() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        gameboy.cpu_mut().bus.write_byte(0xFF46, 0xC1);
+        let cpu = gameboy.cpu();
+        assert_eq!(eval("[0xFF46] == 0xC1", cpu), Ok(1));
+        assert_eq!(eval("[0xE001] == [wCounter + 1]", cpu), Ok(1));
+        assert_eq!(eval("[0xFEA0]", cpu), Ok(0xFF));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu();
+        assert_eq!(eval("0 && 1 / 0", cpu), Ok(0));
+        assert_eq!(eval("1 || 1 / 0", cpu), Ok(1));
+        assert_eq!(eval("1 && 1 / 0", cpu), Err(String::from("division by zero")));
+        assert_eq!(eval("2 && 3 || 0", cpu), Ok(1));
+        assert_eq!(eval("0 || 0 && 1", cpu), Ok(0));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu();
+        let error = |source| eval(source, cpu).err().unwrap();
+        assert_eq!(error(""), "the condition is empty");
+        assert_eq!(error("1 +"), "the condition ends too early");
+        assert_eq!(error("(1 + 2"), "expected ')' at the end of the condition");
+        assert_eq!(error("[hl 1]"), "expected ']' but found '1' in the condition");
+        assert_eq!(error("1 2"), "unexpected '2' in the condition");
+        assert_eq!(error("a = 1"), "unexpected '=' in the condition");
+        assert_eq!(error("nowhere"), "'nowhere' is not a 16 bit number, a register or a label");
+    }
+
+    #[test]
+    fn variables_can_be_set() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        let set = |name: &str, value: u16, cpu: &mut CPU| Variable::by_name(name).unwrap().set(cpu, value);
+        assert_eq!(set("a", 0x42, cpu), Ok(()));
+        assert_eq!(set("HL", 0xC0DE, cpu), Ok(()));
+        assert_eq!(set("sp", 0xDFF0, cpu), Ok(()));
+        assert_eq!(set("pc", 0x0200, cpu), Ok(()));
+        assert_eq!((cpu.regs.a.r(), cpu.regs.hl(), cpu.sp.r(), cpu.pc.r()), (0x42, 0xC0DE, 0xDFF0, 0x0200));
+
+        assert_eq!(set("f", 0xFF, cpu), Ok(()));
+        assert_eq!(cpu.regs.f.r(), 0xF0);
+        assert_eq!(set("zf", 0, cpu), Ok(()));
+        assert_eq!(set("cf", 0, cpu), Ok(()));
+        assert_eq!(cpu.regs.f.r(), 0x60);
+        assert_eq!(set("af", 0x12FF, cpu), Ok(()));
+        assert_eq!(cpu.regs.af(), 0x12F0);
+
+        let b = cpu.regs.b.r();
+        assert_eq!(set("b", 0x100, cpu), Err(String::from("0x100 doesn't fit in a byte")));
+        assert_eq!(cpu.regs.b.r(), b);
+        assert_eq!(set("nf", 2, cpu), Err(String::from("a flag can only be 0 or 1, not 2")));
+        assert_eq!(set("hits", 0, cpu), Err(String::from("hits can't be set")));
+    }
+}
@@ -0,0 +1,478 @@
+// GDB remote serial protocol, so gdb or a script can drive the debugger over TCP:
+//  $m150,4#f6    reads 4 bytes at 0x0150
+// Registers are 16 bits, little endian, in the order of GDB's z80 target: AF BC DE HL SP PC.
+// Breakpoints (Z0, Z1) and watchpoints (Z2 write, Z3 read, Z4 access) are added to the
+// ones of the debugger, so its own commands list them too.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+
+use hardware::cpu::CPU;
+use hardware::debugger::breakpoints::Breakpoints;
+use hardware::debugger::expression::Context;
+use hardware::debugger::expression::Variable;
+use hardware::debugger::watchpoints::WatchHit;
+use hardware::debugger::watchpoints::WatchKind;
+use hardware::debugger::watchpoints::Watchpoint;
+
+const REGISTERS : [Variable; 6] = [Variable::AF, Variable::BC, Variable::DE, Variable::HL, Variable::SP, Variable::PC];
+
+// Signals of the stop replies
+const SIGINT    : u8 = 2;
+const SIGTRAP   : u8 = 5;
+// Sent by GDB out of a packet to stop the program, e.g. on Ctrl-C
+const INTERRUPT : u8 = 0x03;
+
+/// What the program does once GDB lets it go.
+pub enum Resume {
+    Continue,
+    Step,
+    // The client left, the program runs on
+    Detach,
+    // The client asked to end the emulator
+    Kill,
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    // Until the client asks for QStartNoAckMode every packet is acknowledged
+    ack: bool,
+    // Whether the client waits for a stop reply, after a continue or a step
+    running: bool,
+    interrupted: bool,
+    last_stop: String,
+    // Sent again when the client answers with a -
+    last_packet: String,
+    // Addresses and debugger ids of the breakpoints the client set
+    breakpoints: Vec<(u16, usize)>,
+    // Z packet type, address, length and debugger id of the client's watchpoints
+    watchpoints: Vec<(u8, u16, u16, usize)>,
+}
+
+impl GdbStub {
+    /// Waits for a client on `addr`, like "localhost:2345".
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        // Other clients can connect after this one leaves
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener: listener,
+            client: Some(stream),
+            ack: true,
+            running: false,
+            interrupted: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            last_packet: String::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Called while the program runs, true when the program should stop
+    /// because the client interrupted it or a new client connected.
+    pub fn poll(&mut self, cpu: &mut CPU, breakpoints: &mut Breakpoints) -> bool {
+        if self.client.is_none() {
+            return match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nonblocking(false);
+                    let _ = stream.set_nodelay(true);
+                    self.client = Some(stream);
+                    self.ack = true;
+                    self.running = false;
+                    true
+                }
+                Err(_) => false
+            };
+        }
+
+        let mut buf = [0u8; 64];
+        let read = {
+            let stream = self.client.as_mut().unwrap();
+            let _ = stream.set_nonblocking(true);
+            let read = stream.read(&mut buf);
+            let _ = stream.set_nonblocking(false);
+            read
+        };
+        match read {
+            Ok(0) => {self.disconnect(cpu, breakpoints); false}
+            Ok(length) if buf[..length].contains(&INTERRUPT) => {self.interrupted = true; true}
+            Ok(_) => false,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(_) => {self.disconnect(cpu, breakpoints); false}
+        }
+    }
+
+    /// Answers the client while the program is stopped.
+    pub fn serve(&mut self, watch_hit: Option<WatchHit>, cpu: &mut CPU, breakpoints: &mut Breakpoints) -> Resume {
+        if self.running {
+            self.last_stop = self.stop_reply(watch_hit);
+            self.running = false;
+            let reply = self.last_stop.clone();
+            self.send(&reply);
+        }
+
+        loop {
+            let packet = match self.receive() {
+                Some(packet) => packet,
+                None => {self.disconnect(cpu, breakpoints); return Resume::Detach;}
+            };
+            // Only X packets carry binary data, and those aren't supported
+            if !packet.is_ascii() {
+                self.send("E01");
+                continue;
+            }
+            let reply = match packet.as_bytes()[0] {
+                b'?' => self.last_stop.clone(),
+                b'g' => REGISTERS.iter().map(|register| to_hex_word(register_value(*register, cpu))).collect(),
+                b'G' => write_registers(&packet[1..], cpu),
+                b'p' => match parse_hex(&packet[1..]).and_then(|index| REGISTERS.get(index as usize)) {
+                    Some(register) => to_hex_word(register_value(*register, cpu)),
+                    None => String::from("E01")
+                },
+                b'P' => write_register(&packet[1..], cpu),
+                b'm' => read_memory(&packet[1..], cpu),
+                b'M' => write_memory(&packet[1..], cpu),
+                b'Z' => self.insert(&packet[1..], cpu, breakpoints),
+                b'z' => self.remove(&packet[1..], cpu, breakpoints),
+                b'c' | b's' => {
+                    // An address to resume at can follow
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        cpu.pc.w(addr);
+                    }
+                    self.running = true;
+                    return if packet.starts_with('c') {Resume::Continue} else {Resume::Step};
+                }
+                b'D' => {
+                    self.send("OK");
+                    self.disconnect(cpu, breakpoints);
+                    return Resume::Detach;
+                }
+                b'k' => {
+                    self.disconnect(cpu, breakpoints);
+                    return Resume::Kill;
+                }
+                b'H' => String::from("OK"),
+                _ if packet == "QStartNoAckMode" => {
+                    self.send("OK");
+                    self.ack = false;
+                    continue;
+                }
+                _ if packet.starts_with("qSupported") => String::from("PacketSize=4000;QStartNoAckMode+"),
+                _ if packet == "qAttached" => String::from("1"),
+                // Anything else isn't supported, which GDB is told with an empty reply
+                _ => String::new()
+            };
+            self.send(&reply);
+        }
+    }
+
+    fn stop_reply(&mut self, watch_hit: Option<WatchHit>) -> String {
+        if self.interrupted {
+            self.interrupted = false;
+            return format!("S{:02x}", SIGINT);
+        }
+        let watch = watch_hit.and_then(|hit| {
+            self.watchpoints.iter().find(|watchpoint| watchpoint.3 == hit.id).map(|watchpoint| (watchpoint.0, hit.addr))
+        });
+        match watch {
+            Some((2, addr)) => format!("T{:02x}watch:{:04x};", SIGTRAP, addr),
+            Some((3, addr)) => format!("T{:02x}rwatch:{:04x};", SIGTRAP, addr),
+            Some((_, addr)) => format!("T{:02x}awatch:{:04x};", SIGTRAP, addr),
+            None => format!("S{:02x}", SIGTRAP)
+        }
+    }
+
+    // Z<type>,<addr>,<kind or length>
+    fn insert(&mut self, args: &str, cpu: &mut CPU, breakpoints: &mut Breakpoints) -> String {
+        let (kind, addr, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return String::from("E01")
+        };
+        match kind {
+            0 | 1 => {
+                let id = breakpoints.add(addr, None, 1);
+                self.breakpoints.push((addr, id));
+            }
+            2 | 3 | 4 => {
+                let watchpoint = Watchpoint {
+                    kind: match kind {2 => WatchKind::Write, 3 => WatchKind::Read, _ => WatchKind::Access},
+                    start: addr,
+                    end: addr.saturating_add(length.max(1) - 1),
+                    value: None,
+                };
+                let id = cpu.bus.watchpoints.add(watchpoint);
+                self.watchpoints.push((kind, addr, length, id));
+            }
+            _ => return String::new()
+        }
+        String::from("OK")
+    }
+
+    fn remove(&mut self, args: &str, cpu: &mut CPU, breakpoints: &mut Breakpoints) -> String {
+        let (kind, addr, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return String::from("E01")
+        };
+        match kind {
+            0 | 1 => match self.breakpoints.iter().position(|&(bp_addr, _)| bp_addr == addr) {
+                Some(index) => {breakpoints.remove(self.breakpoints.remove(index).1);}
+                None => return String::from("E02")
+            },
+            2 | 3 | 4 => match self.watchpoints.iter().position(|&(wp_kind, wp_addr, wp_length, _)| {
+                wp_kind == kind && wp_addr == addr && wp_length == length
+            }) {
+                Some(index) => {cpu.bus.watchpoints.remove(self.watchpoints.remove(index).3);}
+                None => return String::from("E02")
+            },
+            _ => return String::new()
+        }
+        String::from("OK")
+    }
+
+    // The points of a client that left would stop the program for nobody
+    fn disconnect(&mut self, cpu: &mut CPU, breakpoints: &mut Breakpoints) {
+        for (_, id) in self.breakpoints.drain(..) {
+            breakpoints.remove(id);
+        }
+        for (_, _, _, id) in self.watchpoints.drain(..) {
+            cpu.bus.watchpoints.remove(id);
+        }
+        self.client = None;
+        self.running = false;
+        self.interrupted = false;
+        println!("GDB disconnected");
+    }
+
+    // $<data>#<checksum>, or None once the client is gone
+    fn receive(&mut self) -> Option<String> {
+        loop {
+            // Interrupts between packets don't matter while stopped
+            loop {
+                match self.read_byte()? {
+                    b'$' => break,
+                    b'-' => {
+                        let packet = self.last_packet.clone();
+                        self.write_raw(packet.as_bytes());
+                    }
+                    _ => {}
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = ::std::str::from_utf8(&checksum).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .map_or(false, |checksum| checksum == compute_checksum(&data));
+            if self.ack {
+                self.write_raw(if valid {b"+"} else {b"-"});
+            }
+            if valid && !data.is_empty() {
+                return Some(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, compute_checksum(data.as_bytes()));
+        self.write_raw(packet.as_bytes());
+        self.last_packet = packet;
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+        match self.client {
+            Some(ref mut stream) => match stream.read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None
+            },
+            None => None
+        }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) {
+        if let Some(ref mut stream) = self.client {
+            let _ = stream.write_all(bytes);
+        }
+    }
+}
+
+fn compute_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn register_value(register: Variable, cpu: &CPU) -> u16 {
+    let context = Context {
+        cpu: cpu,
+        pc: cpu.pc.r(),
+        hits: 0,
+    };
+    register.value(&context) as u16
+}
+
+// <n>=<value>
+fn write_register(args: &str, cpu: &mut CPU) -> String {
+    let mut parts = args.splitn(2, '=');
+    let register = parts.next().and_then(parse_hex).and_then(|index| REGISTERS.get(index as usize));
+    let value = parts.next().and_then(from_hex_word);
+    match (register, value) {
+        (Some(register), Some(value)) => match register.set(cpu, value) {
+            Ok(()) => String::from("OK"),
+            Err(_) => String::from("E01")
+        },
+        _ => String::from("E01")
+    }
+}
+
+fn write_registers(args: &str, cpu: &mut CPU) -> String {
+    let values: Vec<Option<u16>> = (0..REGISTERS.len())
+        .map(|index| args.get(index * 4..index * 4 + 4).and_then(from_hex_word))
+        .collect();
+    if values.iter().any(|value| value.is_none()) {
+        return String::from("E01");
+    }
+    for (register, value) in REGISTERS.iter().zip(values) {
+        let _ = register.set(cpu, value.unwrap());
+    }
+    String::from("OK")
+}
+
+// <addr>,<length>, without the watchpoints seeing it
+fn read_memory(args: &str, cpu: &CPU) -> String {
+    let (addr, length) = match parse_range(args) {
+        Some(range) => range,
+        None => return String::from("E01")
+    };
+    let end = (addr as u32 + length as u32).min(0x10000);
+    (addr as u32..end).map(|addr| format!("{:02x}", cpu.bus.peek_byte(addr as u16))).collect()
+}
+
+// <addr>,<length>:<bytes>, written like the debugger's write command so ROM can be patched
+fn write_memory(args: &str, cpu: &mut CPU) -> String {
+    let mut parts = args.splitn(2, ':');
+    let range = parts.next().and_then(parse_range);
+    let bytes = parts.next().and_then(from_hex_bytes);
+    let (addr, bytes) = match (range, bytes) {
+        (Some((addr, length)), Some(bytes)) if bytes.len() == length as usize => (addr, bytes),
+        _ => return String::from("E01")
+    };
+    for (offset, byte) in bytes.iter().enumerate() {
+        cpu.bus.poke_byte(addr.wrapping_add(offset as u16), *byte);
+    }
+    String::from("OK")
+}
+
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let mut parts = args.splitn(2, ',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(addr), Some(length)) => Some((addr, length)),
+        _ => None
+    }
+}
+
+// <type>,<addr>,<kind>, extra conditions after a ; are ignored
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let args = args.split(';').next().unwrap();
+    let mut parts = args.splitn(3, ',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(kind), Some(addr), Some(length)) if kind <= 4 => Some((kind as u8, addr, length)),
+        _ => None
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// Register values are sent low byte first
+fn to_hex_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+}
+
+fn from_hex_word(text: &str) -> Option<u16> {
+    match from_hex_bytes(text) {
+        Some(ref bytes) if bytes.len() == 2 => Some((bytes[1] as u16) << 8 | bytes[0] as u16),
+        _ => None
+    }
+}
+
+// Pairs of hex digits. Works on the bytes, so that a stray UTF-8 character
+// is just an invalid digit and not a slice across a character
+fn from_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits.chunks(2).map(|pair| {
+        let high = (pair[0] as char).to_digit(16)?;
+        let low = (pair[1] as char).to_digit(16)?;
+        Some((high << 4 | low) as u8)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_hex_word;
+    use super::read_memory;
+    use super::write_memory;
+    use super::write_registers;
+    use test_rom;
+
+    #[test]
+    fn memory_writes_go_through_the_bus() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        assert_eq!(write_memory("c000,3:0aff7e", cpu), "OK");
+        assert_eq!(read_memory("c000,3", cpu), "0aff7e");
+        // Reads stop at the end of the memory map
+        assert_eq!(read_memory("ffff,4", cpu).len(), 2);
+    }
+
+    #[test]
+    fn io_registers_and_echo_ram_can_be_read() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        assert_eq!(read_memory("ff00,80", cpu).len(), 0x100);
+        assert_eq!(write_memory("c010,2:beef", cpu), "OK");
+        assert_eq!(read_memory("e010,2", cpu), "beef");
+    }
+
+    #[test]
+    fn malformed_memory_writes_are_refused() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        for packet in &["c000,2:\u{FFFD}a", "c000,2:12", "c000,1:1g", "c000:12", "c000,1:123"] {
+            assert_eq!(write_memory(packet, cpu), "E01", "{}", packet);
+        }
+        assert_eq!(read_memory("c000,1", cpu), "00");
+    }
+
+    #[test]
+    fn registers_are_little_endian_words() {
+        assert_eq!(from_hex_word("3412"), Some(0x1234));
+        assert_eq!(from_hex_word("a\u{FFFD}"), None);
+        assert_eq!(from_hex_word("123"), None);
+
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        assert_eq!(write_registers("ff011300d8004d01feff5001", cpu), "OK");
+        // The low nibble of F always reads 0
+        assert_eq!(cpu.regs.af(), 0x01F0);
+        assert_eq!(cpu.pc.r(), 0x0150);
+        assert_eq!(write_registers("ff01", cpu), "E01");
+    }
+}
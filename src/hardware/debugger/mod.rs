@@ -0,0 +1,722 @@
+use hardware::cpu::CPU;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use hardware::disassembler;
+use hardware::video::gpu_constants::LY_COORD_ADDR;
+use hardware::video::gpu_constants::VBLANK_START_LINE;
+
+mod command;
+mod expression;
+mod gdb;
+mod trace;
+pub mod breakpoints;
+pub mod watchpoints;
+pub mod symbols;
+
+use self::breakpoints::Breakpoints;
+use self::command::Command;
+use self::command::Location;
+use self::gdb::GdbStub;
+use self::gdb::Resume;
+use self::trace::Trace;
+use self::symbols::SymbolTable;
+use self::watchpoints::WatchHit;
+use hardware::call_stack::StackImbalance;
+
+const DEBUG_ACTIVATED: bool = false;
+// Instructions shown before and after the PC when the program stops
+const WINDOW_BEFORE : usize = 3;
+const WINDOW_AFTER  : usize = 6;
+const ROM_BANK_SIZE : usize = 0x4000;
+
+#[derive(PartialEq)]
+enum DebuggerState {
+    RUN,
+    STEP,
+    // Instructions left to run
+    COUNT(u32),
+    // Runs until the call depth is back to this, for step over and step out
+    DEPTH(usize),
+    UNTIL(u16),
+    // Stops at the first instruction of the next frame
+    FRAME,
+    // Stops when the next VBlank starts, true once we are out of the current one
+    VBLANK(bool),
+}
+
+pub struct Debugger {
+    breakpoints: Breakpoints,
+    state: DebuggerState,
+    activated: bool,
+    history: Vec<String>,
+    // Watchpoint hit by the last instruction and the address of that instruction
+    watch_hit: Option<(WatchHit, u16)>,
+    // Whether a stack imbalance stops the program
+    stack_check: bool,
+    imbalance: Option<StackImbalance>,
+    symbols: SymbolTable,
+    // Takes over the prompt while a GDB client is connected
+    gdb: Option<GdbStub>,
+    trace: Option<Trace>,
+    // Set when GDB kills the program, the frontend then shuts down
+    quit: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Breakpoints::new(),
+            state: DebuggerState::RUN,
+            activated: DEBUG_ACTIVATED,
+            history: Vec::new(),
+            watch_hit: None,
+            stack_check: false,
+            imbalance: None,
+            symbols: SymbolTable::new(),
+            gdb: None,
+            trace: None,
+            quit: false,
+        }
+    }
+    
+    pub fn enter_debug_mode(&mut self) {
+        self.state = DebuggerState::STEP;
+    }
+
+    /// Labels used in addresses and shown by the disassembly.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Logs the instructions to `path` in gameboy-doctor format. With `last`, only that
+    /// many are kept and they are written when the game panics or hits a breakpoint.
+    pub fn start_trace(&mut self, path: &Path, last: Option<usize>) -> Result<(), String> {
+        let trace = match last {
+            Some(length) => Trace::ring(path, length),
+            None => Trace::to_file(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?
+        };
+        self.trace = Some(trace);
+        Ok(())
+    }
+
+    /// Whether the program should end, once the current frame is over.
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Waits for GDB to connect on `addr`, then stops before the next instruction.
+    pub fn listen_gdb(&mut self, addr: &str) -> Result<(), String> {
+        let stub = GdbStub::listen(addr).map_err(|e| format!("can't wait for GDB on {}: {}", addr, e))?;
+        self.gdb = Some(stub);
+        self.state = DebuggerState::STEP;
+        Ok(())
+    }
+
+    pub fn stop_if_needed(&mut self, pc: u16, cpu: &mut CPU) {
+        if let Some(ref mut trace) = self.trace {
+            trace.record(cpu);
+        }
+        if self.activated {
+            let instruction = disassembler::decode(pc, |addr| cpu.bus.peek_byte(addr));
+            println!("{}", instruction.format_with(|addr| self.label(addr)));
+        }
+
+        let stops = self.breakpoints.check(pc, cpu);
+        for &(id, ref result) in stops.iter() {
+            match *result {
+                Ok(()) => println!("Breakpoint {} hit at {}", id, self.addr_name(pc)),
+                Err(ref e) => println!("Error in the condition of breakpoint {}: {}", id, e)
+            }
+        }
+
+        let watch_hit = self.watch_hit.take();
+        if let Some((hit, hit_pc)) = watch_hit {
+            println!("Watchpoint {} hit: {} by the instruction at {}", hit.id, hit, self.addr_name(hit_pc));
+        }
+
+        let imbalance = self.imbalance.take();
+        if let Some(ref imbalance) = imbalance {
+            println!("Stack imbalance at {}", imbalance);
+        }
+
+        // Stepping isn't worth a dump, anything that caught the program is
+        if !stops.is_empty() || watch_hit.is_some() || imbalance.is_some() {
+            self.dump_trace();
+        }
+
+        if self.state_stops(pc, cpu) || !stops.is_empty() || watch_hit.is_some() || imbalance.is_some() {
+            if self.gdb.as_ref().map_or(false, |gdb| gdb.is_connected()) {
+                self.serve_gdb(watch_hit.map(|(hit, _)| hit), cpu);
+            } else {
+                self.activated = true;
+                self.stop_and_ask(pc, cpu);
+            }
+        }
+
+        // Only accesses made by the instruction itself count
+        cpu.bus.watchpoints.clear_hit();
+    }
+
+    /// Called by the CPU before it runs the first instruction of a frame.
+    pub fn start_frame(&mut self, cpu: &mut CPU) {
+        if self.state == DebuggerState::FRAME {
+            self.state = DebuggerState::STEP;
+        }
+        // GDB is only listened to once per frame, checking the socket is slow
+        let interrupted = match self.gdb {
+            Some(ref mut gdb) => gdb.poll(cpu, &mut self.breakpoints),
+            None => false
+        };
+        if interrupted {
+            self.state = DebuggerState::STEP;
+        }
+    }
+
+    fn dump_trace(&self) {
+        if let Some(ref trace) = self.trace {
+            if let Err(e) = trace.dump() {
+                println!("Error: can't write the trace: {}", e);
+            }
+        }
+    }
+
+    fn serve_gdb(&mut self, watch_hit: Option<WatchHit>, cpu: &mut CPU) {
+        let resume = match self.gdb {
+            Some(ref mut gdb) => gdb.serve(watch_hit, cpu, &mut self.breakpoints),
+            None => return
+        };
+        self.state = match resume {
+            Resume::Continue | Resume::Detach => DebuggerState::RUN,
+            Resume::Step => DebuggerState::STEP,
+            Resume::Kill => {
+                println!("GDB killed the program");
+                self.quit = true;
+                DebuggerState::RUN
+            }
+        };
+    }
+
+    // Whether the current state asks to stop before the instruction at `pc`
+    fn state_stops(&mut self, pc: u16, cpu: &CPU) -> bool {
+        match self.state {
+            DebuggerState::RUN | DebuggerState::FRAME => false,
+            DebuggerState::STEP => true,
+            DebuggerState::COUNT(left) => {
+                if left > 1 {
+                    self.state = DebuggerState::COUNT(left - 1);
+                }
+                left <= 1
+            }
+            DebuggerState::DEPTH(depth) => cpu.call_stack.depth() <= depth,
+            DebuggerState::UNTIL(addr) => pc == addr,
+            DebuggerState::VBLANK(left_vblank) => {
+                let in_vblank = cpu.bus.peek_byte(LY_COORD_ADDR) >= VBLANK_START_LINE;
+                if !in_vblank {
+                    self.state = DebuggerState::VBLANK(true);
+                }
+                in_vblank && left_vblank
+            }
+        }
+    }
+
+    /// Called after the instruction at `pc` ran, stops before the next one
+    /// if it hit a watchpoint or unbalanced the stack.
+    pub fn after_step(&mut self, pc: u16, cpu: &mut CPU) {
+        if let Some(hit) = cpu.bus.watchpoints.take_hit() {
+            self.watch_hit = Some((hit, pc));
+        }
+        let imbalance = cpu.call_stack.take_imbalance();
+        if self.stack_check {
+            self.imbalance = imbalance;
+        }
+    }
+
+    fn stop_and_ask(&mut self, pc: u16, cpu: &mut CPU) {
+        println!("DEBUGGER");
+        println!("================");
+        println!("Program stopped at address {}", self.addr_name(pc));
+        println!("Processor state:\n{}", cpu);
+        self.print_window(pc, cpu);
+
+        loop {
+            let line = match read_command() {
+                Some(line) => line,
+                // Nobody is left to type commands, so let the game run
+                None => {self.state = DebuggerState::RUN; return;}
+            };
+            let line = match self.expand_history(&line) {
+                Ok(Some(line)) => line,
+                Ok(None) => continue,
+                Err(e) => {println!("Error: {}", e); continue;}
+            };
+
+            // "set pc" and "jump" move it
+            let pc = cpu.pc.r();
+            match command::parse(&line, cpu, pc, &self.symbols) {
+                Ok(command) => {
+                    if self.history.last() != Some(&line) {
+                        self.history.push(line);
+                    }
+                    if self.execute(command, pc, cpu) {
+                        return;
+                    }
+                }
+                Err(e) => println!("Error: {}. Type 'help' for the list of commands.", e)
+            }
+        }
+    }
+
+    // Empty input repeats the last command and "!n" the nth one in the history
+    fn expand_history(&self, line: &str) -> Result<Option<String>, String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(self.history.last().cloned());
+        }
+        if line.starts_with("!") {
+            let index: usize = line[1..].parse()
+                .map_err(|_| format!("'{}' is not a history entry, use !<n>", line))?;
+            return match self.history.get(index) {
+                Some(entry) => {println!("{}", entry); Ok(Some(entry.clone()))}
+                None => Err(format!("there is no command {} in the history", index))
+            };
+        }
+        Ok(Some(String::from(line)))
+    }
+
+    // Returns true when the program should resume
+    fn execute(&mut self, command: Command, pc: u16, cpu: &mut CPU) -> bool {
+        match command {
+            Command::Step(1) => {self.state = DebuggerState::STEP; return true;}
+            Command::Step(count) => {self.state = DebuggerState::COUNT(count); return true;}
+            // A CALL or RST that jumps goes one level deeper, and we stop once it returns
+            Command::StepOver => {self.state = DebuggerState::DEPTH(cpu.call_stack.depth()); return true;}
+            Command::StepOut => {
+                if cpu.call_stack.depth() == 0 {
+                    println!("Error: the program is not inside a call");
+                } else {
+                    self.state = DebuggerState::DEPTH(cpu.call_stack.depth() - 1);
+                    return true;
+                }
+            }
+            Command::RunTo(addr) => {self.state = DebuggerState::UNTIL(addr); return true;}
+            Command::NextFrame => {self.state = DebuggerState::FRAME; return true;}
+            Command::NextVBlank => {
+                let in_vblank = cpu.bus.peek_byte(LY_COORD_ADDR) >= VBLANK_START_LINE;
+                self.state = DebuggerState::VBLANK(!in_vblank);
+                return true;
+            }
+            Command::Continue => {self.state = DebuggerState::RUN; return true;}
+            Command::Print(addr) => {self.print_addr(addr, cpu);}
+            Command::Dump(start, end) => {self.print_range(start, end, cpu);}
+            Command::Registers => {println!("Processor state:\n{}", cpu);}
+            Command::Tiles => {cpu.bus.gpu.tile_data.dump_tiles();}
+            Command::Break(addr, condition, from_hit) => {
+                let id = self.breakpoints.add(addr, condition, from_hit);
+                println!("Breakpoint {}: {}", id, self.breakpoints.list().last().unwrap().1.format_with(|addr| self.symbols.describe(addr)));
+            }
+            Command::Set(variable, value) => {
+                match variable.set(cpu, value) {
+                    Ok(()) => println!("Processor state:\n{}", cpu),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            Command::Jump(addr) => {
+                cpu.pc.w(addr);
+                println!("PC moved to {}", self.addr_name(addr));
+            }
+            Command::Write(start, bytes) => {
+                for (offset, byte) in bytes.iter().enumerate() {
+                    cpu.bus.poke_byte(start + offset as u16, *byte);
+                }
+                self.print_range(start, start + (bytes.len() - 1) as u16, cpu);
+            }
+            Command::Fill(start, end, byte) => {
+                for addr in start as u32..end as u32 + 1 {
+                    cpu.bus.poke_byte(addr as u16, byte);
+                }
+                println!("Filled 0x{:04X}-0x{:04X} with 0x{:02X}", start, end, byte);
+            }
+            Command::Load(path, start, end) => {
+                match load_blob(&path, start, end, cpu) {
+                    Ok(length) => println!("Loaded {} bytes to 0x{:04X}-0x{:04X}", length, start, start as usize + length - 1),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            Command::Disassemble(None, _) => {self.print_window(pc, cpu);}
+            Command::Disassemble(Some(Location::Memory(addr)), count) => {
+                let instructions = disassembler::disassemble(addr, count, |addr| cpu.bus.peek_byte(addr));
+                self.print_instructions(&instructions, pc);
+            }
+            Command::Disassemble(Some(Location::Bank(bank, addr)), count) => {
+                let rom = cpu.bus.cartridge().data();
+                let banks = (rom.len() + ROM_BANK_SIZE - 1) / ROM_BANK_SIZE;
+                let base = bank as usize * ROM_BANK_SIZE;
+                // Bank 0 is always at 0x0000-0x3FFF and the others are switched into 0x4000-0x7FFF
+                let region_start = if bank == 0 {0x0000} else {ROM_BANK_SIZE as u16};
+                if bank as usize >= banks {
+                    println!("Error: the ROM only has {} banks", banks);
+                } else if addr < region_start || addr >= region_start + ROM_BANK_SIZE as u16 {
+                    println!("Error: bank {} is mapped at 0x{:04X}-0x{:04X}", bank, region_start, region_start + ROM_BANK_SIZE as u16 - 1);
+                } else {
+                    let read = |addr: u16| {
+                        let offset = addr.wrapping_sub(region_start) as usize;
+                        if offset < ROM_BANK_SIZE {rom.get(base + offset).cloned().unwrap_or(0xFF)} else {0xFF}
+                    };
+                    for instruction in disassembler::disassemble(addr, count, read) {
+                        if let Some(name) = self.symbols.label_in_bank(bank, instruction.addr) {
+                            println!("{}:", name);
+                        }
+                        let label = |addr| self.symbols.label_in_bank(bank, addr).map(String::from);
+                        println!("{:02X}:{}", bank, instruction.format_with(label));
+                    }
+                }
+            }
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for &(id, ref breakpoint) in self.breakpoints.list() {
+                    println!("{:4}  {:<8}  hits: {:<6}  {}",
+                            id,
+                            if breakpoint.enabled {"enabled"} else {"disabled"},
+                            breakpoint.hits,
+                            breakpoint.format_with(|addr| self.symbols.describe(addr)));
+                }
+            }
+            Command::Enable(id) => {self.set_breakpoint_enabled(id, true);}
+            Command::Disable(id) => {self.set_breakpoint_enabled(id, false);}
+            Command::Delete(id) => {
+                match self.breakpoints.remove(id) {
+                    Some(breakpoint) => println!("Deleted breakpoint {}: {}", id, breakpoint.format_with(|addr| self.symbols.describe(addr))),
+                    None => println!("Error: there is no breakpoint {}", id)
+                }
+            }
+            Command::Watch(watchpoint) => {
+                let id = cpu.bus.watchpoints.add(watchpoint);
+                println!("Watchpoint {}: {}", id, watchpoint);
+            }
+            Command::Watches => {
+                if cpu.bus.watchpoints.is_empty() {
+                    println!("No watchpoints");
+                }
+                for &(id, ref watchpoint) in cpu.bus.watchpoints.list() {
+                    println!("{:4}  {}", id, watchpoint);
+                }
+            }
+            Command::Unwatch(id) => {
+                match cpu.bus.watchpoints.remove(id) {
+                    Some(watchpoint) => println!("Deleted watchpoint {}: {}", id, watchpoint),
+                    None => println!("Error: there is no watchpoint {}", id)
+                }
+            }
+            Command::Backtrace => {self.print_backtrace(pc, cpu);}
+            Command::StackCheck(enabled) => {
+                self.stack_check = enabled;
+                println!("Stack imbalances {}", if enabled {"stop the program"} else {"are ignored"});
+            }
+            Command::History => {
+                for (index, entry) in self.history.iter().enumerate() {
+                    println!("{:4}  {}", index, entry);
+                }
+            }
+            Command::Help(name) => {print_help(name);}
+        }
+        false
+    }
+
+    fn set_breakpoint_enabled(&mut self, id: usize, enabled: bool) {
+        let symbols = &self.symbols;
+        match self.breakpoints.get_mut(id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                println!("{} breakpoint {}: {}",
+                        if enabled {"Enabled"} else {"Disabled"},
+                        id,
+                        breakpoint.format_with(|addr| symbols.describe(addr)));
+            }
+            None => println!("Error: there is no breakpoint {}", id)
+        }
+    }
+
+    // Label at exactly this address, for operands and disassembly
+    fn label(&self, addr: u16) -> Option<String> {
+        self.symbols.label(addr).map(String::from)
+    }
+
+    // "0x0153 <Main+3>", or just the address without a label before it
+    fn addr_name(&self, addr: u16) -> String {
+        format!("0x{:04X}{}", addr, self.label_suffix(addr))
+    }
+
+    fn label_suffix(&self, addr: u16) -> String {
+        match self.symbols.describe(addr) {
+            Some(name) => format!(" <{}>", name),
+            None => String::new()
+        }
+    }
+
+    fn print_window(&self, pc: u16, cpu: &CPU) {
+        let window = disassembler::window(pc, WINDOW_BEFORE, WINDOW_AFTER, |addr| cpu.bus.peek_byte(addr));
+        self.print_instructions(&window, pc);
+    }
+
+    // Instructions that start at a label get a line with its name first
+    fn print_instructions(&self, instructions: &[disassembler::Instruction], pc: u16) {
+        for instruction in instructions {
+            if let Some(name) = self.symbols.label(instruction.addr) {
+                println!("{}:", name);
+            }
+            println!("{}{}",
+                    if instruction.addr == pc {"=> "} else {"   "},
+                    instruction.format_with(|addr| self.label(addr)));
+        }
+    }
+
+    fn print_backtrace(&self, pc: u16, cpu: &CPU) {
+        println!("#0  {}", self.addr_name(pc));
+        for (level, frame) in cpu.call_stack.frames().iter().rev().enumerate() {
+            println!("#{:<2} {}  {}, SP 0x{:04X}", level + 1, self.addr_name(frame.return_addr), frame, frame.sp);
+        }
+        if let Some(imbalance) = cpu.call_stack.last_imbalance() {
+            println!("Last stack imbalance at {}", imbalance);
+        }
+    }
+
+    // Peeks so that looking at memory doesn't trigger read watchpoints
+    fn print_addr(&self, addr: u16, cpu: &CPU) {
+        let val = cpu.bus.peek_byte(addr);
+        println!("Addr {:04X} contains {:02X}", addr, val);
+    }
+
+    fn print_range(&self, start: u16, end: u16, cpu: &CPU) {
+        for line in dump_lines(start, end, cpu) {
+            println!("{}", line);
+        }
+    }
+}
+
+// 16 bytes per line, each line starting with its address
+fn dump_lines(start: u16, end: u16, cpu: &CPU) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line_start = start as u32;
+    while line_start <= end as u32 {
+        let line_end = (line_start + 15).min(end as u32);
+        let values: Vec<String> = (line_start..line_end + 1)
+            .map(|addr| format!("{:02X}", cpu.bus.peek_byte(addr as u16)))
+            .collect();
+        lines.push(format!("{:04X}: {}", line_start, values.join(" ")));
+        line_start += 16;
+    }
+    lines
+}
+
+// Returns how many bytes were written
+fn load_blob(path: &str, start: u16, end: Option<u16>, cpu: &mut CPU) -> Result<usize, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("can't read {}: {}", path, e))?;
+    if data.is_empty() {
+        return Err(format!("{} is empty", path));
+    }
+    let end = end.unwrap_or(0xFFFF) as usize;
+    if start as usize + data.len() - 1 > end {
+        return Err(format!("{} has {} bytes, only {} fit from 0x{:04X} to 0x{:04X}",
+                path, data.len(), end - start as usize + 1, start, end));
+    }
+    for (offset, byte) in data.iter().enumerate() {
+        cpu.bus.poke_byte(start + offset as u16, *byte);
+    }
+    Ok(data.len())
+}
+
+fn read_command() -> Option<String> {
+    print!("Debug Command: ");
+    io::stdout().flush().ok();
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line)
+    }
+}
+
+fn print_help(name: Option<String>) {
+    match name {
+        Some(name) => match command::find_help(&name) {
+            Some(help) => {
+                println!("{}\n    {}", help.usage, help.description);
+                if help.names.len() > 1 {
+                    println!("    Also: {}", help.names[1..].join(", "));
+                }
+            }
+            None => println!("Error: unknown command '{}'", name)
+        },
+        None => {
+            println!("Commands:");
+            for help in command::COMMANDS {
+                println!("    {:<24}{}", help.usage, help.description);
+            }
+            println!("Numbers are decimal, or hex with a 0x or $ prefix. Register names can be used as values.");
+            println!("Labels from a .sym or .map file next to the ROM can be used as addresses, bank:addr picks a ROM bank.");
+            println!("Conditions use registers, flags (zf, nf, hf, cf), hits, [addr] for the byte at an address,");
+            println!("arithmetic, comparisons, && and ||.");
+            println!("An empty line repeats the last command.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use super::DebuggerState;
+    use super::WINDOW_AFTER;
+    use super::WINDOW_BEFORE;
+    use super::dump_lines;
+    use super::load_blob;
+    use super::command::Command;
+    use hardware::call_stack::CallFrame;
+    use hardware::call_stack::CallKind;
+    use hardware::cpu::CPU;
+    use hardware::disassembler;
+    use hardware::debugger::watchpoints::WatchKind;
+    use hardware::debugger::watchpoints::Watchpoint;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use test_rom;
+
+    fn call(cpu: &mut CPU, sp: u16) {
+        cpu.call_stack.push(CallFrame {kind: CallKind::Call, from: 0x0150, target: 0x0200, return_addr: 0x0153, sp: sp});
+    }
+
+    #[test]
+    fn history_expansion() {
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.expand_history(""), Ok(None));
+        debugger.history.push(String::from("step 3"));
+        debugger.history.push(String::from("print hl"));
+        assert_eq!(debugger.expand_history("  "), Ok(Some(String::from("print hl"))));
+        assert_eq!(debugger.expand_history("!0"), Ok(Some(String::from("step 3"))));
+        assert_eq!(debugger.expand_history(" regs "), Ok(Some(String::from("regs"))));
+        assert_eq!(debugger.expand_history("!2"), Err(String::from("there is no command 2 in the history")));
+        assert_eq!(debugger.expand_history("!x"), Err(String::from("'!x' is not a history entry, use !<n>")));
+    }
+
+    #[test]
+    fn counted_steps_stop_after_the_count() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        let mut debugger = Debugger::new();
+        assert!(debugger.execute(Command::Step(1), 0x0150, cpu));
+        assert!(debugger.state == DebuggerState::STEP);
+        assert!(debugger.state_stops(0x0150, cpu));
+
+        assert!(debugger.execute(Command::Step(3), 0x0150, cpu));
+        assert!(!debugger.state_stops(0x0150, cpu));
+        assert!(!debugger.state_stops(0x0151, cpu));
+        assert!(debugger.state_stops(0x0152, cpu));
+    }
+
+    #[test]
+    fn step_over_and_out_wait_for_the_call_depth() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        let mut debugger = Debugger::new();
+        call(cpu, 0xFFFC);
+
+        // A call made from here has to return before step over stops
+        assert!(debugger.execute(Command::StepOver, 0x0150, cpu));
+        call(cpu, 0xFFFA);
+        assert!(!debugger.state_stops(0x0200, cpu));
+        cpu.call_stack.pop(0x0210, 0xFFFA, 0x0153);
+        assert!(debugger.state_stops(0x0153, cpu));
+
+        assert!(debugger.execute(Command::StepOut, 0x0150, cpu));
+        assert!(!debugger.state_stops(0x0151, cpu));
+        cpu.call_stack.pop(0x0152, 0xFFFC, 0x0153);
+        assert!(debugger.state_stops(0x0153, cpu));
+
+        // There is nothing to step out of at the top level
+        debugger.state = DebuggerState::RUN;
+        assert!(!debugger.execute(Command::StepOut, 0x0153, cpu));
+        assert!(debugger.state == DebuggerState::RUN);
+    }
+
+    #[test]
+    fn memory_edits_and_jumps() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        let mut debugger = Debugger::new();
+        assert!(!debugger.execute(Command::Write(0xC000, vec![1, 2, 3]), 0x0150, cpu));
+        assert!(!debugger.execute(Command::Fill(0xC002, 0xC004, 0xAA), 0x0150, cpu));
+        let memory: Vec<u8> = (0xC000..0xC006).map(|addr| cpu.bus.peek_byte(addr)).collect();
+        assert_eq!(memory, vec![1, 2, 0xAA, 0xAA, 0xAA, 0]);
+
+        // Writes to the cartridge patch the ROM instead of reaching the MBC
+        assert!(!debugger.execute(Command::Write(0x0150, vec![0x18, 0xFE]), 0x0150, cpu));
+        assert_eq!(cpu.bus.peek_byte(0x0151), 0xFE);
+
+        assert!(!debugger.execute(Command::Jump(0x0200), 0x0150, cpu));
+        assert_eq!(cpu.pc.r(), 0x0200);
+    }
+
+    #[test]
+    fn blobs_load_when_they_fit() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        let path = env::temp_dir().join(format!("rustikgb-blob-{}.bin", process::id()));
+        let name = path.to_str().unwrap().to_string();
+        fs::write(&path, &[0x11, 0x22, 0x33]).unwrap();
+        assert_eq!(load_blob(&name, 0xC100, Some(0xC102), cpu), Ok(3));
+        assert_eq!(cpu.bus.peek_byte(0xC102), 0x33);
+        assert_eq!(load_blob(&name, 0xC100, Some(0xC101), cpu),
+                   Err(format!("{} has 3 bytes, only 2 fit from 0xC100 to 0xC101", name)));
+        assert_eq!(load_blob(&name, 0xFFFE, None, cpu),
+                   Err(format!("{} has 3 bytes, only 2 fit from 0xFFFE to 0xFFFF", name)));
+
+        fs::write(&path, &[]).unwrap();
+        assert_eq!(load_blob(&name, 0xC100, None, cpu), Err(format!("{} is empty", name)));
+        fs::remove_file(&path).unwrap();
+        assert!(load_blob(&name, 0xC100, None, cpu).unwrap_err().starts_with(&format!("can't read {}: ", name)));
+    }
+
+    #[test]
+    fn dumps_cover_the_io_page_without_watching() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        cpu.bus.watchpoints.add(Watchpoint {kind: WatchKind::Read, start: 0xFF00, end: 0xFFFF, value: None});
+        cpu.bus.write_byte(0xFF46, 0xC1);
+        cpu.bus.poke_byte(0xC00F, 0x5A);
+        let lines = dump_lines(0xFF00, 0xFF7F, cpu);
+        assert_eq!(lines.len(), 8);
+        assert!(lines[4].starts_with("FF40: "));
+        assert_eq!(lines[4].split(' ').nth(7), Some("C1"));
+        assert_eq!(dump_lines(0xE000, 0xE010, cpu), vec![
+            String::from("E000: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 5A"),
+            String::from("E010: 00"),
+        ]);
+        assert_eq!(cpu.bus.watchpoints.take_hit(), None);
+    }
+
+    #[test]
+    fn disassembly_spans_echo_ram_and_io() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        // JP 0x0150 across the end of the internal RAM
+        cpu.bus.poke_byte(0xDFFF, 0xC3);
+        cpu.bus.poke_byte(0xC000, 0x50);
+        cpu.bus.poke_byte(0xC001, 0x01);
+        let read = |addr| cpu.bus.peek_byte(addr);
+        let instructions = disassembler::disassemble(0xDFF0, 32, &read);
+        assert_eq!(instructions[15].mnemonic(), "JP 0x0150");
+        assert_eq!(instructions[16].addr, 0xE002);
+
+        for &pc in &[0xE000, 0xFDFF, 0xFF46, 0xFFFF] {
+            let window = disassembler::window(pc, WINDOW_BEFORE, WINDOW_AFTER, &read);
+            assert!(window.iter().any(|instruction| instruction.addr == pc), "0x{:04X}", pc);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// Labels from RGBDS symbol files:
+//  ; comment
+//  00:0150 Main
+//  01:4000 Graphics.loop
+// or from the symbol lines of RGBDS map files:
+//  ROMX bank #1:
+//      SECTION: $4000-$40ff ($0100 bytes) ["Graphics"]
+//               $4000 = Graphics
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Without an MBC the cartridge always has bank 1 at 0x4000-0x7FFF
+pub const MAPPED_ROM_BANK : u16 = 1;
+
+// Start of each memory region, labels are only offset within their own region
+const REGION_STARTS : [u16; 10] = [0x0000, 0x4000, 0x8000, 0xA000, 0xC000, 0xD000, 0xE000, 0xFE00, 0xFF00, 0xFF80];
+
+pub struct SymbolTable {
+    by_name: HashMap<String, (u16, u16)>,
+    // Bank and name of the labels at each address
+    by_addr: BTreeMap<u16, Vec<(u16, String)>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            by_name: HashMap::new(),
+            by_addr: BTreeMap::new(),
+        }
+    }
+
+    /// Loads a map file when the extension is "map" and a symbol file otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let symbols = if path.extension().map_or(false, |ext| ext == "map") {
+            SymbolTable::parse_map(&text)
+        } else {
+            SymbolTable::parse_sym(&text)
+        };
+        symbols.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse_sym(text: &str) -> Result<Self, String> {
+        let mut symbols = SymbolTable::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let location = words.next().unwrap();
+            let (bank, addr) = match location.find(':') {
+                Some(colon) => (parse_hex(&location[..colon]), parse_hex(&location[colon + 1..])),
+                None => (Some(0), parse_hex(location))
+            };
+            match (bank, addr, words.next()) {
+                (Some(bank), Some(addr), Some(name)) => symbols.add(bank, addr, name),
+                _ => return Err(format!("line {}: expected '<bank>:<address> <label>' but found '{}'", number + 1, line))
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn parse_map(text: &str) -> Result<Self, String> {
+        let mut symbols = SymbolTable::new();
+        let mut bank = 0;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(hash) = line.find("bank #") {
+                bank = parse_decimal(line[hash + 6..].trim_end_matches(':'))
+                    .ok_or_else(|| format!("line {}: bad bank number in '{}'", number + 1, line))?;
+            } else if line.starts_with('$') && line.contains(" = ") {
+                let mut parts = line.splitn(2, " = ");
+                let addr = parse_hex(&parts.next().unwrap()[1..])
+                    .ok_or_else(|| format!("line {}: bad address in '{}'", number + 1, line))?;
+                symbols.add(bank, addr, parts.next().unwrap().trim());
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    fn add(&mut self, bank: u16, addr: u16, name: &str) {
+        self.by_name.insert(String::from(name), (bank, addr));
+        self.by_addr.entry(addr).or_insert_with(Vec::new).push((bank, String::from(name)));
+    }
+
+    /// Bank and address of a label.
+    pub fn resolve(&self, name: &str) -> Option<(u16, u16)> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// The label at exactly this address of the memory map.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.label_in_bank(MAPPED_ROM_BANK, addr)
+    }
+
+    /// The label at this address with `rom_bank` switched into 0x4000-0x7FFF.
+    pub fn label_in_bank(&self, rom_bank: u16, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr)
+            .and_then(|labels| labels.iter().find(|&&(bank, _)| is_mapped(bank, addr, rom_bank)))
+            .map(|&(_, ref name)| &name[..])
+    }
+
+    /// The closest label at or before the address in the same region, like "Main+3".
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        if let Some(name) = self.label(addr) {
+            return Some(String::from(name));
+        }
+        let region_start = *REGION_STARTS.iter().rev().find(|&&start| start <= addr).unwrap();
+        for (&label_addr, labels) in self.by_addr.range(region_start..addr).rev() {
+            if let Some(&(_, ref name)) = labels.iter().find(|&&(bank, _)| is_mapped(bank, label_addr, MAPPED_ROM_BANK)) {
+                return Some(format!("{}+{}", name, addr - label_addr));
+            }
+        }
+        None
+    }
+}
+
+// Whether a label of this bank is the one the CPU sees at the address
+fn is_mapped(bank: u16, addr: u16, rom_bank: u16) -> bool {
+    match addr {
+        0x4000..=0x7FFF => bank == rom_bank,
+        _ => true
+    }
+}
+
+fn parse_hex(word: &str) -> Option<u16> {
+    u16::from_str_radix(word, 16).ok()
+}
+
+fn parse_decimal(word: &str) -> Option<u16> {
+    word.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolTable;
+
+    const SYM : &'static str = "; File generated by rgblink
+00:0150 Main
+00:0160 Main.loop ; the game loop
+01:4000 Graphics
+02:4000 Music
+
+c000 wCounter
+";
+
+    const MAP : &'static str = "ROM0 bank #0:
+    SECTION: $0150-$016f ($0020 bytes) [\"Main\"]
+             $0150 = Main
+             $0160 = Main.loop
+    EMPTY: $3e90 bytes
+ROMX bank #2:
+    SECTION: $4000-$40ff ($0100 bytes) [\"Music\"]
+             $4000 = Music
+";
+
+    #[test]
+    fn sym_lines_have_a_bank_and_address() {
+        let symbols = SymbolTable::parse_sym(SYM).unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.resolve("Main.loop"), Some((0, 0x0160)));
+        assert_eq!(symbols.resolve("Music"), Some((2, 0x4000)));
+        assert_eq!(symbols.resolve("wCounter"), Some((0, 0xC000)));
+        assert_eq!(symbols.resolve("nothing"), None);
+    }
+
+    #[test]
+    fn sym_errors_name_the_line() {
+        let error = SymbolTable::parse_sym("00:0150 Main\n00:zz50 Broken\n").err().unwrap();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        assert!(SymbolTable::parse_sym("00:0150\n").is_err());
+    }
+
+    #[test]
+    fn map_labels_take_the_bank_of_their_header() {
+        let symbols = SymbolTable::parse_map(MAP).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.resolve("Main.loop"), Some((0, 0x0160)));
+        assert_eq!(symbols.resolve("Music"), Some((2, 0x4000)));
+        assert!(SymbolTable::parse_map("ROMX bank #x:\n").is_err());
+    }
+
+    #[test]
+    fn switchable_bank_labels_depend_on_the_bank() {
+        let symbols = SymbolTable::parse_sym(SYM).unwrap();
+        assert_eq!(symbols.label(0x4000), Some("Graphics"));
+        assert_eq!(symbols.label_in_bank(2, 0x4000), Some("Music"));
+        assert_eq!(symbols.label_in_bank(3, 0x4000), None);
+        assert_eq!(symbols.label_in_bank(3, 0x0150), Some("Main"));
+    }
+
+    #[test]
+    fn describe_stays_in_the_region() {
+        let symbols = SymbolTable::parse_sym(SYM).unwrap();
+        assert_eq!(symbols.describe(0x0150), Some(String::from("Main")));
+        assert_eq!(symbols.describe(0x0153), Some(String::from("Main+3")));
+        assert_eq!(symbols.describe(0x3FFF), Some(String::from("Main.loop+16031")));
+        assert_eq!(symbols.describe(0x4010), Some(String::from("Graphics+16")));
+        assert_eq!(symbols.describe(0x0100), None);
+        // WRAM starts a new region, and so does the bank after it
+        assert_eq!(symbols.describe(0xC002), Some(String::from("wCounter+2")));
+        assert_eq!(symbols.describe(0xD000), None);
+        assert_eq!(symbols.describe(0x9800), None);
+    }
+}
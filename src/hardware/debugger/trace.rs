@@ -0,0 +1,141 @@
+// Execution traces in the format of gameboy-doctor, one line per instruction with the
+// state before it runs, so they can be diffed against other emulators:
+//  A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use hardware::cpu::CPU;
+
+#[derive(Clone, Copy)]
+struct TraceLine {
+    regs: [u8; 8],
+    sp: u16,
+    pc: u16,
+    pc_mem: [u8; 4],
+}
+
+impl TraceLine {
+    fn new(cpu: &CPU) -> Self {
+        let regs = &cpu.regs;
+        let pc = cpu.pc.r();
+        TraceLine {
+            regs: [regs.a.r(), regs.f.r(), regs.b.r(), regs.c.r(), regs.d.r(), regs.e.r(), regs.h.r(), regs.l.r()],
+            sp: cpu.sp.r(),
+            pc: pc,
+            pc_mem: [
+                cpu.bus.peek_byte(pc),
+                cpu.bus.peek_byte(pc.wrapping_add(1)),
+                cpu.bus.peek_byte(pc.wrapping_add(2)),
+                cpu.bus.peek_byte(pc.wrapping_add(3)),
+            ],
+        }
+    }
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.regs;
+        let m = &self.pc_mem;
+        write!(fmt, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+               r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7], self.sp, self.pc, m[0], m[1], m[2], m[3])
+    }
+}
+
+enum Output {
+    File(BufWriter<File>),
+    // Only the newest lines are kept, and written to the file when asked to
+    Ring(VecDeque<TraceLine>, usize),
+}
+
+pub struct Trace {
+    path: PathBuf,
+    output: Output,
+}
+
+impl Trace {
+    /// Logs every instruction to `path`.
+    pub fn to_file(path: &Path) -> io::Result<Self> {
+        Ok(Trace {
+            path: path.to_path_buf(),
+            output: Output::File(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    /// Keeps the last `length` instructions, written to `path` by `dump`.
+    pub fn ring(path: &Path, length: usize) -> Self {
+        Trace {
+            path: path.to_path_buf(),
+            output: Output::Ring(VecDeque::with_capacity(length), length),
+        }
+    }
+
+    /// Called before each instruction runs.
+    pub fn record(&mut self, cpu: &CPU) {
+        let line = TraceLine::new(cpu);
+        match self.output {
+            Output::File(ref mut writer) => {
+                // A full disk isn't worth stopping the game for
+                let _ = writeln!(writer, "{}", line);
+            }
+            Output::Ring(ref mut lines, length) => {
+                if lines.len() == length {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        }
+    }
+
+    /// Writes the instructions the ring kept, replacing the previous dump.
+    /// Does nothing when every instruction already goes to the file.
+    pub fn dump(&self) -> io::Result<()> {
+        if let Output::Ring(ref lines, _) = self.output {
+            let mut writer = BufWriter::new(File::create(&self.path)?);
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
+            writer.flush()?;
+            println!("Wrote the last {} instructions to {}", lines.len(), self.path.display());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Trace {
+    // The instructions that led to a panic are the ones worth looking at
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = self.dump() {
+                eprintln!("Error: can't write the trace to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceLine;
+    use test_rom;
+
+    #[test]
+    fn pc_memory_reads_past_the_internal_ram() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let cpu = gameboy.cpu_mut();
+        cpu.pc.w(0xDFFE);
+        for &(addr, byte) in &[(0xDFFE, 0x01), (0xDFFF, 0x02), (0xC000, 0x03), (0xC001, 0x04)] {
+            cpu.bus.poke_byte(addr, byte);
+        }
+        assert!(TraceLine::new(cpu).to_string().ends_with(" PC:DFFE PCMEM:01,02,03,04"));
+
+        cpu.pc.w(0xFF45);
+        assert!(TraceLine::new(cpu).to_string().contains(" PC:FF45 PCMEM:"));
+    }
+}
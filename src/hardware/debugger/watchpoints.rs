@@ -0,0 +1,239 @@
+use std::cell::Cell;
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Reads and writes
+    Access,
+    // Writes that change the stored value
+    Change,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    pub end: u16,
+    // Only accesses of this value trigger the watchpoint
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    fn covers(&self, addr: u16) -> bool {
+        addr >= self.start && addr <= self.end
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read     => "read",
+            WatchKind::Write    => "write",
+            WatchKind::Access   => "access",
+            WatchKind::Change   => "change",
+        };
+        write!(fmt, "{} 0x{:04X}", kind, self.start)?;
+        if self.end != self.start {
+            write!(fmt, "-0x{:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(fmt, " == 0x{:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub write: bool,
+    pub addr: u16,
+    pub value: u8,
+    // Value before a write, for change watchpoints
+    pub old_value: Option<u8>,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.write {
+            write!(fmt, "write of 0x{:02X} to 0x{:04X}", self.value, self.addr)?;
+        } else {
+            write!(fmt, "read of 0x{:02X} from 0x{:04X}", self.value, self.addr)?;
+        }
+        if let Some(old_value) = self.old_value {
+            write!(fmt, " (was 0x{:02X})", old_value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Memory watchpoints, checked by the BUS on every access, so DMA
+/// triggers them as well as the CPU. Only the first hit is kept until
+/// the debugger takes it.
+pub struct Watchpoints {
+    // Ids stay the same when other watchpoints are removed
+    list: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+    // Reads only borrow the BUS, hence the Cell
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints {
+            list: Vec::new(),
+            next_id: 0,
+            hit: Cell::new(None),
+        }
+    }
+
+    /// Returns the id of the new watchpoint.
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push((id, watchpoint));
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        let index = self.list.iter().position(|entry| entry.0 == id)?;
+        Some(self.list.remove(index).1)
+    }
+
+    pub fn list(&self) -> &[(usize, Watchpoint)] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Whether a write to the address needs the old value to be checked.
+    pub fn watches_changes(&self, addr: u16) -> bool {
+        self.list.iter().any(|&(_, ref watch)| watch.kind == WatchKind::Change && watch.covers(addr))
+    }
+
+    pub fn on_read(&self, addr: u16, value: u8) {
+        self.check(false, addr, value, None);
+    }
+
+    pub fn on_write(&self, addr: u16, value: u8, old_value: Option<u8>) {
+        self.check(true, addr, value, old_value);
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    pub fn clear_hit(&self) {
+        self.hit.set(None);
+    }
+
+    fn check(&self, write: bool, addr: u16, value: u8, old_value: Option<u8>) {
+        if self.hit.get().is_some() {
+            return;
+        }
+        for &(id, ref watch) in self.list.iter() {
+            let kind_matches = match watch.kind {
+                WatchKind::Read     => !write,
+                WatchKind::Write    => write,
+                WatchKind::Access   => true,
+                WatchKind::Change   => write && old_value.map_or(false, |old| old != value),
+            };
+            if kind_matches && watch.covers(addr) && watch.value.map_or(true, |expected| expected == value) {
+                self.hit.set(Some(WatchHit {
+                    id: id,
+                    write: write,
+                    addr: addr,
+                    value: value,
+                    old_value: if watch.kind == WatchKind::Change {old_value} else {None},
+                }));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WatchHit;
+    use super::WatchKind;
+    use super::Watchpoint;
+    use super::Watchpoints;
+    use test_rom;
+
+    fn watch(kind: WatchKind, start: u16, end: u16, value: Option<u8>) -> Watchpoints {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.add(Watchpoint { kind: kind, start: start, end: end, value: value });
+        watchpoints
+    }
+
+    #[test]
+    fn kinds_pick_reads_writes_or_changes() {
+        // (kind, read, write of the same value, write of a new value)
+        let cases = [
+            (WatchKind::Read,   true,  false, false),
+            (WatchKind::Write,  false, true,  true),
+            (WatchKind::Access, true,  true,  true),
+            (WatchKind::Change, false, false, true),
+        ];
+        for &(kind, read, same, changed) in cases.iter() {
+            let watchpoints = watch(kind, 0xC000, 0xC000, None);
+            watchpoints.on_read(0xC000, 5);
+            assert_eq!(watchpoints.take_hit().is_some(), read, "{:?} read", kind);
+            watchpoints.on_write(0xC000, 5, Some(5));
+            assert_eq!(watchpoints.take_hit().is_some(), same, "{:?} same write", kind);
+            watchpoints.on_write(0xC000, 6, Some(5));
+            assert_eq!(watchpoints.take_hit().is_some(), changed, "{:?} changing write", kind);
+        }
+    }
+
+    #[test]
+    fn ranges_and_values_narrow_the_hits() {
+        let watchpoints = watch(WatchKind::Write, 0xC000, 0xC00F, Some(0x42));
+        watchpoints.on_write(0xC010, 0x42, None);
+        watchpoints.on_write(0xBFFF, 0x42, None);
+        watchpoints.on_write(0xC008, 0x41, None);
+        assert_eq!(watchpoints.take_hit(), None);
+        watchpoints.on_write(0xC00F, 0x42, None);
+        assert_eq!(watchpoints.take_hit(), Some(WatchHit { id: 0, write: true, addr: 0xC00F, value: 0x42, old_value: None }));
+        assert!(!watchpoints.watches_changes(0xC000));
+    }
+
+    #[test]
+    fn the_first_hit_is_kept_until_taken() {
+        let mut watchpoints = watch(WatchKind::Access, 0xFF40, 0xFF40, None);
+        let second = watchpoints.add(Watchpoint { kind: WatchKind::Read, start: 0xFF44, end: 0xFF44, value: None });
+        watchpoints.on_write(0xFF40, 0x91, None);
+        watchpoints.on_read(0xFF44, 0x90);
+        assert_eq!(watchpoints.take_hit().map(|hit| hit.id), Some(0));
+        assert_eq!(watchpoints.take_hit(), None);
+        watchpoints.on_read(0xFF44, 0x90);
+        watchpoints.clear_hit();
+        assert_eq!(watchpoints.take_hit(), None);
+
+        assert!(watchpoints.remove(0).is_some());
+        assert!(watchpoints.remove(0).is_none());
+        assert_eq!(watchpoints.list()[0].0, second);
+        assert_eq!(watchpoints.list()[0].1.to_string(), "read 0xFF44");
+    }
+
+    #[test]
+    fn the_bus_reports_old_values_but_not_peeks() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.watchpoints.add(Watchpoint { kind: WatchKind::Change, start: 0xC000, end: 0xC0FF, value: None });
+        bus.poke_byte(0xC010, 7);
+        bus.peek_byte(0xC010);
+        assert_eq!(bus.watchpoints.take_hit(), None);
+        bus.write_byte(0xC010, 7);
+        assert_eq!(bus.watchpoints.take_hit(), None);
+        bus.write_byte(0xC010, 8);
+        let hit = bus.watchpoints.take_hit().unwrap();
+        assert_eq!(hit.old_value, Some(7));
+        assert_eq!(hit.to_string(), "write of 0x08 to 0xC010 (was 0x07)");
+        assert_eq!(bus.watchpoints.list()[0].1.to_string(), "change 0xC000-0xC0FF");
+    }
+}
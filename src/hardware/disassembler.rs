@@ -0,0 +1,300 @@
+// Decodes instructions from any source of bytes: the memory map, a ROM bank or a file.
+// Operands show up in the mnemonic, with relative jumps already turned into addresses:
+//  0x0155  18 FC     JR 0x0153
+
+use std::fmt;
+
+const REGISTERS     : [&'static str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const ALU_OPS       : [&'static str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROTATE_OPS    : [&'static str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operand {
+    None,
+    // d8
+    Byte(u8),
+    // d16
+    Word(u16),
+    // a16, and a8 already added to 0xFF00
+    Address(u16),
+    // r8, resolved to the address it jumps to
+    Relative(u16),
+    // s8, added to SP
+    Offset(i8),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // Mnemonic with the placeholder of the operand still in it
+    template: String,
+    pub operand: Operand,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// Address of the instruction that comes after this one in memory.
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+
+    /// Where a jump, call or RST goes, when it is known before running it.
+    pub fn target(&self) -> Option<u16> {
+        let opcode = self.bytes[0];
+        match self.operand {
+            Operand::Relative(target) => Some(target),
+            Operand::Address(addr) if is_jump_or_call(opcode) => Some(addr),
+            _ if opcode & 0xC7 == 0xC7 => Some((opcode & 0x38) as u16),
+            _ => None
+        }
+    }
+
+    pub fn mnemonic(&self) -> String {
+        self.mnemonic_with(|_| None)
+    }
+
+    /// The mnemonic with the addresses `label` knows replaced by their names.
+    pub fn mnemonic_with<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let addr_name = |addr: u16| label(addr).unwrap_or_else(|| format!("0x{:04X}", addr));
+        match self.operand {
+            Operand::None           => self.template.clone(),
+            Operand::Byte(value)    => self.template.replace("d8", &format!("0x{:02X}", value)),
+            Operand::Word(value)    => self.template.replace("d16", &format!("0x{:04X}", value)),
+            Operand::Address(addr)  => self.template.replace("a16", &addr_name(addr))
+                                                     .replace("a8", &addr_name(addr)),
+            Operand::Relative(addr) => self.template.replace("r8", &addr_name(addr)),
+            Operand::Offset(offset) => self.template.replace("s8", &format!("{:+}", offset)),
+        }
+    }
+
+    /// Address, bytes and mnemonic, with labels from `label`.
+    pub fn format_with<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("0x{:04X}  {:<8}  {}", self.addr, bytes.join(" "), self.mnemonic_with(label))
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.format_with(|_| None))
+    }
+}
+
+fn is_jump_or_call(opcode: u8) -> bool {
+    match opcode {
+        0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA |          // JP
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => true,   // CALL
+        _ => false
+    }
+}
+
+/// Decodes the instruction at `addr`, reading its bytes with `read`.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Instruction {
+    let opcode = read(addr);
+    let byte_at = |offset: u16| read(addr.wrapping_add(offset));
+    if opcode == 0xCB {
+        let cb_opcode = byte_at(1);
+        return Instruction {
+            addr: addr,
+            bytes: vec![opcode, cb_opcode],
+            template: bitwise_template(cb_opcode),
+            operand: Operand::None,
+        };
+    }
+
+    let template = template(opcode);
+    let (operand, length) = if template.contains("d16") {
+        (Operand::Word(byte_at(1) as u16 | (byte_at(2) as u16) << 8), 3)
+    } else if template.contains("a16") {
+        (Operand::Address(byte_at(1) as u16 | (byte_at(2) as u16) << 8), 3)
+    } else if template.contains("a8") {
+        (Operand::Address(0xFF00 | byte_at(1) as u16), 2)
+    } else if template.contains("d8") {
+        (Operand::Byte(byte_at(1)), 2)
+    } else if template.contains("r8") {
+        let target = addr.wrapping_add(2).wrapping_add(byte_at(1) as i8 as u16);
+        (Operand::Relative(target), 2)
+    } else if template.contains("s8") {
+        (Operand::Offset(byte_at(1) as i8), 2)
+    } else if opcode == 0x10 {
+        // STOP is followed by a byte the CPU skips
+        (Operand::None, 2)
+    } else {
+        (Operand::None, 1)
+    };
+    Instruction {
+        addr: addr,
+        bytes: (0..length).map(|offset| byte_at(offset)).collect(),
+        template: template,
+        operand: operand,
+    }
+}
+
+/// Decodes `count` instructions in a row from `start`.
+pub fn disassemble<F: Fn(u16) -> u8>(start: u16, count: usize, read: F) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut addr = start;
+    for _ in 0..count {
+        let instruction = decode(addr, &read);
+        addr = instruction.next_addr();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Up to `before` instructions leading to `addr`, `addr` itself and `after` more.
+/// Instructions have different lengths, so the ones before are guessed: we take the
+/// furthest start that decodes straight into `addr`.
+pub fn window<F: Fn(u16) -> u8>(addr: u16, before: usize, after: usize, read: F) -> Vec<Instruction> {
+    let mut leading = Vec::new();
+    for distance in (1..before as u16 * 3 + 1).rev() {
+        if distance > addr {
+            continue;
+        }
+        let mut candidate = Vec::new();
+        let mut next = addr - distance;
+        while next < addr {
+            let instruction = decode(next, &read);
+            next = instruction.next_addr();
+            candidate.push(instruction);
+        }
+        if next == addr {
+            let skip = candidate.len().saturating_sub(before);
+            leading = candidate.split_off(skip);
+            break;
+        }
+    }
+    leading.extend(disassemble(addr, after + 1, read));
+    leading
+}
+
+fn template(opcode: u8) -> String {
+    let template = match opcode {
+        0x00 => "NOP",          0x01 => "LD BC,d16",    0x02 => "LD (BC),A",    0x03 => "INC BC",
+        0x04 => "INC B",        0x05 => "DEC B",        0x06 => "LD B,d8",      0x07 => "RLCA",
+        0x08 => "LD (a16),SP",  0x09 => "ADD HL,BC",    0x0A => "LD A,(BC)",    0x0B => "DEC BC",
+        0x0C => "INC C",        0x0D => "DEC C",        0x0E => "LD C,d8",      0x0F => "RRCA",
+        0x10 => "STOP",         0x11 => "LD DE,d16",    0x12 => "LD (DE),A",    0x13 => "INC DE",
+        0x14 => "INC D",        0x15 => "DEC D",        0x16 => "LD D,d8",      0x17 => "RLA",
+        0x18 => "JR r8",        0x19 => "ADD HL,DE",    0x1A => "LD A,(DE)",    0x1B => "DEC DE",
+        0x1C => "INC E",        0x1D => "DEC E",        0x1E => "LD E,d8",      0x1F => "RRA",
+        0x20 => "JR NZ,r8",     0x21 => "LD HL,d16",    0x22 => "LD (HL+),A",   0x23 => "INC HL",
+        0x24 => "INC H",        0x25 => "DEC H",        0x26 => "LD H,d8",      0x27 => "DAA",
+        0x28 => "JR Z,r8",      0x29 => "ADD HL,HL",    0x2A => "LD A,(HL+)",   0x2B => "DEC HL",
+        0x2C => "INC L",        0x2D => "DEC L",        0x2E => "LD L,d8",      0x2F => "CPL",
+        0x30 => "JR NC,r8",     0x31 => "LD SP,d16",    0x32 => "LD (HL-),A",   0x33 => "INC SP",
+        0x34 => "INC (HL)",     0x35 => "DEC (HL)",     0x36 => "LD (HL),d8",   0x37 => "SCF",
+        0x38 => "JR C,r8",      0x39 => "ADD HL,SP",    0x3A => "LD A,(HL-)",   0x3B => "DEC SP",
+        0x3C => "INC A",        0x3D => "DEC A",        0x3E => "LD A,d8",      0x3F => "CCF",
+        0x76 => "HALT",
+        0x40..=0x7F => return format!("LD {},{}", REGISTERS[(opcode >> 3 & 7) as usize], REGISTERS[(opcode & 7) as usize]),
+        0x80..=0xBF => return format!("{}{}", ALU_OPS[(opcode >> 3 & 7) as usize], REGISTERS[(opcode & 7) as usize]),
+        0xC0 => "RET NZ",       0xC1 => "POP BC",       0xC2 => "JP NZ,a16",    0xC3 => "JP a16",
+        0xC4 => "CALL NZ,a16",  0xC5 => "PUSH BC",      0xC6 => "ADD A,d8",     0xC7 => "RST 0x00",
+        0xC8 => "RET Z",        0xC9 => "RET",          0xCA => "JP Z,a16",
+        0xCC => "CALL Z,a16",   0xCD => "CALL a16",     0xCE => "ADC A,d8",     0xCF => "RST 0x08",
+        0xD0 => "RET NC",       0xD1 => "POP DE",       0xD2 => "JP NC,a16",
+        0xD4 => "CALL NC,a16",  0xD5 => "PUSH DE",      0xD6 => "SUB d8",       0xD7 => "RST 0x10",
+        0xD8 => "RET C",        0xD9 => "RETI",         0xDA => "JP C,a16",
+        0xDC => "CALL C,a16",                           0xDE => "SBC A,d8",     0xDF => "RST 0x18",
+        0xE0 => "LDH (a8),A",   0xE1 => "POP HL",       0xE2 => "LD (0xFF00+C),A",
+                                0xE5 => "PUSH HL",      0xE6 => "AND d8",       0xE7 => "RST 0x20",
+        0xE8 => "ADD SP,s8",    0xE9 => "JP (HL)",      0xEA => "LD (a16),A",
+                                                        0xEE => "XOR d8",       0xEF => "RST 0x28",
+        0xF0 => "LDH A,(a8)",   0xF1 => "POP AF",       0xF2 => "LD A,(0xFF00+C)", 0xF3 => "DI",
+                                0xF5 => "PUSH AF",      0xF6 => "OR d8",        0xF7 => "RST 0x30",
+        0xF8 => "LD HL,SPs8",   0xF9 => "LD SP,HL",     0xFA => "LD A,(a16)",   0xFB => "EI",
+                                                        0xFE => "CP d8",        0xFF => "RST 0x38",
+        // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD
+        _ => return format!("DB 0x{:02X}", opcode)
+    };
+    String::from(template)
+}
+
+fn bitwise_template(opcode: u8) -> String {
+    let register = REGISTERS[(opcode & 7) as usize];
+    let bit = opcode >> 3 & 7;
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATE_OPS[bit as usize], register),
+        1 => format!("BIT {},{}", bit, register),
+        2 => format!("RES {},{}", bit, register),
+        _ => format!("SET {},{}", bit, register),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::window;
+    use super::Operand;
+
+    const BASE : u16 = 0x0150;
+
+    fn decode_bytes(bytes: &[u8]) -> super::Instruction {
+        decode(BASE, |addr| bytes.get(addr.wrapping_sub(BASE) as usize).cloned().unwrap_or(0))
+    }
+
+    #[test]
+    fn operands_are_decoded() {
+        let cases: &[(&[u8], Operand, &str)] = &[
+            (&[0x00],               Operand::None,              "NOP"),
+            (&[0x06, 0x2A],         Operand::Byte(0x2A),        "LD B,0x2A"),
+            (&[0xFE, 0xFF],         Operand::Byte(0xFF),        "CP 0xFF"),
+            (&[0x21, 0x34, 0x12],   Operand::Word(0x1234),      "LD HL,0x1234"),
+            (&[0xC3, 0x50, 0x01],   Operand::Address(0x0150),   "JP 0x0150"),
+            (&[0x08, 0x00, 0xC0],   Operand::Address(0xC000),   "LD (0xC000),SP"),
+            (&[0xE0, 0x40],         Operand::Address(0xFF40),   "LDH (0xFF40),A"),
+            (&[0x18, 0xFE],         Operand::Relative(0x0150),  "JR 0x0150"),
+            (&[0x20, 0x7F],         Operand::Relative(0x01D1),  "JR NZ,0x01D1"),
+            (&[0x38, 0x80],         Operand::Relative(0x00D2),  "JR C,0x00D2"),
+            (&[0xE8, 0xFC],         Operand::Offset(-4),        "ADD SP,-4"),
+            (&[0xF8, 0x05],         Operand::Offset(5),         "LD HL,SP+5"),
+            (&[0x10, 0x00],         Operand::None,              "STOP"),
+            (&[0x46],               Operand::None,              "LD B,(HL)"),
+            (&[0xAF],               Operand::None,              "XOR A"),
+            (&[0xD3],               Operand::None,              "DB 0xD3"),
+        ];
+        for &(bytes, operand, mnemonic) in cases {
+            let instruction = decode_bytes(bytes);
+            assert_eq!(instruction.operand, operand, "{}", mnemonic);
+            assert_eq!(instruction.mnemonic(), mnemonic);
+            assert_eq!(instruction.bytes, bytes);
+        }
+    }
+
+    #[test]
+    fn cb_prefix_takes_one_byte() {
+        let cases: &[(u8, &str)] = &[
+            (0x00, "RLC B"), (0x37, "SWAP A"), (0x3E, "SRL (HL)"),
+            (0x7C, "BIT 7,H"), (0x87, "RES 0,A"), (0xFE, "SET 7,(HL)"),
+        ];
+        for &(opcode, mnemonic) in cases {
+            let instruction = decode_bytes(&[0xCB, opcode]);
+            assert_eq!(instruction.mnemonic(), mnemonic);
+            assert_eq!(instruction.len(), 2);
+        }
+    }
+
+    #[test]
+    fn targets_and_labels() {
+        let call = decode_bytes(&[0xCD, 0x00, 0x40]);
+        assert_eq!(call.target(), Some(0x4000));
+        assert_eq!(call.mnemonic_with(|addr| if addr == 0x4000 {Some(String::from("Init"))} else {None}), "CALL Init");
+        assert_eq!(decode_bytes(&[0xEF]).target(), Some(0x0028));
+        assert_eq!(decode_bytes(&[0xFA, 0x00, 0x40]).target(), None);
+        assert_eq!(format!("{}", decode_bytes(&[0x18, 0xFC])), "0x0150  18 FC     JR 0x014E");
+    }
+
+    #[test]
+    fn window_lines_up_with_the_address() {
+        // LD HL,0x8000 / INC (HL) / INC L / JR -4
+        let bytes = [0x21, 0x00, 0x80, 0x34, 0x2C, 0x18, 0xFC];
+        let instructions = window(BASE + 4, 2, 1, |addr| bytes.get(addr.wrapping_sub(BASE) as usize).cloned().unwrap_or(0));
+        let addrs: Vec<u16> = instructions.iter().map(|instruction| instruction.addr).collect();
+        assert_eq!(addrs, vec![BASE, BASE + 3, BASE + 4, BASE + 5]);
+    }
+}
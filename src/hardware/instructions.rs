@@ -1,5 +1,6 @@
 use hardware::cpu::CPU;
 use hardware::cpu::CPUFlags;
+use hardware::call_stack::CallKind;
 
 struct Instruction<'i> {
     pub dissassembly : &'static str,
@@ -249,6 +250,7 @@ fn call_cond(cpu: &mut CPU, cond: JumpImmCond) {
     if jump_cond_imm(cpu, cond, JumpImmMode::Immediate) {
         let next_inst = old_pc.wrapping_add(2);
         cpu.push_word(next_inst);
+        cpu.enter_call(CallKind::Call, old_pc.wrapping_sub(1), next_inst);
     }
 }
 
@@ -261,6 +263,8 @@ fn ret_cond(cpu: &mut CPU, cond: JumpImmCond) {
         _ => {true}
     };
     if do_ret {
+        let ret_addr = cpu.pc.r().wrapping_sub(1);
+        cpu.leave_call(ret_addr);
         let target_addr = cpu.pop_word(); 
         jump(target_addr, cpu);
     }
@@ -271,6 +275,7 @@ fn reset(opcode: u8, cpu: &mut CPU) {
     cpu.push_word(cur_addr);
     let addr = (opcode - 0xC7) as u16;
     cpu.pc.w(addr);
+    cpu.enter_call(CallKind::Rst, cur_addr.wrapping_sub(1), cur_addr);
 }
 
 fn ldh(cpu: &mut CPU, offset: u8, store_into_a: bool) {
@@ -1,5 +1,6 @@
 use hardware::memory::memory_region::MemoryRegion;
 use hardware::registers::Register;
+use hardware::state::*;
 
 const INTERRUPT_ENABLE_ADDR     : u16 = 0xFFFF;
 const INTERRUPT_FLAG_ADDR       : u16 = 0xFF0F;
@@ -10,7 +11,7 @@ pub const TIMER_ISR_START       : u16 = 0x0050;
 pub const SERIAL_ISR_START      : u16 = 0x0058;
 pub const JOYPAD_ISR_START      : u16 = 0x0060;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum InterruptType {
     Pad         = 4,
     Serial      = 3,
@@ -120,4 +121,23 @@ impl MemoryRegion for Interrupts {
     fn end(&self) -> u16{
         panic!("Interrupts don't have just one end")
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Interrupts {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.are_enabled);
+        writer.write_u8(self.interrupt_enable.r());
+        writer.write_u8(self.interrupt_flags.r());
+        writer.write_u8(self.steps_before_change as u8);
+        writer.write_bool(self.target_enable_state);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.are_enabled = reader.read_bool()?;
+        self.interrupt_enable.w(reader.read_u8()?);
+        self.interrupt_flags.w(reader.read_u8()?);
+        self.steps_before_change = reader.read_u8()? as i8;
+        self.target_enable_state = reader.read_bool()?;
+        Ok(())
+    }
+}
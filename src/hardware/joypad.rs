@@ -1,4 +1,3 @@
-use piston_window::*;
 use std::fmt;
 use std::fmt::Display;
 
@@ -6,6 +5,7 @@ use hardware::memory::memory_region::MemoryRegion;
 use hardware::registers::Register;
 use hardware::interrupts::Interrupts;
 use hardware::interrupts::InterruptType;
+use hardware::state::*;
 
 pub const JOYPAD_ADDR : u16 = 0xFF00;
 
@@ -16,7 +16,35 @@ const B_UP_OR_SELECT     : u8 = 2;
 const B_LEFT_OR_B        : u8 = 1;
 const B_RIGHT_OR_A       : u8 = 0;
 
-enum Button {A, B, Up, Down, Left, Right, Start, Select}
+const SELECT_MASK        : u8 = 0x30;
+const LINES_MASK         : u8 = 0x0F;
+// Bits 6 and 7 of P1 aren't wired to anything
+const UNUSED_MASK        : u8 = 0xC0;
+
+/// Game Boy buttons, independent of whatever input device drives them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GbButton {A, B, Up, Down, Left, Right, Start, Select}
+
+pub const ALL_BUTTONS: [GbButton; 8] = [
+    GbButton::A, GbButton::B, GbButton::Select, GbButton::Start,
+    GbButton::Right, GbButton::Left, GbButton::Up, GbButton::Down,
+];
+
+impl GbButton {
+    /// Bit of the button in the masks used by `Joypad::buttons`
+    pub fn mask(&self) -> u8 {
+        match *self {
+            GbButton::A       => 1 << 0,
+            GbButton::B       => 1 << 1,
+            GbButton::Select  => 1 << 2,
+            GbButton::Start   => 1 << 3,
+            GbButton::Right   => 1 << 4,
+            GbButton::Left    => 1 << 5,
+            GbButton::Up      => 1 << 6,
+            GbButton::Down    => 1 << 7,
+        }
+    }
+}
 
 pub struct Joypad {
     state: Register<u8>,
@@ -28,6 +56,10 @@ pub struct Joypad {
     pressed_right: bool,
     pressed_select: bool,
     pressed_start: bool,
+    // Input lines as the CPU last saw them, low means pressed
+    lines: u8,
+    // Frontend setting, not part of the machine state
+    block_opposing_directions: bool,
 
     interrupt_generated: bool
 }
@@ -44,72 +76,113 @@ impl Joypad {
             pressed_right:  false,
             pressed_select: false,
             pressed_start:  false,
+            lines:          LINES_MASK,
+            block_opposing_directions: false,
 
             interrupt_generated: false
         }
     }
 
-    pub fn process_press(&mut self, key: Key) {
-        match key {
-            Key::Z      => {self.set_key_press(Button::A, true)}
-            Key::X      => {self.set_key_press(Button::B, true)}
-            Key::Up     => {self.set_key_press(Button::Up, true)}
-            Key::Down   => {self.set_key_press(Button::Down, true)}
-            Key::Left   => {self.set_key_press(Button::Left, true)}
-            Key::Right  => {self.set_key_press(Button::Right, true)}
-            Key::N      => {self.set_key_press(Button::Start, true)}
-            Key::M      => {self.set_key_press(Button::Select, true)}
-            _           => {}
+    pub fn step(&mut self, cycles: u32, interrupt_handler: &mut Interrupts) {
+        if self.interrupt_generated {
+            interrupt_handler.set_interrupt(InterruptType::Pad);
+            self.interrupt_generated = false;
         }
-
-        println!("{}", self);
     }
 
-     pub fn process_release(&mut self, key: Key) {
-        match key {
-            Key::Z      => {self.set_key_press(Button::A, false)}
-            Key::X      => {self.set_key_press(Button::B, false)}
-            Key::Up     => {self.set_key_press(Button::Up, false)}
-            Key::Down   => {self.set_key_press(Button::Down, false)}
-            Key::Left   => {self.set_key_press(Button::Left, false)}
-            Key::Right  => {self.set_key_press(Button::Right, false)}
-            Key::N      => {self.set_key_press(Button::Start, false)}
-            Key::M      => {self.set_key_press(Button::Select, false)}
-            _           => {}
+    pub fn set_button(&mut self, button: GbButton, on: bool) {
+        match button {
+            GbButton::A       => {self.pressed_a = on;} 
+            GbButton::B       => {self.pressed_b = on;} 
+            GbButton::Up      => {self.pressed_up = on;} 
+            GbButton::Down    => {self.pressed_down = on;} 
+            GbButton::Left    => {self.pressed_left = on;} 
+            GbButton::Right   => {self.pressed_right = on;} 
+            GbButton::Start   => {self.pressed_start = on;} 
+            GbButton::Select  => {self.pressed_select = on;}
         }
+        self.update_lines();
     }
 
-    pub fn step(&mut self, cycles: u32, interrupt_handler: &mut Interrupts) {
-        if self.interrupt_generated {
-            interrupt_handler.set_interrupt(InterruptType::Pad);
-            self.interrupt_generated = false;
-        }
+    /// Hides Left+Right and Up+Down pressed together, which a real d-pad can't do
+    /// and some games don't expect.
+    pub fn set_block_opposing_directions(&mut self, block: bool) {
+        self.block_opposing_directions = block;
+        self.update_lines();
+    }
+
+    pub fn blocks_opposing_directions(&self) -> bool {
+        self.block_opposing_directions
     }
 
-    fn set_key_press(&mut self, button: Button, on: bool) {
+    pub fn is_pressed(&self, button: GbButton) -> bool {
         match button {
-            Button::A       => {self.pressed_a = on;} 
-            Button::B       => {self.pressed_b = on;} 
-            Button::Up      => {self.pressed_up = on;} 
-            Button::Down    => {self.pressed_down = on;} 
-            Button::Left    => {self.pressed_left = on;} 
-            Button::Right   => {self.pressed_right = on;} 
-            Button::Start   => {self.pressed_start = on;} 
-            Button::Select  => {self.pressed_select = on;}
+            GbButton::A       => self.pressed_a,
+            GbButton::B       => self.pressed_b,
+            GbButton::Up      => self.pressed_up,
+            GbButton::Down    => self.pressed_down,
+            GbButton::Left    => self.pressed_left,
+            GbButton::Right   => self.pressed_right,
+            GbButton::Start   => self.pressed_start,
+            GbButton::Select  => self.pressed_select,
         }
+    }
 
-        if on {
-            self.interrupt_generated = true;
+    /// Pressed buttons as a mask of `GbButton::mask` bits
+    pub fn buttons(&self) -> u8 {
+        ALL_BUTTONS.iter()
+            .filter(|button| self.is_pressed(**button))
+            .fold(0, |mask, button| mask | button.mask())
+    }
+
+    pub fn set_buttons(&mut self, mask: u8) {
+        for button in ALL_BUTTONS.iter() {
+            let on = mask & button.mask() > 0;
+            if self.is_pressed(*button) != on {
+                self.set_button(*button, on);
+            }
         }
     }
 
     fn is_reading_direction(&self) -> bool {
         self.state.r() & (1 << B_SELECT_DIRECTION) == 0
-    } 
+    }
 
     fn is_reading_buttons(&self) -> bool {
         self.state.r() & (1 << B_SELECT_BUTTON) == 0
     }
+
+    // Low nibble of P1. With both groups selected they are ANDed together
+    fn current_lines(&self) -> u8 {
+        let mut left = self.pressed_left;
+        let mut right = self.pressed_right;
+        let mut up = self.pressed_up;
+        let mut down = self.pressed_down;
+        if self.block_opposing_directions {
+            if left && right {left = false; right = false;}
+            if up && down {up = false; down = false;}
+        }
+
+        let mut pressed = 0;
+        if self.is_reading_direction() {
+            pressed |= line(B_RIGHT_OR_A, right) | line(B_LEFT_OR_B, left)
+                | line(B_UP_OR_SELECT, up) | line(B_DOWN_OR_START, down);
+        }
+        if self.is_reading_buttons() {
+            pressed |= line(B_RIGHT_OR_A, self.pressed_a) | line(B_LEFT_OR_B, self.pressed_b)
+                | line(B_UP_OR_SELECT, self.pressed_select) | line(B_DOWN_OR_START, self.pressed_start);
+        }
+        !pressed & LINES_MASK
+    }
+
+    // The interrupt fires when a line goes from high to low
+    fn update_lines(&mut self) {
+        let lines = self.current_lines();
+        if self.lines & !lines != 0 {
+            self.interrupt_generated = true;
+        }
+        self.lines = lines;
+    }
 }
 
 impl Display for Joypad {
@@ -125,27 +198,13 @@ impl Display for Joypad {
 
 impl MemoryRegion for Joypad {
     fn read_byte(&self, addr: u16) -> u8 {
-        let mut ret = Register::new(self.state.r());
-        if  self.is_reading_direction() {
-            ret.set_bit(B_RIGHT_OR_A,       !self.pressed_right);
-            ret.set_bit(B_LEFT_OR_B,        !self.pressed_left);
-            ret.set_bit(B_DOWN_OR_START,    !self.pressed_down);
-            ret.set_bit(B_UP_OR_SELECT,     !self.pressed_up);
-        } else if self.is_reading_buttons() {
-            ret.set_bit(B_RIGHT_OR_A,       !self.pressed_a);
-            ret.set_bit(B_LEFT_OR_B,        !self.pressed_b);
-            ret.set_bit(B_DOWN_OR_START,    !self.pressed_start);
-            ret.set_bit(B_UP_OR_SELECT,     !self.pressed_select);
-        }
-        ret.r()
+        UNUSED_MASK | (self.state.r() & SELECT_MASK) | self.current_lines()
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
-        if val & (1 << B_SELECT_BUTTON) == 0
-            && val & (1 << B_SELECT_DIRECTION) == 0 {
-                panic!("Trying to read both Buttons and Direction at the same time!!");
-        }
-        self.state.set_bit(B_SELECT_BUTTON,     val & (1 << B_SELECT_BUTTON) > 0);        
+        self.state.set_bit(B_SELECT_BUTTON,     val & (1 << B_SELECT_BUTTON) > 0);
         self.state.set_bit(B_SELECT_DIRECTION,  val & (1 << B_SELECT_DIRECTION) > 0);
+        // Selecting a group with a button held pulls its line low as well
+        self.update_lines();
     }
 
     fn in_region(&self, addr: u16) -> bool {
@@ -157,4 +216,130 @@ impl MemoryRegion for Joypad {
     fn end(&self) -> u16 {
         JOYPAD_ADDR
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.state.r());
+        writer.write_bool(self.pressed_a);
+        writer.write_bool(self.pressed_b);
+        writer.write_bool(self.pressed_up);
+        writer.write_bool(self.pressed_down);
+        writer.write_bool(self.pressed_left);
+        writer.write_bool(self.pressed_right);
+        writer.write_bool(self.pressed_select);
+        writer.write_bool(self.pressed_start);
+        writer.write_bool(self.interrupt_generated);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.state.w(reader.read_u8()?);
+        self.pressed_a = reader.read_bool()?;
+        self.pressed_b = reader.read_bool()?;
+        self.pressed_up = reader.read_bool()?;
+        self.pressed_down = reader.read_bool()?;
+        self.pressed_left = reader.read_bool()?;
+        self.pressed_right = reader.read_bool()?;
+        self.pressed_select = reader.read_bool()?;
+        self.pressed_start = reader.read_bool()?;
+        self.interrupt_generated = reader.read_bool()?;
+        self.lines = self.current_lines();
+        Ok(())
+    }
+}
+
+fn line(bit: u8, pressed: bool) -> u8 {
+    if pressed {1 << bit} else {0}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECT_DIRECTIONS : u8 = 0x20;
+    const SELECT_BUTTONS    : u8 = 0x10;
+    const SELECT_NONE       : u8 = 0x30;
+
+    fn interrupts() -> Interrupts {
+        let mut interrupts = Interrupts::new();
+        interrupts.enable();
+        interrupts.write_byte(0xFFFF, 1 << InterruptType::Pad as u8);
+        interrupts
+    }
+
+    // Whether the joypad requested an interrupt since the last call
+    fn fired(joypad: &mut Joypad, interrupts: &mut Interrupts) -> bool {
+        joypad.step(4, interrupts);
+        interrupts.read_and_clear(InterruptType::Pad)
+    }
+
+    #[test]
+    fn only_the_selected_group_is_read() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(GbButton::A, true);
+        joypad.set_button(GbButton::Down, true);
+        joypad.write_byte(JOYPAD_ADDR, SELECT_DIRECTIONS);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR), 0xE7);
+        joypad.write_byte(JOYPAD_ADDR, SELECT_BUTTONS);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR), 0xDE);
+        joypad.write_byte(JOYPAD_ADDR, SELECT_NONE);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR), 0xFF);
+        // Both groups together are ANDed
+        joypad.write_byte(JOYPAD_ADDR, 0x00);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR), 0xC6);
+    }
+
+    #[test]
+    fn interrupts_fire_on_falling_edges_of_the_selected_lines() {
+        let mut joypad = Joypad::new();
+        let mut interrupts = interrupts();
+        joypad.write_byte(JOYPAD_ADDR, SELECT_DIRECTIONS);
+        fired(&mut joypad, &mut interrupts);
+
+        // Buttons of the group that isn't selected don't pull a line
+        joypad.set_button(GbButton::Start, true);
+        assert!(!fired(&mut joypad, &mut interrupts));
+        joypad.set_button(GbButton::Up, true);
+        assert!(fired(&mut joypad, &mut interrupts));
+        // Holding or releasing isn't an edge
+        joypad.set_button(GbButton::Up, true);
+        assert!(!fired(&mut joypad, &mut interrupts));
+        joypad.set_button(GbButton::Up, false);
+        assert!(!fired(&mut joypad, &mut interrupts));
+        joypad.set_button(GbButton::Down, true);
+        assert!(fired(&mut joypad, &mut interrupts));
+        // Line 3 is already low from Down, so selecting Start on the same line doesn't fire
+        joypad.write_byte(JOYPAD_ADDR, 0x00);
+        assert!(!fired(&mut joypad, &mut interrupts));
+        // Selecting the buttons with Start held pulls line 3 again
+        joypad.set_button(GbButton::Down, false);
+        joypad.write_byte(JOYPAD_ADDR, SELECT_NONE);
+        assert!(!fired(&mut joypad, &mut interrupts));
+        joypad.write_byte(JOYPAD_ADDR, SELECT_BUTTONS);
+        assert!(fired(&mut joypad, &mut interrupts));
+    }
+
+    #[test]
+    fn opposing_directions_can_be_blocked() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(JOYPAD_ADDR, SELECT_DIRECTIONS);
+        joypad.set_button(GbButton::Left, true);
+        joypad.set_button(GbButton::Right, true);
+        joypad.set_button(GbButton::Up, true);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR) & 0x0F, 0x08);
+
+        joypad.set_block_opposing_directions(true);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR) & 0x0F, 0x0B);
+        joypad.set_button(GbButton::Down, true);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR) & 0x0F, 0x0F);
+        // The buttons still count as held, for movies and the debugger
+        assert_eq!(joypad.buttons(), 0xF0);
+
+        // Releasing Right lets Left through, which is a new press
+        let mut interrupts = interrupts();
+        fired(&mut joypad, &mut interrupts);
+        joypad.set_button(GbButton::Right, false);
+        assert_eq!(joypad.read_byte(JOYPAD_ADDR) & 0x0F, 0x0D);
+        assert!(fired(&mut joypad, &mut interrupts));
+    }
+}
@@ -8,10 +8,11 @@ use hardware::video::screen::Screen;
 use hardware::interrupts::Interrupts;
 use hardware::interrupts::InterruptType;
 use hardware::joypad::Joypad;
+use hardware::serial::Serial;
 use hardware::video::gpu_constants::*;
 use hardware::video::sprites::*;
-
-use piston_window::*;
+use hardware::state::*;
+use hardware::debugger::watchpoints::Watchpoints;
 
 const BIOS_START                : u16 = 0x0000;
 const BIOS_END                  : u16 = 0x00FF;
@@ -47,6 +48,7 @@ pub struct BUS {
     cartridge_ram: PLAIN_RAM,
     pub interrupt_handler: Interrupts,
     pub joypad: Joypad,
+    pub serial: Serial,
 
     dma_start: Register<u8>,
     dma_target_addr: u16,
@@ -54,10 +56,12 @@ pub struct BUS {
 
     pub screen: Screen,    
     io_registers: IORegs,     
+
+    pub watchpoints: Watchpoints,
 }
 
 impl BUS {
-    pub fn new(window: &mut PistonWindow, boot_rom: Box<[u8]>, cartridge: Cartridge) -> Self {
+    pub fn new(boot_rom: Box<[u8]>, cartridge: Cartridge) -> Self {
         BUS {
             cartridge: cartridge,
             boot_rom: PLAIN_RAM::from_data(BIOS_START, BIOS_END, boot_rom),
@@ -70,6 +74,7 @@ impl BUS {
                 ]),
             interrupt_handler: Interrupts::new(),
             joypad: Joypad::new(),
+            serial: Serial::new(),
             cartridge_ram: PLAIN_RAM::new(CARTRIDGE_RAM_START, CARTRIDGE_RAM_END),
 
             dma_start: Register::new(0x00),
@@ -77,18 +82,60 @@ impl BUS {
             dma_cycles_remaining: 0xFFFF,
 
             io_registers: IORegs::new(),
-            screen: Screen::new(window),
+            screen: Screen::new(),
+
+            watchpoints: Watchpoints::new(),
         }
     }
 
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
     pub fn step(&mut self, cycles: u32) {
         self.gpu.step(cycles, &mut self.screen, &mut self.interrupt_handler);
         self.joypad.step(cycles, &mut self.interrupt_handler);
+        self.serial.step(cycles, &mut self.interrupt_handler);
         self.step_dma(cycles);
         self.interrupt_handler.step(cycles);
     }
 
-    pub fn read_byte(&self, addr: u16) -> u8 {  
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        let val = self.read_mapped(addr);
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.on_read(addr, val);
+        }
+        val
+    }
+
+    /// Reads without triggering watchpoints, for tools that inspect memory.
+    pub fn peek_byte(&self, addr: u16) -> u8 {
+        self.read_mapped(addr)
+    }
+
+    /// Writes without triggering watchpoints, for the debugger.
+    /// Writes to the cartridge change the ROM instead of going to the MBC.
+    pub fn poke_byte(&mut self, addr: u16, val: u8) {
+        if self.cartridge.in_region(addr) {
+            self.cartridge.patch_byte(addr, val);
+        } else {
+            self.write_mapped(addr, val);
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            let old_val = if self.watchpoints.watches_changes(addr) {
+                Some(self.read_mapped(addr))
+            } else {
+                None
+            };
+            self.watchpoints.on_write(addr, val, old_val);
+        }
+        self.write_mapped(addr, val);
+    }
+
+    fn read_mapped(&self, addr: u16) -> u8 {  
         quick_fix!({  
         if self.io_registers.boot_rom_enabled() && self.boot_rom.in_region(addr) {
             return self.boot_rom.read_byte(addr);
@@ -97,15 +144,17 @@ impl BUS {
         } else if self.gpu.in_region(addr) {
             return self.gpu.read_byte(addr);
         } else if self.storage_ram.in_region(addr) | (addr >= INTERNAL_RAM_ECHO_START && addr <= INTERNAL_RAM_ECHO_END) {
-            return self.storage_ram.read_byte(addr);
+            return self.storage_ram.read_byte(echo_to_ram(addr));
         } else if self.interrupt_handler.in_region(addr) {
             return self.interrupt_handler.read_byte(addr);
         } else if self.storage_zero_ram.in_region(addr) {
             return self.storage_zero_ram.read_byte(addr);
         } else if self.joypad.in_region(addr) {
             return self.joypad.read_byte(addr);
+        } else if self.serial.in_region(addr) {
+            return self.serial.read_byte(addr);
         } else if addr == DMA_START_ADDR {
-            panic!("DMA is write only");
+            return self.dma_start.r();
         } else if self.io_registers.in_region(addr) {
             return self.io_registers.read_byte(addr);
         } else if self.cartridge_ram.in_region(addr) {
@@ -119,7 +168,7 @@ impl BUS {
          It shouldn't be order-dependant. Fix it.");
     }
 
-    pub fn write_byte(&mut self, addr: u16, val: u8) {         
+    fn write_mapped(&mut self, addr: u16, val: u8) {         
         quick_fix!({
 
         if self.cartridge.in_region(addr) {
@@ -127,13 +176,15 @@ impl BUS {
         } else if self.gpu.in_region(addr) {
             self.gpu.write_byte(addr, val);
         } else if self.storage_ram.in_region(addr) | (addr >= INTERNAL_RAM_ECHO_START && addr <= INTERNAL_RAM_ECHO_END) {
-            self.storage_ram.write_byte(addr, val);
+            self.storage_ram.write_byte(echo_to_ram(addr), val);
         } else if self.interrupt_handler.in_region(addr) {
             self.interrupt_handler.write_byte(addr, val);
         } else if self.storage_zero_ram.in_region(addr) {
             self.storage_zero_ram.write_byte(addr, val);
         } else if self.joypad.in_region(addr) {
             self.joypad.write_byte(addr, val);
+        } else if self.serial.in_region(addr) {
+            self.serial.write_byte(addr, val);
         } else if addr == DMA_START_ADDR {
             self.setup_dma_transfer(val);
         } else if self.io_registers.in_region(addr) {
@@ -172,6 +223,7 @@ impl BUS {
     }
 
     fn setup_dma_transfer(&mut self, val: u8) {
+        self.dma_start.w(val);
         self.dma_target_addr = (val as u16) << 8;
         self.dma_cycles_remaining = DMA_CYCLES;
     }
@@ -195,6 +247,11 @@ impl BUS {
     }
 }
 
+// 0xE000-0xFDFF mirrors 0xC000-0xDDFF
+fn echo_to_ram(addr: u16) -> u16 {
+    if addr >= INTERNAL_RAM_ECHO_START {addr - (INTERNAL_RAM_ECHO_START - INTERNAL_RAM_START)} else {addr}
+}
+
 struct UnusedMemory {
     unused_regions: Vec<(u16, u16)>
 }
@@ -232,3 +289,75 @@ impl MemoryRegion for UnusedMemory {
         panic!("Unused Memory doesn't have just one end")
     }
 }
+
+// The boot ROM and the cartridge ROM are not saved, they have to be the same on load.
+// Cartridges are ROM only for now, so there is no banking state besides the cartridge RAM.
+impl Savestate for BUS {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.gpu.save_state(writer);
+        self.storage_ram.save_state(writer);
+        self.storage_zero_ram.save_state(writer);
+        self.cartridge_ram.save_state(writer);
+        self.interrupt_handler.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+
+        writer.write_u8(self.dma_start.r());
+        writer.write_u16(self.dma_target_addr);
+        writer.write_u32(self.dma_cycles_remaining);
+
+        self.screen.save_state(writer);
+        self.io_registers.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.gpu.load_state(reader)?;
+        self.storage_ram.load_state(reader)?;
+        self.storage_zero_ram.load_state(reader)?;
+        self.cartridge_ram.load_state(reader)?;
+        self.interrupt_handler.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+
+        self.dma_start.w(reader.read_u8()?);
+        self.dma_target_addr = reader.read_u16()?;
+        self.dma_cycles_remaining = reader.read_u32()?;
+
+        self.screen.load_state(reader)?;
+        self.io_registers.load_state(reader)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DMA_START_ADDR;
+    use test_rom;
+
+    #[test]
+    fn every_address_can_be_peeked() {
+        let gameboy = test_rom::gameboy(&[]);
+        let bus = &gameboy.cpu().bus;
+        for addr in 0..0x10000u32 {
+            bus.peek_byte(addr as u16);
+        }
+    }
+
+    #[test]
+    fn echo_ram_mirrors_the_internal_ram() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.write_byte(0xC123, 0x42);
+        assert_eq!(bus.peek_byte(0xE123), 0x42);
+        bus.write_byte(0xFDFF, 0x24);
+        assert_eq!(bus.read_byte(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn the_dma_register_reads_back() {
+        let mut gameboy = test_rom::gameboy(&[]);
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.write_byte(DMA_START_ADDR, 0xC1);
+        assert_eq!(bus.read_byte(DMA_START_ADDR), 0xC1);
+    }
+}
@@ -1,10 +1,11 @@
 use hardware::registers::Register;
 use hardware::memory::memory_region::MemoryRegion;
 use hardware::memory::memory_region::BitAccess;
+use hardware::state::*;
 
 const IO_MEMORY_START           : u16 = 0xFF01;
 const IO_MEMORY_END             : u16 = 0xFF3F;
-const BOOT_ROM_ENABLE           : u16 = 0xFF50;
+pub const BOOT_ROM_ENABLE       : u16 = 0xFF50;
 
 pub struct IORegs {
     contents: [Register<u8>; 0x3F],
@@ -71,3 +72,20 @@ impl BitAccess for IORegs {
     }
 }
 
+impl Savestate for IORegs {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let contents: Vec<u8> = self.contents.iter().map(|reg| reg.r()).collect();
+        writer.write_bytes(&contents);
+        writer.write_u8(self.boot_rom_enable.r());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut contents = [0u8; 0x3F];
+        reader.read_into(&mut contents)?;
+        for (reg, val) in self.contents.iter_mut().zip(contents.iter()) {
+            reg.w(*val);
+        }
+        self.boot_rom_enable.w(reader.read_u8()?);
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use hardware::memory::memory_region::MemoryRegion;
 use hardware::memory::memory_region::BitAccess;
+use hardware::state::*;
 
 pub struct PLAIN_RAM {
     storage: Vec<u8>,
@@ -74,4 +75,14 @@ impl BitAccess for PLAIN_RAM {
             self.storage[tru_addr as usize] = cur_val & !(1 << bit);
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for PLAIN_RAM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.storage);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_into(&mut self.storage)
+    }
+}
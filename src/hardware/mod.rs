@@ -10,14 +10,19 @@ macro_rules! quick_fix {
 }
 
 pub mod cpu;
+pub mod call_stack;
 pub mod cartridge;
 pub mod instructions;
+pub mod disassembler;
 pub mod memory;
 pub mod debugger;
 pub mod registers;
 pub mod video;
 pub mod interrupts;
 pub mod joypad;
+pub mod serial;
+pub mod state;
+pub mod test_runner;
 
 pub fn hex_print(title: &'static str, data: &[u8], bytes_per_line: u8) {
     println!("{}", title);
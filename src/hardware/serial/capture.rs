@@ -0,0 +1,63 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use hardware::serial::SerialDevice;
+
+/// Read side of a `SerialCapture`, kept by whoever wants to inspect the output.
+#[derive(Clone)]
+pub struct CapturedOutput {
+    text: Rc<RefCell<String>>,
+}
+
+impl CapturedOutput {
+    pub fn text(&self) -> String {
+        self.text.borrow().clone()
+    }
+
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.text.borrow().contains(pattern)
+    }
+
+    pub fn clear(&self) {
+        self.text.borrow_mut().clear();
+    }
+}
+
+/// Collects every byte the Game Boy sends as text.
+/// Test ROMs such as Blargg's report their results this way.
+pub struct SerialCapture {
+    text: Rc<RefCell<String>>,
+}
+
+impl SerialCapture {
+    pub fn new() -> (SerialCapture, CapturedOutput) {
+        let text = Rc::new(RefCell::new(String::new()));
+        (SerialCapture { text: text.clone() }, CapturedOutput { text: text })
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.text.borrow_mut().push(data as char);
+        0xFF
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SerialCapture;
+    use hardware::serial::SerialDevice;
+
+    #[test]
+    fn every_byte_shows_up_in_the_output() {
+        let (mut capture, output) = SerialCapture::new();
+        let reader = output.clone();
+        for byte in b"Passed\n" {
+            assert_eq!(capture.exchange(*byte), 0xFF);
+        }
+        assert_eq!(reader.text(), "Passed\n");
+        assert!(output.contains("ass"));
+        output.clear();
+        assert_eq!(reader.text(), "");
+    }
+}
@@ -0,0 +1,353 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::fs;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use hardware::serial::SerialDevice;
+use hardware::serial::SERIAL_TRANSFER_CYCLES;
+
+// Both emulators stop every SYNC_QUANTUM cycles until the other one catches up.
+// It has to be shorter than a transfer, so that the peer always hears about a
+// transfer before the moment it has to answer it.
+const SYNC_QUANTUM      : u64 = 2048;
+
+const MESSAGE_SIZE      : usize = 10;
+const MSG_SYNC          : u8 = 0;
+const MSG_TRANSFER      : u8 = 1;
+const MSG_REPLY         : u8 = 2;
+
+const UNIX_PREFIX       : &'static str = "unix:";
+
+// A peer that sends nothing for this long, paused in the debugger for example,
+// is treated as unplugged until it talks again
+const STALL_TIMEOUT_MS  : u64 = 1000;
+
+pub trait LinkStream: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl LinkStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+enum Message {
+    Sync,
+    // A byte clocked out by the peer, and the cycle at which the transfer completes
+    Transfer(u8, u64),
+    Reply(u8),
+}
+
+/// Link cable between two emulator instances.
+/// Cycles are counted from the moment the cable is connected, and both sides
+/// run in lockstep so that transfers land on the same cycle on both ends.
+pub struct LinkCable {
+    stream: Box<LinkStream>,
+    connected: bool,
+    // The peer stopped answering, transfers read 0xFF and nobody waits for it
+    stalled: bool,
+    // Bytes of a message that didn't fully arrive yet
+    pending: Vec<u8>,
+
+    cycles: u64,
+    next_sync: u64,
+    local_syncs: u64,
+    peer_syncs: u64,
+
+    // Transfer started by the peer, answered once we reach its completion cycle
+    incoming: Option<(u8, u64)>,
+    // Answer to the transfer we are clocking
+    reply: Option<u8>,
+    sending: bool,
+}
+
+impl LinkCable {
+    pub fn new(stream: Box<LinkStream>) -> Self {
+        if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(STALL_TIMEOUT_MS))) {
+            println!("Link cable without a timeout, a paused peer will freeze this one: {}", e);
+        }
+        LinkCable {
+            stream: stream,
+            connected: true,
+            stalled: false,
+            pending: Vec::with_capacity(MESSAGE_SIZE),
+
+            cycles: 0,
+            next_sync: SYNC_QUANTUM,
+            local_syncs: 0,
+            peer_syncs: 0,
+
+            incoming: None,
+            reply: None,
+            sending: false,
+        }
+    }
+
+    /// Waits for the other emulator to join.
+    /// `addr` is either "host:port" or "unix:/path/to/socket".
+    pub fn host(addr: &str) -> io::Result<Self> {
+        if addr.starts_with(UNIX_PREFIX) {
+            LinkCable::host_unix(&addr[UNIX_PREFIX.len()..])
+        } else {
+            let listener = TcpListener::bind(addr)?;
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            Ok(LinkCable::new(Box::new(stream)))
+        }
+    }
+
+    /// Connects to an emulator started with `host`.
+    pub fn join(addr: &str) -> io::Result<Self> {
+        if addr.starts_with(UNIX_PREFIX) {
+            LinkCable::join_unix(&addr[UNIX_PREFIX.len()..])
+        } else {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Ok(LinkCable::new(Box::new(stream)))
+        }
+    }
+
+    #[cfg(unix)]
+    fn host_unix(path: &str) -> io::Result<Self> {
+        // A socket left behind by a previous session would make bind fail
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    #[cfg(unix)]
+    fn join_unix(path: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    #[cfg(not(unix))]
+    fn host_unix(path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+    }
+
+    #[cfg(not(unix))]
+    fn join_unix(path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, msg: Message) {
+        if !self.connected {return}
+        let mut buf = [0u8; MESSAGE_SIZE];
+        match msg {
+            Message::Sync => {buf[0] = MSG_SYNC;}
+            Message::Transfer(byte, at) => {
+                buf[0] = MSG_TRANSFER;
+                buf[1] = byte;
+                for i in 0..8 {
+                    buf[2 + i] = (at >> (8 * i)) as u8;
+                }
+            }
+            Message::Reply(byte) => {buf[0] = MSG_REPLY; buf[1] = byte;}
+        }
+        if let Err(e) = self.stream.write_all(&buf) {
+            self.disconnect(e);
+        }
+    }
+
+    /// Handles whatever the peer sent, waiting for it at most STALL_TIMEOUT_MS,
+    /// or not at all while it is stalled.
+    fn receive(&mut self) {
+        if !self.connected {return}
+        let mut buf = [0u8; 16 * MESSAGE_SIZE];
+        match self.stream.read(&mut buf) {
+            Ok(0) => {
+                self.disconnect(io::Error::new(io::ErrorKind::UnexpectedEof, "the other emulator left"));
+            }
+            Ok(length) => {
+                if self.stalled {
+                    println!("Link cable: the other emulator is back");
+                    self.set_stalled(false);
+                }
+                self.pending.extend_from_slice(&buf[..length]);
+                while self.connected && self.pending.len() >= MESSAGE_SIZE {
+                    let message: Vec<u8> = self.pending.drain(..MESSAGE_SIZE).collect();
+                    self.handle(&message);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                if !self.stalled {
+                    println!("Link cable: the other emulator stopped answering, it reads as unplugged until it is back");
+                    self.set_stalled(true);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => self.disconnect(e),
+        }
+    }
+
+    // A stalled peer is only polled, so that this emulator keeps running
+    fn set_stalled(&mut self, stalled: bool) {
+        self.stalled = stalled;
+        if let Err(e) = self.stream.set_nonblocking(stalled) {
+            self.disconnect(e);
+        }
+    }
+
+    fn handle(&mut self, buf: &[u8]) {
+        match buf[0] {
+            MSG_SYNC => {self.peer_syncs += 1;}
+            MSG_TRANSFER => {
+                let mut at = 0u64;
+                for i in 0..8 {
+                    at |= (buf[2 + i] as u64) << (8 * i);
+                }
+                if self.sending {
+                    // Both ends are driving the clock, nobody answers
+                    self.send(Message::Reply(0xFF));
+                } else {
+                    self.incoming = Some((buf[1], at));
+                }
+            }
+            MSG_REPLY => {self.reply = Some(buf[1]);}
+            other => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, format!("unknown message {:02X}", other));
+                self.disconnect(e);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        println!("Link cable disconnected: {}", e);
+        self.connected = false;
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn start_transfer(&mut self, data: u8) {
+        if self.incoming.take().is_some() {
+            self.send(Message::Reply(0xFF));
+        }
+        self.sending = true;
+        self.reply = None;
+        let at = self.cycles + SERIAL_TRANSFER_CYCLES as u64;
+        self.send(Message::Transfer(data, at));
+    }
+
+    fn exchange(&mut self, data: u8) -> u8 {
+        while self.connected && !self.stalled && self.reply.is_none() {
+            self.receive();
+        }
+        self.sending = false;
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn step(&mut self, cycles: u32, slave_data: Option<u8>) -> Option<u8> {
+        self.cycles += cycles as u64;
+
+        let mut received = None;
+        if let Some((byte, at)) = self.incoming {
+            if self.cycles >= at {
+                self.incoming = None;
+                let answer = match slave_data {
+                    Some(data) => {received = Some(byte); data}
+                    None => 0xFF
+                };
+                self.send(Message::Reply(answer));
+            }
+        }
+
+        while self.connected && self.cycles >= self.next_sync {
+            self.next_sync += SYNC_QUANTUM;
+            if self.stalled {
+                // No syncs pile up in the socket meanwhile, the peer still
+                // waits for the one it missed and both pick up from there
+                self.receive();
+                continue;
+            }
+            self.send(Message::Sync);
+            self.local_syncs += 1;
+            while self.connected && !self.stalled && self.peer_syncs < self.local_syncs {
+                self.receive();
+            }
+        }
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::thread;
+
+    use hardware::interrupts::Interrupts;
+    use hardware::memory::memory_region::MemoryRegion;
+    use hardware::serial::Serial;
+    use hardware::serial::SERIAL_CONTROL_ADDR;
+    use hardware::serial::SERIAL_DATA_ADDR;
+    use hardware::serial::SERIAL_TRANSFER_CYCLES;
+    use super::LinkCable;
+
+    // Both ends of a local connection, each one becomes a cable in its own thread
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let joined = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (hosted, _) = listener.accept().unwrap();
+        (hosted, joined)
+    }
+
+    // Puts `out` in SB, starts a transfer and runs until it is over
+    fn transfer(stream: TcpStream, internal_clock: bool, out: u8) -> u8 {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        serial.connect(Box::new(LinkCable::new(Box::new(stream))));
+        serial.write_byte(SERIAL_DATA_ADDR, out);
+        serial.write_byte(SERIAL_CONTROL_ADDR, if internal_clock {0x81} else {0x80});
+        for _ in 0..SERIAL_TRANSFER_CYCLES * 4 {
+            serial.step(4, &mut interrupts);
+        }
+        assert!(serial.read_byte(SERIAL_CONTROL_ADDR) & 0x80 == 0, "the transfer didn't finish");
+        serial.read_byte(SERIAL_DATA_ADDR)
+    }
+
+    #[test]
+    fn bytes_cross_the_cable_both_ways() {
+        let (master, slave) = stream_pair();
+        let slave_thread = thread::spawn(move || transfer(slave, false, 0x99));
+        let master_received = transfer(master, true, 0x42);
+        let slave_received = slave_thread.join().unwrap();
+        assert_eq!(master_received, 0x99);
+        assert_eq!(slave_received, 0x42);
+    }
+
+    #[test]
+    fn a_stalled_peer_reads_as_unplugged() {
+        let (master, _silent) = stream_pair();
+        assert_eq!(transfer(master, true, 0x42), 0xFF);
+    }
+}
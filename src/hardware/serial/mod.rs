@@ -0,0 +1,170 @@
+pub mod capture;
+pub mod link_cable;
+pub mod printer;
+
+use hardware::memory::memory_region::MemoryRegion;
+use hardware::registers::Register;
+use hardware::interrupts::Interrupts;
+use hardware::interrupts::InterruptType;
+use hardware::serial::capture::SerialCapture;
+use hardware::serial::capture::CapturedOutput;
+use hardware::state::*;
+
+pub const SERIAL_DATA_ADDR      : u16 = 0xFF01;
+pub const SERIAL_CONTROL_ADDR   : u16 = 0xFF02;
+
+// With the internal clock at 8192Hz, shifting 8 bits takes 4096 cycles
+pub const SERIAL_TRANSFER_CYCLES : u32 = 4096;
+
+const B_TRANSFER_START          : u8 = 7;
+const B_INTERNAL_CLOCK          : u8 = 0;
+
+/// Anything that can sit on the other end of the link port.
+pub trait SerialDevice {
+    /// Called when the Game Boy starts a transfer driving the clock itself.
+    fn start_transfer(&mut self, data: u8) {}
+
+    /// Called when an internally clocked transfer completes.
+    /// Returns the byte that was shifted in from the device.
+    fn exchange(&mut self, data: u8) -> u8;
+
+    /// Advances the device by `cycles`. `slave_data` holds SB when the Game Boy is
+    /// waiting for an external clock. If the device clocked a full byte in,
+    /// it returns the byte the Game Boy receives.
+    fn step(&mut self, cycles: u32, slave_data: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+pub struct Serial {
+    data: Register<u8>,
+    control: Register<u8>,
+
+    // Cycles left for an internally clocked transfer, 0 when idle
+    transfer_cycles_remaining: u32,
+
+    device: Option<Box<SerialDevice>>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: Register::new(0x00),
+            control: Register::new(0x00),
+            transfer_cycles_remaining: 0,
+            device: None,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    /// Plugs in a `SerialCapture` and returns the handle to read what it collects.
+    pub fn capture(&mut self) -> CapturedOutput {
+        let (capture, output) = SerialCapture::new();
+        self.connect(Box::new(capture));
+        output
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<SerialDevice>> {
+        self.device.take()
+    }
+
+    pub fn step(&mut self, cycles: u32, interrupt_handler: &mut Interrupts) {
+        let slave_data = if self.waiting_external_clock() {Some(self.data.r())} else {None};
+        let received = match self.device {
+            Some(ref mut device) => device.step(cycles, slave_data),
+            None => None
+        };
+        if let Some(byte) = received {
+            if self.waiting_external_clock() {
+                self.finish_transfer(byte, interrupt_handler);
+            }
+        }
+
+        if self.transfer_cycles_remaining == 0 {return}
+        if self.transfer_cycles_remaining <= cycles {
+            self.transfer_cycles_remaining = 0;
+            let out = self.data.r();
+            let byte = match self.device {
+                Some(ref mut device) => device.exchange(out),
+                // Nothing plugged in, the line floats high
+                None => 0xFF
+            };
+            self.finish_transfer(byte, interrupt_handler);
+        } else {
+            self.transfer_cycles_remaining -= cycles;
+        }
+    }
+
+    fn waiting_external_clock(&self) -> bool {
+        self.control.is_bit_set(B_TRANSFER_START) && !self.control.is_bit_set(B_INTERNAL_CLOCK)
+    }
+
+    fn start_internal_transfer(&mut self) {
+        self.transfer_cycles_remaining = SERIAL_TRANSFER_CYCLES;
+        let out = self.data.r();
+        if let Some(ref mut device) = self.device {
+            device.start_transfer(out);
+        }
+    }
+
+    fn finish_transfer(&mut self, received: u8, interrupt_handler: &mut Interrupts) {
+        self.data.w(received);
+        self.control.set_bit(B_TRANSFER_START, false);
+        interrupt_handler.set_interrupt(InterruptType::Serial);
+    }
+}
+
+impl MemoryRegion for Serial {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            SERIAL_DATA_ADDR    => {self.data.r()}
+            // Unused bits read as 1
+            SERIAL_CONTROL_ADDR => {self.control.r() | 0b01111110}
+            _ => {panic!("Serial can't access memory location {:04X}", addr);}
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        match addr {
+            SERIAL_DATA_ADDR    => {self.data.w(val);}
+            SERIAL_CONTROL_ADDR => {
+                self.control.w(val & 0b10000001);
+                if self.control.is_bit_set(B_TRANSFER_START) && self.control.is_bit_set(B_INTERNAL_CLOCK) {
+                    self.start_internal_transfer();
+                } else {
+                    self.transfer_cycles_remaining = 0;
+                }
+            }
+            _ => {panic!("Serial can't access memory location {:04X}", addr);}
+        }
+    }
+
+    fn in_region(&self, addr: u16) -> bool {
+        addr == SERIAL_DATA_ADDR || addr == SERIAL_CONTROL_ADDR
+    }
+    fn start(&self) -> u16 {
+        SERIAL_DATA_ADDR
+    }
+    fn end(&self) -> u16 {
+        SERIAL_CONTROL_ADDR
+    }
+}
+
+// The device on the other end is not part of the state, only the port is
+impl Savestate for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data.r());
+        writer.write_u8(self.control.r());
+        writer.write_u32(self.transfer_cycles_remaining);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.data.w(reader.read_u8()?);
+        self.control.w(reader.read_u8()?);
+        self.transfer_cycles_remaining = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+use std::path::PathBuf;
+use image::{ImageBuffer, RgbaImage, Rgba};
+
+use hardware::serial::SerialDevice;
+
+const MAGIC_1                   : u8 = 0x88;
+const MAGIC_2                   : u8 = 0x33;
+const ALIVE                     : u8 = 0x81;
+
+const CMD_INIT                  : u8 = 0x01;
+const CMD_PRINT                 : u8 = 0x02;
+const CMD_DATA                  : u8 = 0x04;
+const CMD_STATUS                : u8 = 0x0F;
+
+// Status bits
+const B_CHECKSUM_ERROR          : u8 = 0;
+const B_PRINTING                : u8 = 1;
+const B_IMAGE_DATA_FULL         : u8 = 2;
+const B_UNPROCESSED_DATA        : u8 = 3;
+
+pub const PRINTER_WIDTH         : u32 = 160;
+const TILES_PER_ROW             : usize = 20;
+const TILE_BYTES                : usize = 16;
+const ROW_BYTES                 : usize = TILES_PER_ROW * TILE_BYTES;
+// The printer RAM holds 9 bands of 2 tile rows
+const BUFFER_SIZE               : usize = 9 * 2 * ROW_BYTES;
+
+// Number of status queries the printer reports itself busy after a print
+const PRINT_BUSY_QUERIES        : u8 = 4;
+
+pub const PAPER_PALETTE: [[u8; 4]; 4] = [
+    [255, 255, 255, 255],
+    [170, 170, 170, 255],
+    [ 85,  85,  85, 255],
+    [  0,   0,   0, 255],
+];
+
+pub struct PrinterConfig {
+    pub output_dir: PathBuf,
+    /// Colors for the four shades, from white to black
+    pub palette: [[u8; 4]; 4],
+    /// Blank pixel rows per margin unit requested by the game
+    pub margin_unit: u32,
+    /// Blank pixel columns added to each side of the paper
+    pub side_margin: u32,
+}
+
+impl PrinterConfig {
+    pub fn new(output_dir: PathBuf) -> Self {
+        PrinterConfig {
+            output_dir: output_dir,
+            palette: PAPER_PALETTE,
+            margin_unit: 8,
+            side_margin: 0,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLo,
+    LengthHi,
+    Data,
+    ChecksumLo,
+    ChecksumHi,
+    Alive,
+    Status,
+}
+
+struct Packet {
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    sum: u16,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Packet {
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            sum: 0,
+        }
+    }
+}
+
+/// Game Boy Printer. Printed strips pile up on the current sheet of paper,
+/// which is written to a PNG once the game feeds paper after it.
+pub struct Printer {
+    config: PrinterConfig,
+
+    state: PacketState,
+    packet: Packet,
+    status: u8,
+    busy_queries: u8,
+
+    // Raw tile data received since the last print
+    buffer: Vec<u8>,
+    // Shades of the sheet being printed, one byte per pixel
+    paper: Vec<u8>,
+    printed_pages: u32,
+}
+
+impl Printer {
+    pub fn new(config: PrinterConfig) -> Self {
+        Printer {
+            config: config,
+
+            state: PacketState::Magic1,
+            packet: Packet::new(),
+            status: 0x00,
+            busy_queries: 0,
+
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            paper: Vec::new(),
+            printed_pages: 0,
+        }
+    }
+
+    /// Writes the sheet being printed, if any, to a PNG.
+    pub fn flush(&mut self) {
+        if self.paper.is_empty() {return}
+        let page = self.paper.clone();
+        self.paper.clear();
+        self.save_page(&page);
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        let state = self.state;
+        match state {
+            PacketState::Magic1 => {
+                if byte == MAGIC_1 {self.state = PacketState::Magic2;}
+            }
+            PacketState::Magic2 => {
+                self.state = if byte == MAGIC_2 {PacketState::Command} else {PacketState::Magic1};
+            }
+            PacketState::Command => {
+                self.packet = Packet::new();
+                self.packet.command = byte;
+                self.packet.sum = byte as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.packet.compressed = byte & 0x01 > 0;
+                self.packet.sum = self.packet.sum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthLo;
+            }
+            PacketState::LengthLo => {
+                self.packet.length = byte as u16;
+                self.packet.sum = self.packet.sum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthHi;
+            }
+            PacketState::LengthHi => {
+                self.packet.length |= (byte as u16) << 8;
+                self.packet.sum = self.packet.sum.wrapping_add(byte as u16);
+                self.state = if self.packet.length > 0 {PacketState::Data} else {PacketState::ChecksumLo};
+            }
+            PacketState::Data => {
+                self.packet.data.push(byte);
+                self.packet.sum = self.packet.sum.wrapping_add(byte as u16);
+                if self.packet.data.len() == self.packet.length as usize {
+                    self.state = PacketState::ChecksumLo;
+                }
+            }
+            PacketState::ChecksumLo => {
+                self.packet.checksum = byte as u16;
+                self.state = PacketState::ChecksumHi;
+            }
+            PacketState::ChecksumHi => {
+                self.packet.checksum |= (byte as u16) << 8;
+                self.process_packet();
+                self.state = PacketState::Alive;
+            }
+            PacketState::Alive => {
+                response = ALIVE;
+                self.state = PacketState::Status;
+            }
+            PacketState::Status => {
+                response = self.status;
+                self.state = PacketState::Magic1;
+            }
+        }
+        response
+    }
+
+    fn process_packet(&mut self) {
+        let checksum_ok = self.packet.checksum == self.packet.sum;
+        self.set_status(B_CHECKSUM_ERROR, !checksum_ok);
+        if !checksum_ok {return}
+
+        match self.packet.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0x00;
+                self.busy_queries = 0;
+            }
+            CMD_DATA => {
+                let data = if self.packet.compressed {
+                    decompress(&self.packet.data)
+                } else {
+                    self.packet.data.clone()
+                };
+                let room = BUFFER_SIZE - self.buffer.len();
+                let take = if data.len() < room {data.len()} else {room};
+                self.buffer.extend_from_slice(&data[..take]);
+                self.set_status(B_UNPROCESSED_DATA, !self.buffer.is_empty());
+                self.set_status(B_IMAGE_DATA_FULL, self.buffer.len() == BUFFER_SIZE);
+            }
+            CMD_PRINT => {
+                if self.packet.data.len() >= 4 {
+                    let margins = self.packet.data[1];
+                    let palette = self.packet.data[2];
+                    self.print(margins >> 4, margins & 0x0F, palette);
+                }
+                self.buffer.clear();
+                self.set_status(B_UNPROCESSED_DATA, false);
+                self.set_status(B_IMAGE_DATA_FULL, false);
+                self.set_status(B_PRINTING, true);
+                self.busy_queries = PRINT_BUSY_QUERIES;
+            }
+            CMD_STATUS => {
+                if self.busy_queries > 0 {
+                    self.busy_queries -= 1;
+                    if self.busy_queries == 0 {self.set_status(B_PRINTING, false);}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_status(&mut self, bit: u8, val: bool) {
+        if val {
+            self.status |= 1 << bit;
+        } else {
+            self.status &= !(1 << bit);
+        }
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        // A palette of 0 behaves like the usual 0xE4
+        let palette = if palette == 0 {0xE4} else {palette};
+        let width = PRINTER_WIDTH as usize;
+
+        self.feed_paper(margin_before);
+        let rows = self.buffer.len() / ROW_BYTES;
+        for row in 0..rows {
+            for line in 0..8 {
+                for x in 0..width {
+                    let tile = &self.buffer[row * ROW_BYTES + (x / 8) * TILE_BYTES..];
+                    let lo = (tile[line * 2] >> (7 - x % 8)) & 1;
+                    let hi = (tile[line * 2 + 1] >> (7 - x % 8)) & 1;
+                    let color = (hi << 1) | lo;
+                    self.paper.push((palette >> (color * 2)) & 0b11);
+                }
+            }
+        }
+
+        if margin_after > 0 {
+            self.feed_paper(margin_after);
+            self.flush();
+        }
+    }
+
+    fn feed_paper(&mut self, margin: u8) {
+        let lines = margin as usize * self.config.margin_unit as usize;
+        for _ in 0..lines * PRINTER_WIDTH as usize {
+            self.paper.push(0);
+        }
+    }
+
+    fn save_page(&mut self, page: &[u8]) {
+        let side = self.config.side_margin;
+        let height = (page.len() / PRINTER_WIDTH as usize) as u32;
+        let mut img: RgbaImage = ImageBuffer::new(PRINTER_WIDTH + 2 * side, height);
+        let white = self.config.palette[0];
+        for pixel in img.pixels_mut() {
+            *pixel = Rgba { data: white };
+        }
+        for (i, shade) in page.iter().enumerate() {
+            let x = (i % PRINTER_WIDTH as usize) as u32 + side;
+            let y = (i / PRINTER_WIDTH as usize) as u32;
+            img.put_pixel(x, y, Rgba { data: self.config.palette[*shade as usize] });
+        }
+
+        let path = self.next_page_path();
+        match img.save(&path) {
+            Ok(_) => println!("Printed {}", path.display()),
+            Err(e) => println!("Failed to save printout {}: {}", path.display(), e)
+        }
+    }
+
+    fn next_page_path(&mut self) -> PathBuf {
+        loop {
+            self.printed_pages += 1;
+            let path = self.config.output_dir.join(format!("printout_{:04}.png", self.printed_pages));
+            if !path.exists() {
+                return path;
+            }
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.receive(data)
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Expands the printer RLE: a control byte with bit 7 set repeats the next byte
+/// (control & 0x7F) + 2 times, otherwise (control + 1) literal bytes follow.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(ROW_BYTES * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 > 0 {
+            if i >= data.len() {break}
+            let count = (control & 0x7F) as usize + 2;
+            for _ in 0..count {
+                out.push(data[i]);
+            }
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = if i + count < data.len() {i + count} else {data.len()};
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use image;
+    use super::*;
+
+    // Sends a whole packet and returns the last two bytes the printer answers with
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8], checksum_offset: u16) -> (u8, u8) {
+        let mut bytes = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16)).wrapping_add(checksum_offset);
+        for byte in [MAGIC_1, MAGIC_2].iter().chain(bytes.iter()).chain([sum as u8, (sum >> 8) as u8].iter()) {
+            assert_eq!(printer.exchange(*byte), 0);
+        }
+        (printer.exchange(0), printer.exchange(0))
+    }
+
+    #[test]
+    fn rle_expands_runs_and_literals() {
+        assert_eq!(decompress(&[]), Vec::<u8>::new());
+        assert_eq!(decompress(&[0x02, 1, 2, 3]), vec![1, 2, 3]);
+        assert_eq!(decompress(&[0x81, 0xAA]), vec![0xAA; 3]);
+        assert_eq!(decompress(&[0xFF, 0x55]), vec![0x55; 129]);
+        assert_eq!(decompress(&[0x00, 7, 0x80, 9, 0x01, 4, 5]), vec![7, 9, 9, 4, 5]);
+        // A packet cut short keeps what it had
+        assert_eq!(decompress(&[0x03, 1, 2]), vec![1, 2]);
+        assert_eq!(decompress(&[0x00, 1, 0x85]), vec![1]);
+    }
+
+    #[test]
+    fn status_follows_the_packets() {
+        let mut printer = Printer::new(PrinterConfig::new(env::temp_dir()));
+        assert_eq!(send(&mut printer, CMD_INIT, false, &[], 0), (ALIVE, 0x00));
+        assert_eq!(send(&mut printer, CMD_DATA, false, &[0; ROW_BYTES], 1), (ALIVE, 1 << B_CHECKSUM_ERROR));
+        assert_eq!(send(&mut printer, CMD_DATA, true, &[0xFF, 0x00], 0).1, 1 << B_UNPROCESSED_DATA);
+        for _ in 0..BUFFER_SIZE / ROW_BYTES {
+            send(&mut printer, CMD_DATA, false, &[0; ROW_BYTES], 0);
+        }
+        assert_eq!(printer.buffer.len(), BUFFER_SIZE);
+        assert_eq!(send(&mut printer, CMD_STATUS, false, &[], 0).1, 1 << B_UNPROCESSED_DATA | 1 << B_IMAGE_DATA_FULL);
+        // A print keeps the printer busy for a few status queries
+        assert_eq!(send(&mut printer, CMD_PRINT, false, &[1, 0x00, 0xE4, 0x40], 0).1, 1 << B_PRINTING);
+        for _ in 1..PRINT_BUSY_QUERIES {
+            assert_eq!(send(&mut printer, CMD_STATUS, false, &[], 0).1, 1 << B_PRINTING);
+        }
+        assert_eq!(send(&mut printer, CMD_STATUS, false, &[], 0).1, 0x00);
+        printer.paper.clear();
+    }
+
+    #[test]
+    fn prints_go_to_png_after_the_margin() {
+        let dir = env::temp_dir().join(format!("rustikgb-printer-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut printer = Printer::new(PrinterConfig::new(dir.clone()));
+        send(&mut printer, CMD_INIT, false, &[], 0);
+        // One row of tiles with the top line in color 3 and the rest in color 1
+        let mut row = vec![0; ROW_BYTES];
+        for tile in row.chunks_mut(TILE_BYTES) {
+            tile[0] = 0xFF;
+            tile[1] = 0xFF;
+            for line in 1..8 {
+                tile[line * 2] = 0xFF;
+            }
+        }
+        send(&mut printer, CMD_DATA, false, &row, 0);
+        send(&mut printer, CMD_PRINT, false, &[1, 0x01, 0xE4, 0x40], 0);
+
+        let page = image::open(dir.join("printout_0001.png")).unwrap().to_rgba();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(page.dimensions(), (PRINTER_WIDTH, 8 + 8));
+        assert_eq!(page.get_pixel(0, 0).data, PAPER_PALETTE[3]);
+        assert_eq!(page.get_pixel(159, 1).data, PAPER_PALETTE[1]);
+        assert_eq!(page.get_pixel(80, 15).data, PAPER_PALETTE[0]);
+        assert!(printer.paper.is_empty());
+    }
+}
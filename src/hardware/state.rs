@@ -0,0 +1,126 @@
+// Save state format:
+//  "RGBS" magic, u16 version, then every component in a fixed order.
+// All multi-byte values are little endian.
+
+pub const STATE_MAGIC   : &'static [u8; 4] = b"RGBS";
+pub const STATE_VERSION : u16 = 1;
+
+/// Components that can be snapshotted. `load_state` reads back exactly
+/// what `save_state` wrote, in the same order.
+pub trait Savestate {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.buf.push(if val {1} else {0});
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.buf.push(val as u8);
+        self.buf.push((val >> 8) as u8);
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.write_u16(val as u16);
+        self.write_u16((val >> 16) as u16);
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.write_u32(val as u32);
+        self.write_u32((val >> 32) as u32);
+    }
+
+    /// Length-prefixed block of bytes
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        if self.pos >= self.data.len() {
+            return Err(String::from("the save state is truncated"));
+        }
+        self.pos += 1;
+        Ok(self.data[self.pos - 1])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let lo = self.read_u8()? as u16;
+        let hi = self.read_u8()? as u16;
+        Ok(hi << 8 | lo)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let lo = self.read_u16()? as u32;
+        let hi = self.read_u16()? as u32;
+        Ok(hi << 16 | lo)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let lo = self.read_u32()? as u64;
+        let hi = self.read_u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.read_u32()? as usize;
+        if self.data.len() - self.pos < len {
+            return Err(String::from("the save state is truncated"));
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.data[start..self.pos])
+    }
+
+    /// Reads a block of bytes that has to be exactly as big as `target`.
+    pub fn read_into(&mut self, target: &mut [u8]) -> Result<(), String> {
+        let data = self.read_bytes()?;
+        if data.len() != target.len() {
+            return Err(format!("expected a block of {} bytes in the save state, found {}", target.len(), data.len()));
+        }
+        target.copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use hardware::cpu::CPU;
+use hardware::debugger::Debugger;
+use hardware::instructions::InstructionSet;
+
+pub enum TestResult {
+    Passed(String),
+    Failed(String),
+    // The ROM didn't report anything within the frame limit
+    Timeout(String),
+    // The debugger ended the program before the ROM reported anything
+    Stopped(String),
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        match *self {
+            TestResult::Passed(_) => true,
+            _ => false
+        }
+    }
+
+    pub fn output(&self) -> &str {
+        match *self {
+            TestResult::Passed(ref out) => out,
+            TestResult::Failed(ref out) => out,
+            TestResult::Timeout(ref out) => out,
+            TestResult::Stopped(ref out) => out,
+        }
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = match *self {
+            TestResult::Passed(_) => "PASSED",
+            TestResult::Failed(_) => "FAILED",
+            TestResult::Timeout(_) => "TIMEOUT",
+            TestResult::Stopped(_) => "STOPPED",
+        };
+        writeln!(fmt, "{}\nSerial output:\n{}", verdict, self.output())
+    }
+}
+
+/// Runs a test ROM that reports through the serial port (Blargg style)
+/// until it prints "Passed" or "Failed", or `max_frames` go by.
+pub fn run_serial_test(cpu: &mut CPU, debugger: &mut Debugger, instr_set: &InstructionSet, max_frames: u32) -> TestResult {
+    let output = cpu.bus.serial.capture();
+    for _ in 0..max_frames {
+        cpu.run_frame(debugger, instr_set);
+        if output.contains("Passed") {
+            return TestResult::Passed(output.text());
+        } else if output.contains("Failed") {
+            return TestResult::Failed(output.text());
+        } else if debugger.quit_requested() {
+            return TestResult::Stopped(output.text());
+        }
+    }
+    TestResult::Timeout(output.text())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_rom;
+
+    // Sends the text through the serial port with the internal clock, then loops forever
+    fn program(text: &str) -> Vec<u8> {
+        let mut program = Vec::new();
+        for byte in text.bytes() {
+            program.extend_from_slice(&[
+                0x3E, byte,         // LD A,byte
+                0xE0, 0x01,         // LDH (SB),A
+                0x3E, 0x81,         // LD A,0x81
+                0xE0, 0x02,         // LDH (SC),A
+                0xF0, 0x02,         // LDH A,(SC)
+                0xCB, 0x7F,         // BIT 7,A
+                0x20, 0xFA,         // JR NZ,-6
+            ]);
+        }
+        program.extend_from_slice(&[0x18, 0xFE]);
+        program
+    }
+
+    #[test]
+    fn the_verdict_comes_from_the_output() {
+        let result = test_rom::gameboy(&program("cpu_instrs\n\nPassed\n")).run_serial_test(60);
+        assert!(result.passed());
+        assert_eq!(result.output(), "cpu_instrs\n\nPassed\n");
+        assert_eq!(result.to_string(), "PASSED\nSerial output:\ncpu_instrs\n\nPassed\n\n");
+
+        let result = test_rom::gameboy(&program("01:ok 02:Failed")).run_serial_test(60);
+        assert!(!result.passed());
+        assert!(result.to_string().starts_with("FAILED\n"));
+    }
+
+    #[test]
+    fn silent_roms_time_out() {
+        let result = test_rom::gameboy(&program("Pass")).run_serial_test(10);
+        assert!(!result.passed());
+        assert_eq!(result.to_string(), "TIMEOUT\nSerial output:\nPass\n");
+    }
+}
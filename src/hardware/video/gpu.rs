@@ -13,9 +13,9 @@ use hardware::interrupts::Interrupts;
 use hardware::interrupts::InterruptType;
 use hardware::video::sprites::SpriteOAM;
 use hardware::video::sprites::Sprite;
+use hardware::state::*;
 
 use std::fmt;
-use rand;
 
 pub struct GPU {
 
@@ -39,7 +39,8 @@ pub struct GPU {
     window_y:       Register<u8>,
     window_x:       Register<u8>,
 
-    debug_color:    [u8; 4]
+    debug_color:    [u8; 4],
+    palette:        [[u8; 4]; 4],
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -79,10 +80,19 @@ impl GPU {
             window_y:       Register::new(0x00),
             window_x:       Register::new(0x00),
 
-            debug_color:    [0, 0, 0, 255]
+            debug_color:    [0, 0, 0, 255],
+            palette:        *PALETTE_IN_USE,
         }
     }
 
+    pub fn palette(&self) -> [[u8; 4]; 4] {
+        self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: [[u8; 4]; 4]) {
+        self.palette = palette;
+    }
+
     pub fn step(&mut self, cycles: u32, screen: &mut Screen, interrupt_handler: &mut Interrupts) {
         // If the display is not enabled, don't render
         //if !self.lcd_control.is_bit_set(B_LCD_DISPLAY_ENABLED) {return}
@@ -193,7 +203,7 @@ impl GPU {
             let tile = self.tile_data.tiles[tile_index as usize];
             let y = self.ly_coord.r();
 
-            let color = PALETTE_IN_USE[self.tile_data.get_pixel(&tile, tile_offset_y as u8, tile_offset_x as u8) as usize];
+            let color = self.palette[self.tile_data.get_pixel(&tile, tile_offset_y as u8, tile_offset_x as u8) as usize];
             
             //println!("Get pixel ({}, {}). Color: {:?} Tile: {:4X}", tile_offset_x, tile_offset_y, color, tile_index);
             screen.set_pixel(x as u8, y, color);
@@ -212,7 +222,7 @@ impl GPU {
                 let tile = self.tile_data.tiles[sprite.data_tile as usize];
                 for pixel in 0..8 {
                     let tile_offset_x = if sprite.flip_x {7 - pixel} else {pixel};
-                    let color = PALETTE_IN_USE[self.tile_data.get_pixel(&tile, tile_offset_y as u8, tile_offset_x as u8) as usize];
+                    let color = self.palette[self.tile_data.get_pixel(&tile, tile_offset_y as u8, tile_offset_x as u8) as usize];
                     screen.set_pixel(start_x + pixel as u8, start_y + line, color);
                 }                
             }
@@ -298,4 +308,54 @@ impl MemoryRegion for GPU {
     fn end(&self) -> u16 {
         panic!("GPU Doesn't have a real 'end()'");
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for GPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.tile_data.save_state(writer);
+        self.tile_maps.save_state(writer);
+        self.sprite_oam.save_state(writer);
+
+        writer.write_u8(self.lcdc_mode as u8);
+        writer.write_u32(self.mode_cycles);
+
+        writer.write_u8(self.lcd_control.r());
+        writer.write_u8(self.lcd_status.r());
+        writer.write_u8(self.scroll_y.r());
+        writer.write_u8(self.scroll_x.r());
+        writer.write_u8(self.ly_coord.r());
+        writer.write_u8(self.lyc_compare.r());
+        writer.write_u8(self.bg_palette.r());
+        writer.write_u8(self.obj_palette_1.r());
+        writer.write_u8(self.obj_palette_2.r());
+        writer.write_u8(self.window_y.r());
+        writer.write_u8(self.window_x.r());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.tile_data.load_state(reader)?;
+        self.tile_maps.load_state(reader)?;
+        self.sprite_oam.load_state(reader)?;
+
+        self.lcdc_mode = match reader.read_u8()? {
+            0b00 => LCDCMode::HBLANK,
+            0b01 => LCDCMode::VBLANK,
+            0b10 => LCDCMode::OAM,
+            _    => LCDCMode::VRAM,
+        };
+        self.mode_cycles = reader.read_u32()?;
+
+        self.lcd_control.w(reader.read_u8()?);
+        self.lcd_status.w(reader.read_u8()?);
+        self.scroll_y.w(reader.read_u8()?);
+        self.scroll_x.w(reader.read_u8()?);
+        self.ly_coord.w(reader.read_u8()?);
+        self.lyc_compare.w(reader.read_u8()?);
+        self.bg_palette.w(reader.read_u8()?);
+        self.obj_palette_1.w(reader.read_u8()?);
+        self.obj_palette_2.w(reader.read_u8()?);
+        self.window_y.w(reader.read_u8()?);
+        self.window_x.w(reader.read_u8()?);
+        Ok(())
+    }
+}
@@ -73,4 +73,22 @@ pub const ORIGINAL_PALETTE: [[u8; 4]; 4] = [
     [ 15, 56, 15, 255],
 ];
 
+pub const GRAYSCALE_PALETTE: [[u8; 4]; 4] = [
+    [255, 255, 255, 255],
+    [170, 170, 170, 255],
+    [ 85,  85,  85, 255],
+    [  0,   0,   0, 255],
+];
+
 pub const PALETTE_IN_USE: &[[u8; 4]; 4] = &ORIGINAL_PALETTE;
+
+pub const PALETTE_NAMES: [&'static str; 3] = ["original", "gray", "pinku"];
+
+pub fn palette_by_name(name: &str) -> Option<[[u8; 4]; 4]> {
+    match name {
+        "original"  => Some(ORIGINAL_PALETTE),
+        "gray"      => Some(GRAYSCALE_PALETTE),
+        "pinku"     => Some(PALETTE_PINKU),
+        _ => None
+    }
+}
@@ -1,60 +1,50 @@
-use piston_window::*;
-use texture::*;
+use std::io;
+use std::path::Path;
 use image;
 
+use hardware::state::*;
+
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 pub const SCREEN_DIMS: [u32; 2] = [SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32];
-const FRAMEBUFFER_SIZE: usize = (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize;
-const SCREEN_SCALE : f64 = 2.0;
+pub const FRAMEBUFFER_SIZE: usize = (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize;
 
+/// RGBA framebuffer the GPU draws into.
+/// It knows nothing about windows, frontends read it through `framebuffer()`.
 pub struct Screen {    
     framebuffer: [u8; FRAMEBUFFER_SIZE],
-    texture_settings: TextureSettings,
     is_on: bool,
-    texture: G2dTexture
 }
 
 impl Screen {
-    pub fn new(window: &mut PistonWindow) -> Self {
-        let buffer = [255; FRAMEBUFFER_SIZE];
-        let ts = TextureSettings::new().filter(Filter::Nearest).compress(false).generate_mipmap(false);
-        let texture = Texture::create(
-            &mut window.factory,
-            Format::Rgba8,
-            &buffer,
-            SCREEN_DIMS,
-            &ts
-        ).unwrap_or_else(|err|{panic!()});
-        
+    pub fn new() -> Self {
         Screen {
-            framebuffer: buffer,
-            texture_settings: ts,
-            texture: texture,
+            framebuffer: [255; FRAMEBUFFER_SIZE],
             is_on: false,
         }
     }
 
-    pub fn update(&mut self, window: &mut PistonWindow, evt: Event) {
-        if !self.is_on {
-            UpdateTexture::update(
-                &mut self.texture,
-                &mut window.encoder, 
-                Format::Rgba8,
-                &self.framebuffer,
-                [0; 2],
-                SCREEN_DIMS
-            ).unwrap();    
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
 
-            window.draw_2d(&evt, |c, g| { 
-                image(&self.texture, c.transform.scale(SCREEN_SCALE, SCREEN_SCALE), g);
-            });
-        } else {
-            window.draw_2d(&evt, |c, g| { 
-                clear([0.0, 0.0, 0.0, 1.0], g);
-            });
-        }
-        
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        self.save_png_scaled(path, 1)
+    }
+
+    /// Saves the frame with every pixel blown up to a `scale` x `scale` square.
+    pub fn save_png_scaled(&self, path: &Path, scale: u32) -> io::Result<()> {
+        self.to_image(scale).save(path)
+    }
+
+    pub fn to_image(&self, scale: u32) -> image::RgbaImage {
+        let scale = scale.max(1);
+        image::ImageBuffer::from_fn(SCREEN_DIMS[0] * scale, SCREEN_DIMS[1] * scale, |x, y| {
+            let first_index = 4 * ((x / scale) as usize + (y / scale) as usize * SCREEN_WIDTH);
+            let mut rgba = [0; 4];
+            rgba.copy_from_slice(&self.framebuffer[first_index..first_index + 4]);
+            image::Rgba { data: rgba }
+        })
     }
 
     pub fn set_pixel(&mut self, x: u8, y: u8, rgba: [u8; 4]) {        
@@ -65,7 +55,48 @@ impl Screen {
         self.framebuffer[first_index + 3]   = rgba[3];
     }
 
+    pub fn is_on(&self) -> bool {
+        self.is_on
+    }
+
     pub fn turn_on_off(&mut self, is_on: bool) {
         self.is_on = is_on;
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Screen {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.framebuffer);
+        writer.write_bool(self.is_on);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.read_into(&mut self.framebuffer)?;
+        self.is_on = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Screen;
+    use super::SCREEN_HEIGHT;
+    use super::SCREEN_WIDTH;
+
+    #[test]
+    fn scaling_turns_pixels_into_squares() {
+        let mut screen = Screen::new();
+        screen.set_pixel(0, 0, [1, 2, 3, 255]);
+        screen.set_pixel(159, 143, [4, 5, 6, 255]);
+        let image = screen.to_image(3);
+        assert_eq!(image.dimensions(), (SCREEN_WIDTH as u32 * 3, SCREEN_HEIGHT as u32 * 3));
+        for &(x, y) in &[(0, 0), (2, 2), (0, 2)] {
+            assert_eq!(image.get_pixel(x, y).data, [1, 2, 3, 255]);
+        }
+        assert_eq!(image.get_pixel(3, 0).data, [255; 4]);
+        assert_eq!(image.get_pixel(479, 431).data, [4, 5, 6, 255]);
+        assert_eq!(image.get_pixel(476, 428).data, [255; 4]);
+        // 0 is taken as 1
+        assert_eq!(screen.to_image(0).dimensions(), (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32));
+    }
+}
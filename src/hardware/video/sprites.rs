@@ -1,6 +1,7 @@
 use hardware::memory::memory_region::MemoryRegion;
 use hardware::registers::Register;
 use hardware::video::screen::*;
+use hardware::state::*;
 
 pub const SPRITE_OAM_START              : u16 = 0xFE00;
 pub const SPRITE_OAM_END                : u16 = 0xFE9F;
@@ -105,3 +106,19 @@ impl MemoryRegion for SpriteOAM {
     }
 }
 
+impl Savestate for SpriteOAM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let data: Vec<u8> = (self.start()..self.end() + 1).map(|addr| self.read_byte(addr)).collect();
+        writer.write_bytes(&data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut data = vec![0u8; (self.end() - self.start()) as usize + 1];
+        reader.read_into(&mut data)?;
+        let start = self.start();
+        for (i, val) in data.iter().enumerate() {
+            self.write_byte(start + i as u16, *val);
+        }
+        Ok(())
+    }
+}
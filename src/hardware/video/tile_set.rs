@@ -2,6 +2,7 @@ use hardware::video::gpu_constants::*;
 use hardware::memory::memory_region::MemoryRegion;
 use std::fmt::Display;
 use hardware::registers::Register;
+use hardware::state::*;
 
 pub type Tile = [[Register<u8>; 2]; 8];
 
@@ -94,3 +95,20 @@ impl MemoryRegion for TileSet {
         TILE_DATA_END
     }
 }
+
+impl Savestate for TileSet {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let data: Vec<u8> = (self.start()..self.end() + 1).map(|addr| self.read_byte(addr)).collect();
+        writer.write_bytes(&data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut data = vec![0u8; (self.end() - self.start()) as usize + 1];
+        reader.read_into(&mut data)?;
+        let start = self.start();
+        for (i, val) in data.iter().enumerate() {
+            self.write_byte(start + i as u16, *val);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+// Macro file format:
+//  [name]
+//  <frames> <buttons...>
+// Each step holds the buttons for that many frames, '-' holds nothing.
+// Lines starting with # are comments.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use hardware::joypad::GbButton;
+use hardware::joypad::ALL_BUTTONS;
+
+/// Frames a turbo button stays pressed, and then released. 2 is 15 presses per second
+pub const DEFAULT_TURBO_RATE : u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MacroStep {
+    pub frames: u32,
+    // Joypad mask, see `GbButton::mask`
+    pub buttons: u8,
+}
+
+/// A named, scripted sequence of joypad states.
+#[derive(Clone, PartialEq, Debug)]
+pub struct InputMacro {
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+}
+
+impl InputMacro {
+    pub fn frames(&self) -> u32 {
+        self.steps.iter().map(|step| step.frames).sum()
+    }
+
+    pub fn load_file(path: &Path) -> Result<Vec<InputMacro>, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("can't read the macros {}: {}", path.display(), e))?;
+        InputMacro::parse(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Vec<InputMacro>, String> {
+        let mut macros: Vec<InputMacro> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            if line.starts_with("[") && line.ends_with("]") {
+                let name = line[1..line.len() - 1].trim();
+                if name.is_empty() || macros.iter().any(|m| m.name == name) {
+                    return Err(format!("line {}: macro names have to be unique and not empty", number + 1));
+                }
+                macros.push(InputMacro { name: String::from(name), steps: Vec::new() });
+                continue;
+            }
+
+            let current = macros.last_mut()
+                .ok_or_else(|| format!("line {}: step outside of a macro, start one with [name]", number + 1))?;
+            let mut words = line.split_whitespace();
+            let frames_word = words.next().unwrap();
+            let frames = frames_word.parse()
+                .map_err(|_| format!("line {}: expected a number of frames, got '{}'", number + 1, frames_word))?;
+            let mut buttons = 0;
+            for word in words {
+                if word == "-" {
+                    continue;
+                }
+                let button = button_by_name(word)
+                    .ok_or_else(|| format!("line {}: unknown button '{}'", number + 1, word))?;
+                buttons |= button.mask();
+            }
+            current.steps.push(MacroStep { frames: frames, buttons: buttons });
+        }
+        Ok(macros)
+    }
+}
+
+pub fn button_by_name(name: &str) -> Option<GbButton> {
+    let button = match &name.to_lowercase()[..] {
+        "a"         => GbButton::A,
+        "b"         => GbButton::B,
+        "start"     => GbButton::Start,
+        "select"    => GbButton::Select,
+        "up"        => GbButton::Up,
+        "down"      => GbButton::Down,
+        "left"      => GbButton::Left,
+        "right"     => GbButton::Right,
+        _           => return None
+    };
+    Some(button)
+}
+
+struct RunningMacro {
+    steps: Vec<MacroStep>,
+    step: usize,
+    frame: u32,
+}
+
+/// Combines what the player holds, turbo buttons and macros into
+/// the joypad state for each frame.
+pub struct InputLayer {
+    held: u8,
+    turbo_held: u8,
+    turbo_rates: [u32; 8],
+    // Frame each turbo button was pressed on, so every press starts pressed
+    turbo_since: [u64; 8],
+    frame: u64,
+    running_macro: Option<RunningMacro>,
+}
+
+impl InputLayer {
+    pub fn new() -> Self {
+        InputLayer {
+            held: 0,
+            turbo_held: 0,
+            turbo_rates: [DEFAULT_TURBO_RATE; 8],
+            turbo_since: [0; 8],
+            frame: 0,
+            running_macro: None,
+        }
+    }
+
+    pub fn set_button(&mut self, button: GbButton, pressed: bool) {
+        set_mask(&mut self.held, button.mask(), pressed);
+    }
+
+    /// Holds the button with autofire: pressed and released every turbo rate frames.
+    pub fn set_turbo(&mut self, button: GbButton, pressed: bool) {
+        if pressed && self.turbo_held & button.mask() == 0 {
+            self.turbo_since[index(button)] = self.frame;
+        }
+        set_mask(&mut self.turbo_held, button.mask(), pressed);
+    }
+
+    pub fn set_turbo_rate(&mut self, button: GbButton, frames: u32) {
+        self.turbo_rates[index(button)] = frames.max(1);
+    }
+
+    /// Starts a macro from its first step, replacing any running one.
+    pub fn play_macro(&mut self, input_macro: &InputMacro) {
+        self.running_macro = Some(RunningMacro {
+            steps: input_macro.steps.clone(),
+            step: 0,
+            frame: 0,
+        });
+    }
+
+    pub fn stop_macro(&mut self) {
+        self.running_macro = None;
+    }
+
+    pub fn is_playing_macro(&self) -> bool {
+        self.running_macro.is_some()
+    }
+
+    /// Joypad mask for the next frame, see `GbButton::mask`.
+    pub fn next_frame(&mut self) -> u8 {
+        let mut buttons = self.held;
+
+        for button in ALL_BUTTONS.iter() {
+            let i = index(*button);
+            let since = self.frame - self.turbo_since[i];
+            if self.turbo_held & button.mask() != 0 && (since / self.turbo_rates[i] as u64) % 2 == 0 {
+                buttons |= button.mask();
+            }
+        }
+
+        let mut macro_ended = false;
+        if let Some(ref mut running) = self.running_macro {
+            // Zero frame steps are skipped
+            while running.step < running.steps.len() && running.frame >= running.steps[running.step].frames {
+                running.step += 1;
+                running.frame = 0;
+            }
+            match running.steps.get(running.step) {
+                Some(step) => {
+                    buttons |= step.buttons;
+                    running.frame += 1;
+                }
+                None => {macro_ended = true;}
+            }
+        }
+        if macro_ended {
+            self.running_macro = None;
+        }
+
+        self.frame += 1;
+        buttons
+    }
+}
+
+fn index(button: GbButton) -> usize {
+    button.mask().trailing_zeros() as usize
+}
+
+fn set_mask(mask: &mut u8, bits: u8, on: bool) {
+    if on {
+        *mask |= bits;
+    } else {
+        *mask &= !bits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InputLayer;
+    use super::InputMacro;
+    use super::MacroStep;
+    use hardware::joypad::GbButton;
+
+    const A : u8 = 1 << 0;
+    const B : u8 = 1 << 1;
+    const RIGHT : u8 = 1 << 4;
+
+    fn frames(input: &mut InputLayer, count: usize) -> Vec<u8> {
+        (0..count).map(|_| input.next_frame()).collect()
+    }
+
+    #[test]
+    fn turbo_presses_every_rate_frames_from_the_press() {
+        let mut input = InputLayer::new();
+        input.next_frame();
+        input.set_turbo(GbButton::A, true);
+        assert_eq!(frames(&mut input, 6), vec![A, A, 0, 0, A, A]);
+
+        input.set_turbo_rate(GbButton::A, 3);
+        input.set_turbo(GbButton::A, false);
+        input.next_frame();
+        // A new press starts pressed, whatever the frame count
+        input.set_turbo(GbButton::A, true);
+        assert_eq!(frames(&mut input, 7), vec![A, A, A, 0, 0, 0, A]);
+        input.set_turbo(GbButton::A, false);
+        assert_eq!(input.next_frame(), 0);
+    }
+
+    #[test]
+    fn held_buttons_cover_turbo_gaps() {
+        let mut input = InputLayer::new();
+        input.set_turbo_rate(GbButton::B, 0);
+        input.set_turbo(GbButton::B, true);
+        input.set_button(GbButton::Right, true);
+        assert_eq!(frames(&mut input, 4), vec![B | RIGHT, RIGHT, B | RIGHT, RIGHT]);
+        input.set_button(GbButton::B, true);
+        assert_eq!(frames(&mut input, 2), vec![B | RIGHT, B | RIGHT]);
+    }
+
+    #[test]
+    fn macros_hold_each_step_for_its_frames() {
+        let macros = InputMacro::parse("# jump and run\n[jump]\n2 a\n0 b\n1 -\n3 a right\n").unwrap();
+        assert_eq!(macros[0].steps[1], MacroStep { frames: 0, buttons: B });
+        assert_eq!(macros[0].frames(), 6);
+
+        let mut input = InputLayer::new();
+        input.set_button(GbButton::B, true);
+        input.play_macro(&macros[0]);
+        assert!(input.is_playing_macro());
+        assert_eq!(frames(&mut input, 7), vec![A | B, A | B, B, A | RIGHT | B, A | RIGHT | B, A | RIGHT | B, B]);
+        assert!(!input.is_playing_macro());
+
+        input.play_macro(&macros[0]);
+        input.next_frame();
+        input.stop_macro();
+        assert_eq!(input.next_frame(), B);
+    }
+
+    #[test]
+    fn bad_macro_files() {
+        let error = |text| InputMacro::parse(text).err().unwrap();
+        assert_eq!(error("2 a"), "line 1: step outside of a macro, start one with [name]");
+        assert_eq!(error("[a]\n[a]"), "line 2: macro names have to be unique and not empty");
+        assert_eq!(error("[]"), "line 1: macro names have to be unique and not empty");
+        assert_eq!(error("[a]\nx a"), "line 2: expected a number of frames, got 'x'");
+        assert_eq!(error("[a]\n1 jump"), "line 2: unknown button 'jump'");
+    }
+}
@@ -0,0 +1,300 @@
+// Key bindings file format, one binding per line:
+//  <action> = <key> [<key> ...]
+// Actions are Game Boy buttons (a, b, start, select, up, down, left, right),
+// the same buttons with autofire (turbo_a, ...), macros (macro:<name>)
+// or emulator hotkeys. Lines starting with # are comments.
+// Actions missing from the file keep their default keys.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use piston_window::Key;
+use rustikgb::hardware::joypad::GbButton;
+use rustikgb::input::InputMacro;
+
+pub const DEFAULT_KEYMAP_PATH: &'static str = "keys.cfg";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hotkey {
+    Rewind,
+    FastForward,
+    Pause,
+    AdvanceFrame,
+    SpeedDown,
+    SpeedUp,
+    SpeedReset,
+    SaveState,
+    LoadState,
+    NextSlot,
+    Screenshot,
+    Video,
+    Debugger,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Button(GbButton),
+    Turbo(GbButton),
+    // Index into the macros the bindings were loaded with
+    Macro(usize),
+    Hotkey(Hotkey),
+}
+
+const MACRO_PREFIX: &'static str = "macro:";
+
+const ACTION_NAMES: &'static [(&'static str, Action)] = &[
+    ("a",               Action::Button(GbButton::A)),
+    ("b",               Action::Button(GbButton::B)),
+    ("start",           Action::Button(GbButton::Start)),
+    ("select",          Action::Button(GbButton::Select)),
+    ("up",              Action::Button(GbButton::Up)),
+    ("down",            Action::Button(GbButton::Down)),
+    ("left",            Action::Button(GbButton::Left)),
+    ("right",           Action::Button(GbButton::Right)),
+    ("turbo_a",         Action::Turbo(GbButton::A)),
+    ("turbo_b",         Action::Turbo(GbButton::B)),
+    ("turbo_start",     Action::Turbo(GbButton::Start)),
+    ("turbo_select",    Action::Turbo(GbButton::Select)),
+    ("turbo_up",        Action::Turbo(GbButton::Up)),
+    ("turbo_down",      Action::Turbo(GbButton::Down)),
+    ("turbo_left",      Action::Turbo(GbButton::Left)),
+    ("turbo_right",     Action::Turbo(GbButton::Right)),
+    ("rewind",          Action::Hotkey(Hotkey::Rewind)),
+    ("fast_forward",    Action::Hotkey(Hotkey::FastForward)),
+    ("pause",           Action::Hotkey(Hotkey::Pause)),
+    ("advance_frame",   Action::Hotkey(Hotkey::AdvanceFrame)),
+    ("speed_down",      Action::Hotkey(Hotkey::SpeedDown)),
+    ("speed_up",        Action::Hotkey(Hotkey::SpeedUp)),
+    ("speed_reset",     Action::Hotkey(Hotkey::SpeedReset)),
+    ("save_state",      Action::Hotkey(Hotkey::SaveState)),
+    ("load_state",      Action::Hotkey(Hotkey::LoadState)),
+    ("next_slot",       Action::Hotkey(Hotkey::NextSlot)),
+    ("screenshot",      Action::Hotkey(Hotkey::Screenshot)),
+    ("video",           Action::Hotkey(Hotkey::Video)),
+    ("debugger",        Action::Hotkey(Hotkey::Debugger)),
+];
+
+const DEFAULT_BINDINGS: &'static [(Key, Action)] = &[
+    (Key::Z,            Action::Button(GbButton::A)),
+    (Key::X,            Action::Button(GbButton::B)),
+    (Key::N,            Action::Button(GbButton::Start)),
+    (Key::M,            Action::Button(GbButton::Select)),
+    (Key::Up,           Action::Button(GbButton::Up)),
+    (Key::Down,         Action::Button(GbButton::Down)),
+    (Key::Left,         Action::Button(GbButton::Left)),
+    (Key::Right,        Action::Button(GbButton::Right)),
+    (Key::A,            Action::Turbo(GbButton::A)),
+    (Key::S,            Action::Turbo(GbButton::B)),
+    (Key::Backspace,    Action::Hotkey(Hotkey::Rewind)),
+    (Key::Tab,          Action::Hotkey(Hotkey::FastForward)),
+    (Key::P,            Action::Hotkey(Hotkey::Pause)),
+    (Key::Period,       Action::Hotkey(Hotkey::AdvanceFrame)),
+    (Key::Minus,        Action::Hotkey(Hotkey::SpeedDown)),
+    (Key::Equals,       Action::Hotkey(Hotkey::SpeedUp)),
+    (Key::D0,           Action::Hotkey(Hotkey::SpeedReset)),
+    (Key::F5,           Action::Hotkey(Hotkey::SaveState)),
+    (Key::F7,           Action::Hotkey(Hotkey::LoadState)),
+    (Key::F6,           Action::Hotkey(Hotkey::NextSlot)),
+    (Key::F12,          Action::Hotkey(Hotkey::Screenshot)),
+    (Key::F9,           Action::Hotkey(Hotkey::Video)),
+    (Key::F10,          Action::Hotkey(Hotkey::Debugger)),
+];
+
+// Names used in the bindings file, as piston names the keys
+const KEY_NAMES: &'static [(&'static str, Key)] = &[
+    ("A", Key::A), ("B", Key::B), ("C", Key::C), ("D", Key::D), ("E", Key::E),
+    ("F", Key::F), ("G", Key::G), ("H", Key::H), ("I", Key::I), ("J", Key::J),
+    ("K", Key::K), ("L", Key::L), ("M", Key::M), ("N", Key::N), ("O", Key::O),
+    ("P", Key::P), ("Q", Key::Q), ("R", Key::R), ("S", Key::S), ("T", Key::T),
+    ("U", Key::U), ("V", Key::V), ("W", Key::W), ("X", Key::X), ("Y", Key::Y),
+    ("Z", Key::Z),
+    ("D0", Key::D0), ("D1", Key::D1), ("D2", Key::D2), ("D3", Key::D3), ("D4", Key::D4),
+    ("D5", Key::D5), ("D6", Key::D6), ("D7", Key::D7), ("D8", Key::D8), ("D9", Key::D9),
+    ("F1", Key::F1), ("F2", Key::F2), ("F3", Key::F3), ("F4", Key::F4),
+    ("F5", Key::F5), ("F6", Key::F6), ("F7", Key::F7), ("F8", Key::F8),
+    ("F9", Key::F9), ("F10", Key::F10), ("F11", Key::F11), ("F12", Key::F12),
+    ("Up", Key::Up), ("Down", Key::Down), ("Left", Key::Left), ("Right", Key::Right),
+    ("Return", Key::Return), ("Space", Key::Space), ("Backspace", Key::Backspace),
+    ("Tab", Key::Tab), ("Escape", Key::Escape),
+    ("Minus", Key::Minus), ("Equals", Key::Equals), ("Period", Key::Period), ("Comma", Key::Comma),
+    ("LShift", Key::LShift), ("RShift", Key::RShift), ("LCtrl", Key::LCtrl), ("RCtrl", Key::RCtrl),
+    ("LAlt", Key::LAlt), ("RAlt", Key::RAlt),
+    ("Slash", Key::Slash),
+];
+
+/// Which keys trigger which actions. A key can trigger several actions
+/// and an action can be bound to several keys.
+pub struct Keymap {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            bindings: DEFAULT_BINDINGS.to_vec(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Macros can be bound by name, as `macro:<name>`.
+    pub fn load(path: &Path, macros: &[InputMacro]) -> Result<Self, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("can't read the key bindings {}: {}", path.display(), e))?;
+        Keymap::parse(&text, macros)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str, macros: &[InputMacro]) -> Result<Self, String> {
+        let mut keymap = Keymap::default();
+        let mut configured = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let keys = parts.next()
+                .ok_or_else(|| format!("line {}: expected '<action> = <keys>'", number + 1))?;
+            let action = action_by_name(name, macros)
+                .ok_or_else(|| format!("line {}: unknown action '{}'", number + 1, name))?;
+
+            keymap.bindings.retain(|binding| binding.1 != action);
+            configured.push(action);
+            for key_name in keys.split_whitespace() {
+                let key = key_by_name(key_name)
+                    .ok_or_else(|| format!("line {}: unknown key '{}'", number + 1, key_name))?;
+                // A key taken from a default binding loses that binding
+                keymap.bindings.retain(|binding| binding.0 != key || configured.contains(&binding.1));
+                keymap.bindings.push((key, action));
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn actions(&self, key: Key) -> Vec<Action> {
+        self.bindings.iter()
+            .filter(|binding| binding.0 == key)
+            .map(|binding| binding.1)
+            .collect()
+    }
+
+    /// Whether any of the keys bound to the action is down.
+    pub fn is_held(&self, action: Action, held_keys: &HashSet<Key>) -> bool {
+        self.bindings.iter()
+            .any(|binding| binding.1 == action && held_keys.contains(&binding.0))
+    }
+}
+
+fn action_by_name(name: &str, macros: &[InputMacro]) -> Option<Action> {
+    if name.starts_with(MACRO_PREFIX) {
+        let macro_name = &name[MACRO_PREFIX.len()..];
+        return macros.iter()
+            .position(|m| m.name == macro_name)
+            .map(Action::Macro);
+    }
+    ACTION_NAMES.iter()
+        .find(|entry| entry.0.eq_ignore_ascii_case(name))
+        .map(|entry| entry.1)
+}
+
+fn key_by_name(name: &str) -> Option<Key> {
+    KEY_NAMES.iter()
+        .find(|entry| entry.0.eq_ignore_ascii_case(name))
+        .map(|entry| entry.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use piston_window::Key;
+    use rustikgb::hardware::joypad::GbButton;
+    use rustikgb::input::InputMacro;
+    use super::action_by_name;
+    use super::key_by_name;
+    use super::Action;
+    use super::Hotkey;
+    use super::Keymap;
+    use super::ACTION_NAMES;
+    use super::KEY_NAMES;
+
+    fn keys(keymap: &Keymap, action: Action) -> Vec<Key> {
+        keymap.bindings.iter().filter(|binding| binding.1 == action).map(|binding| binding.0).collect()
+    }
+
+    #[test]
+    fn names_ignore_case() {
+        assert_eq!(key_by_name("space"), Some(Key::Space));
+        assert_eq!(key_by_name("F10"), Some(Key::F10));
+        assert_eq!(key_by_name("d1"), Some(Key::D1));
+        assert_eq!(key_by_name("Hyper"), None);
+        assert_eq!(action_by_name("Turbo_A", &[]), Some(Action::Turbo(GbButton::A)));
+        assert_eq!(action_by_name("FAST_FORWARD", &[]), Some(Action::Hotkey(Hotkey::FastForward)));
+        assert_eq!(action_by_name("jump", &[]), None);
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let mut actions: Vec<String> = ACTION_NAMES.iter().map(|entry| entry.0.to_lowercase()).collect();
+        let mut keys: Vec<String> = KEY_NAMES.iter().map(|entry| entry.0.to_lowercase()).collect();
+        let (action_count, key_count) = (actions.len(), keys.len());
+        actions.sort();
+        actions.dedup();
+        keys.sort();
+        keys.dedup();
+        assert_eq!((actions.len(), keys.len()), (action_count, key_count));
+    }
+
+    #[test]
+    fn macros_are_bound_by_name() {
+        let macros = InputMacro::parse("[jump]\n2 a\n[dash]\n10 b right\n").unwrap();
+        assert_eq!(action_by_name("macro:dash", &macros), Some(Action::Macro(1)));
+        assert_eq!(action_by_name("macro:fly", &macros), None);
+        let keymap = Keymap::parse("macro:jump = J", &macros).unwrap();
+        assert_eq!(keymap.actions(Key::J), vec![Action::Macro(0)]);
+    }
+
+    #[test]
+    fn lines_replace_the_default_keys() {
+        let keymap = Keymap::parse("# comment\n\na = Space K\n  start=Return  \n", &[]).unwrap();
+        assert_eq!(keys(&keymap, Action::Button(GbButton::A)), vec![Key::Space, Key::K]);
+        assert_eq!(keys(&keymap, Action::Button(GbButton::Start)), vec![Key::Return]);
+        // Actions left out keep their default
+        assert_eq!(keys(&keymap, Action::Button(GbButton::B)), vec![Key::X]);
+        assert!(keymap.actions(Key::Z).is_empty());
+        // An action can be unbound
+        let keymap = Keymap::parse("pause =", &[]).unwrap();
+        assert!(keys(&keymap, Action::Hotkey(Hotkey::Pause)).is_empty());
+    }
+
+    #[test]
+    fn configured_keys_take_over_default_ones() {
+        // Z is A by default, and B takes it
+        let keymap = Keymap::parse("b = Z", &[]).unwrap();
+        assert_eq!(keymap.actions(Key::Z), vec![Action::Button(GbButton::B)]);
+        assert!(keys(&keymap, Action::Button(GbButton::A)).is_empty());
+        // Keys the file binds twice trigger both actions
+        let keymap = Keymap::parse("a = Space\nb = Space", &[]).unwrap();
+        assert_eq!(keymap.actions(Key::Space), vec![Action::Button(GbButton::A), Action::Button(GbButton::B)]);
+
+        let mut held = HashSet::new();
+        held.insert(Key::Space);
+        assert!(keymap.is_held(Action::Button(GbButton::B), &held));
+        assert!(!keymap.is_held(Action::Button(GbButton::Start), &held));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |text| Keymap::parse(text, &[]).err().unwrap();
+        assert_eq!(error("a = Z\nb X"), "line 2: expected '<action> = <keys>'");
+        assert_eq!(error("jump = Space"), "line 1: unknown action 'jump'");
+        assert_eq!(error("\na = Hyper"), "line 2: unknown key 'Hyper'");
+    }
+}
@@ -0,0 +1,15 @@
+extern crate image;
+extern crate gif;
+
+pub mod hardware;
+pub mod gameboy;
+pub mod rewind;
+pub mod movie;
+pub mod scheduler;
+pub mod input;
+pub mod recorder;
+
+#[cfg(test)]
+mod test_rom;
+
+pub use gameboy::GameBoy;
@@ -1,70 +1,404 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::path::PathBuf;
+use std::env;
+use std::process;
+use std::time::Instant;
 
-mod hardware;
+mod cli;
+mod display;
+mod keymap;
 
 use piston_window::*;
+use keymap::{Action, Hotkey, Keymap};
+use rustikgb::GameBoy;
+use rustikgb::gameboy::screenshot_path;
+use rustikgb::gameboy::video_path;
+use rustikgb::gameboy::slot_path;
+use rustikgb::hardware;
+use rustikgb::hardware::joypad::ALL_BUTTONS;
+use rustikgb::input::InputMacro;
+use rustikgb::recorder::RecorderConfig;
+use rustikgb::movie::Movie;
+use rustikgb::rewind::RewindConfig;
+use rustikgb::scheduler::FrameScheduler;
 
-#[macro_use] extern crate text_io;
 extern crate piston_window;
-extern crate image;
-extern crate rand;
+extern crate rustikgb;
 
 fn main() {
-    let mut debugger = hardware::debugger::Debugger::new();
-    let instructions = hardware::instructions::InstructionSet::new();
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    // Exiting only once run() returned lets the machine flush its traces and recordings
+    match run(options) {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
 
-    let boot_buf = read_bin("assets/BIOS.gb");
-    let rom_buf = read_bin("assets/Tetris (World).gb");
-    let cartridge = hardware::cartridge::Cartridge::new(&rom_buf);    
-    println!("Game data\n==========\n{}", &cartridge);
+// Returns the exit code of the process
+fn run(options: cli::Options) -> Result<i32, String> {
+    let boot_buf = load_bios(&options)?;
+    let rom_buf = read_bin(&options.rom)?;
+    let mut gameboy = GameBoy::new(boot_buf, &rom_buf)
+        .map_err(|e| format!("can't load {}: {}", options.rom.display(), e))?;
+    println!("Game data\n==========\n{}", gameboy.cartridge());
 
-    let mut window = init_window();
+    if let Some(palette) = options.palette {
+        gameboy.set_palette(palette);
+    }
+    gameboy.set_block_opposing_directions(options.block_opposing);
+    if let Some(rate) = options.turbo_rate {
+        for button in ALL_BUTTONS.iter() {
+            gameboy.set_turbo_rate(*button, rate);
+        }
+    }
+    load_symbols(&mut gameboy, &options.rom);
+    if options.debug {
+        gameboy.enter_debug_mode();
+    }
+    if let Some(ref path) = options.trace {
+        gameboy.start_trace(path, options.trace_last.map(|length| length as usize))?;
+    }
+    if let Some(port) = options.gdb_port {
+        let addr = format!("127.0.0.1:{}", port);
+        println!("Waiting for GDB on {}", addr);
+        gameboy.listen_gdb(&addr)?;
+    }
+
+    if options.serial_test {
+        // ~2 minutes of emulated time is enough for any of Blargg's ROMs
+        let result = gameboy.run_serial_test(options.frames.unwrap_or(7200));
+        println!("{}", result);
+        return Ok(if result.passed() {0} else {1});
+    }
+    if let Some(device) = serial_device(&options)? {
+        gameboy.connect_serial(device);
+    }
+
+    if let Some(ref path) = options.play {
+        gameboy.play_movie(Movie::load(path)?)
+            .map_err(|e| format!("can't play {}: {}", path.display(), e))?;
+    }
+    if options.record.is_some() {
+        gameboy.start_recording(true);
+    }
+    if let Some(ref path) = options.video {
+        gameboy.start_video(path, video_config(&options))?;
+    }
+
+    if options.headless {
+        run_headless(&mut gameboy, &options)?;
+        finish_recordings(&mut gameboy, &options)?;
+        return Ok(0);
+    }
 
-    let bus = hardware::memory::bus::BUS::new(&mut window, boot_buf, cartridge);
-    let mut processor : hardware::cpu::CPU = hardware::cpu::CPU::new(bus);
+    let macros = match options.macros {
+        Some(ref path) => InputMacro::load_file(path)?,
+        None => Vec::new()
+    };
+    let keymap = load_keymap(&options, &macros)?;
+    let mut window = init_window(options.scale)?;
+    let mut display = display::Display::new(&mut window, gameboy.screen(), options.scale);
+
+    if options.rewind_seconds > 0 {
+        gameboy.enable_rewind(RewindConfig {
+            max_frames: options.rewind_seconds as usize * 60,
+            ..RewindConfig::default()
+        });
+    }
+    let mut controls = Controls {
+        keymap: keymap,
+        macros: macros,
+        held_keys: HashSet::new(),
+        scheduler: FrameScheduler::new(),
+        rewinding: false,
+        slot: 0,
+        rom: options.rom.clone(),
+        screenshot_scale: options.screenshot_scale,
+        video_config: video_config(&options),
+    };
 
     while let Some(e) = window.next() {
         if let Some(Button::Keyboard(key)) = e.press_args() {
-            if key == Key::F10 {
-                debugger.enter_debug_mode();
-            } else {
-                processor.bus.joypad.process_press(key);
+            controls.key_event(&mut gameboy, key, true);
+        }
+        if let Some(Button::Keyboard(key)) = e.release_args() {
+            controls.key_event(&mut gameboy, key, false);
+        }
+
+        // Updates only poll the scheduler, it decides how many frames are due
+        if e.update_args().is_some() {
+            for _ in 0..controls.scheduler.tick(Instant::now()) {
+                // Rewinds one frame per frame while the rewind key is held,
+                // and stays on the oldest one when the history runs out
+                if controls.rewinding {
+                    gameboy.rewind_frame();
+                } else {
+                    gameboy.run_frame();
+                }
+            }
+        }
+
+        if e.render_args().is_some() {
+            display.update(&mut window, e, gameboy.screen());
+        }
+        if gameboy.quit_requested() {
+            break;
+        }
+    }
+    finish_recordings(&mut gameboy, &options)?;
+    Ok(0)
+}
+
+const SAVE_SLOTS : u8 = 10;
+
+// Everything the windowed frontend tracks besides the machine itself
+struct Controls {
+    keymap: Keymap,
+    macros: Vec<InputMacro>,
+    held_keys: HashSet<Key>,
+    scheduler: FrameScheduler,
+    rewinding: bool,
+    slot: u8,
+    rom: PathBuf,
+    screenshot_scale: u32,
+    video_config: RecorderConfig,
+}
+
+impl Controls {
+    fn key_event(&mut self, gameboy: &mut GameBoy, key: Key, pressed: bool) {
+        if pressed {
+            self.held_keys.insert(key);
+        } else {
+            self.held_keys.remove(&key);
+        }
+
+        for action in self.keymap.actions(key) {
+            match action {
+                Action::Button(button) => {
+                    // Movies drive the joypad on their own
+                    if !gameboy.is_playing_movie() {
+                        let held = self.keymap.is_held(action, &self.held_keys);
+                        gameboy.set_button(button, held);
+                    }
+                }
+                Action::Turbo(button) => {
+                    if !gameboy.is_playing_movie() {
+                        let held = self.keymap.is_held(action, &self.held_keys);
+                        gameboy.set_turbo(button, held);
+                    }
+                }
+                Action::Macro(index) => {
+                    if pressed && !gameboy.is_playing_movie() {
+                        println!("Playing macro {}", self.macros[index].name);
+                        gameboy.play_macro(&self.macros[index]);
+                    }
+                }
+                Action::Hotkey(hotkey) => {
+                    if let Err(e) = self.hotkey(gameboy, hotkey, pressed) {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn hotkey(&mut self, gameboy: &mut GameBoy, hotkey: Hotkey, pressed: bool) -> Result<(), String> {
+        // Only these act while the key is held, the rest fire once per press
+        match hotkey {
+            Hotkey::Rewind      => {self.rewinding = pressed; return Ok(());}
+            Hotkey::FastForward => {self.scheduler.set_fast_forward(pressed); return Ok(());}
+            _ => {}
+        }
+        if !pressed {
+            return Ok(());
+        }
+
+        match hotkey {
+            Hotkey::Pause           => {self.scheduler.toggle_pause();}
+            Hotkey::AdvanceFrame    => {self.scheduler.advance_frame();}
+            Hotkey::SpeedDown | Hotkey::SpeedUp | Hotkey::SpeedReset => {
+                let speed = match hotkey {
+                    Hotkey::SpeedDown   => self.scheduler.speed() / 2.0,
+                    Hotkey::SpeedUp     => self.scheduler.speed() * 2.0,
+                    _                   => 1.0
+                };
+                self.scheduler.set_speed(speed);
+                println!("Speed: x{}", self.scheduler.speed());
+            }
+            Hotkey::SaveState => {
+                let path = slot_path(&self.rom, self.slot);
+                gameboy.save_state_file(&path)?;
+                println!("Saved state to {}", path.display());
+            }
+            Hotkey::LoadState => {
+                let path = slot_path(&self.rom, self.slot);
+                gameboy.load_state_file(&path)?;
+                println!("Loaded state from {}", path.display());
+            }
+            Hotkey::NextSlot => {
+                self.slot = (self.slot + 1) % SAVE_SLOTS;
+                println!("Save slot {}", self.slot);
+            }
+            Hotkey::Screenshot => {
+                let path = screenshot_path(&self.rom);
+                gameboy.save_screenshot(&path, self.screenshot_scale)?;
+                println!("Screenshot saved to {}", path.display());
+            }
+            Hotkey::Video => {
+                if gameboy.is_recording_video() {
+                    let frames = gameboy.stop_video()?;
+                    println!("Saved a video with {} frames", frames);
+                } else {
+                    let path = video_path(&self.rom);
+                    gameboy.start_video(&path, self.video_config)?;
+                    println!("Recording video to {}", path.display());
+                }
             }
+            Hotkey::Debugger => {gameboy.enter_debug_mode();}
+            Hotkey::Rewind | Hotkey::FastForward => {}
         }
+        Ok(())
+    }
+}
 
-        if let Some(Button::Keyboard(key)) = e.release_args() {            
-            processor.bus.joypad.process_release(key);
-            
+fn load_keymap(options: &cli::Options, macros: &[InputMacro]) -> Result<Keymap, String> {
+    match options.keymap {
+        Some(ref path) => Keymap::load(path, macros),
+        None => {
+            let path = Path::new(keymap::DEFAULT_KEYMAP_PATH);
+            if path.exists() {Keymap::load(path, macros)} else {Ok(Keymap::default())}
         }
+    }
+}
 
-        if let Some(args) = e.render_args() {
-            // TODO: Move out of the render event       
-            processor.run_frame(&mut debugger, &instructions);
-            processor.bus.screen.update(&mut window, e);
+// RGBDS writes "game.sym" or "game.map" next to "game.gb"
+fn load_symbols(gameboy: &mut GameBoy, rom: &Path) {
+    let path = ["sym", "map"].iter()
+        .map(|extension| rom.with_extension(extension))
+        .find(|path| path.is_file());
+    if let Some(path) = path {
+        // The game runs fine without labels
+        match gameboy.load_symbols(&path) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, path.display()),
+            Err(e) => eprintln!("Error: {}", e)
         }
     }
 }
 
+fn run_headless(gameboy: &mut GameBoy, options: &cli::Options) -> Result<(), String> {
+    // Without --frames a movie plays until its last frame
+    let mut frames = 0;
+    while options.frames.map_or(gameboy.is_playing_movie(), |limit| frames < limit) && !gameboy.quit_requested() {
+        gameboy.run_frame();
+        frames += 1;
+    }
+    println!("Ran {} frames", frames);
+
+    if let Some(ref path) = options.screenshot {
+        gameboy.save_screenshot(path, options.screenshot_scale)?;
+        println!("Screenshot saved to {}", path.display());
+    }
+    Ok(())
+}
 
-fn read_bin(path: &'static str) -> Box<[u8]> {
-    let path = Path::new(path);
-    let mut file = File::open(path).unwrap();
+fn finish_recordings(gameboy: &mut GameBoy, options: &cli::Options) -> Result<(), String> {
+    if let (Some(ref path), Some(movie)) = (options.record.as_ref(), gameboy.stop_recording()) {
+        movie.save(path)?;
+        println!("Recorded {} frames to {}", movie.len(), path.display());
+    }
+    if gameboy.is_recording_video() {
+        let frames = gameboy.stop_video()?;
+        println!("Saved a video with {} frames", frames);
+    }
+    Ok(())
+}
+
+fn video_config(options: &cli::Options) -> RecorderConfig {
+    RecorderConfig {
+        frame_step: options.video_step,
+        deduplicate: !options.video_keep_duplicates,
+    }
+}
+
+fn load_bios(options: &cli::Options) -> Result<Option<Box<[u8]>>, String> {
+    if options.no_bios {
+        return Ok(None);
+    }
+    match options.bios {
+        Some(ref path) => read_bin(path).map(Some),
+        None => {
+            let path = Path::new(cli::DEFAULT_BIOS_PATH);
+            if path.exists() {
+                read_bin(path).map(Some)
+            } else {
+                println!("No boot ROM found at {}, starting at the cartridge entry point", path.display());
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn read_bin(path: &Path) -> Result<Box<[u8]>, String> {
+    let mut file = File::open(path)
+        .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
     let mut file_buf = Vec::new();
-    file.read_to_end(&mut file_buf).unwrap();
-    file_buf.into_boxed_slice()
+    file.read_to_end(&mut file_buf)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    Ok(file_buf.into_boxed_slice())
+}
+
+fn serial_device(options: &cli::Options) -> Result<Option<Box<hardware::serial::SerialDevice>>, String> {
+    use hardware::serial::link_cable::LinkCable;
+    use hardware::serial::printer::{Printer, PrinterConfig};
+
+    if let Some(ref addr) = options.link_host {
+        println!("Waiting for the other player on {}", addr);
+        let cable = LinkCable::host(addr)
+            .map_err(|e| format!("can't host a link cable on {}: {}", addr, e))?;
+        return Ok(Some(Box::new(cable)));
+    }
+    if let Some(ref addr) = options.link_join {
+        let cable = LinkCable::join(addr)
+            .map_err(|e| format!("can't join the link cable on {}: {}", addr, e))?;
+        return Ok(Some(Box::new(cable)));
+    }
+    if let Some(ref dir) = options.printer {
+        if !dir.is_dir() {
+            return Err(format!("the printer output directory {} doesn't exist", dir.display()));
+        }
+        return Ok(Some(Box::new(Printer::new(PrinterConfig::new(dir.clone())))));
+    }
+    Ok(None)
 }
 
-fn init_window() -> PistonWindow {
-    let mut window: PistonWindow = WindowSettings::new("RustikGB", 
-        (hardware::video::screen::SCREEN_DIMS[0] * 2, 
-         hardware::video::screen::SCREEN_DIMS[1] * 2))
+fn init_window(scale: u32) -> Result<PistonWindow, String> {
+    let mut window: PistonWindow = WindowSettings::new("RustikGB",
+        (hardware::video::screen::SCREEN_DIMS[0] * scale,
+         hardware::video::screen::SCREEN_DIMS[1] * scale))
         .resizable(false)
         .exit_on_esc(true)
         .build()
-        .unwrap_or_else(|e| { panic!("Failed to build PistonWindow: {}", e) });
-    window.set_ups(60);
-    window
+        .map_err(|e| format!("failed to build the window: {}", e))?;
+    // Polls the frame scheduler often enough to pace at any speed
+    window.set_ups(240);
+    window.set_max_fps(60);
+    Ok(window)
 }
-
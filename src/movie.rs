@@ -0,0 +1,153 @@
+// Movie format:
+//  "RGBM" magic, u16 version, u16 global checksum of the ROM,
+//  u8 start (0 power on, 1 save state) followed by the state block if there is one,
+//  then a block with one joypad mask per frame.
+// Values are little endian, blocks are length-prefixed, as in save states.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use hardware::state::StateReader;
+use hardware::state::StateWriter;
+
+pub const MOVIE_MAGIC   : &'static [u8; 4] = b"RGBM";
+pub const MOVIE_VERSION : u16 = 1;
+
+const START_POWER_ON    : u8 = 0;
+const START_SAVE_STATE  : u8 = 1;
+
+#[derive(Clone)]
+pub enum MovieStart {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+/// Joypad input for every frame since a known starting point.
+/// Replaying it on the same ROM reproduces the run exactly.
+#[derive(Clone)]
+pub struct Movie {
+    pub rom_checksum: u16,
+    pub start: MovieStart,
+    // Joypad masks, see `GbButton::mask`
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(rom_checksum: u16, start: MovieStart) -> Self {
+        Movie {
+            rom_checksum: rom_checksum,
+            start: start,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for byte in MOVIE_MAGIC {
+            writer.write_u8(*byte);
+        }
+        writer.write_u16(MOVIE_VERSION);
+        writer.write_u16(self.rom_checksum);
+        match self.start {
+            MovieStart::PowerOn => {writer.write_u8(START_POWER_ON);}
+            MovieStart::SaveState(ref state) => {
+                writer.write_u8(START_SAVE_STATE);
+                writer.write_bytes(state);
+            }
+        }
+        writer.write_bytes(&self.frames);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let mut reader = StateReader::new(data);
+        for byte in MOVIE_MAGIC {
+            if reader.read_u8()? != *byte {
+                return Err(String::from("not a RustikGB movie"));
+            }
+        }
+        let version = reader.read_u16()?;
+        if version != MOVIE_VERSION {
+            return Err(format!("the movie has version {}, but only version {} is supported", version, MOVIE_VERSION));
+        }
+        let rom_checksum = reader.read_u16()?;
+        let start = match reader.read_u8()? {
+            START_POWER_ON => MovieStart::PowerOn,
+            START_SAVE_STATE => MovieStart::SaveState(reader.read_bytes()?.to_vec()),
+            other => return Err(format!("unknown movie start {}", other))
+        };
+        let frames = reader.read_bytes()?.to_vec();
+        Ok(Movie {
+            rom_checksum: rom_checksum,
+            start: start,
+            frames: frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        File::create(path)
+            .and_then(|mut file| file.write_all(&self.to_bytes()))
+            .map_err(|e| format!("can't write the movie {}: {}", path.display(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("can't read the movie {}: {}", path.display(), e))?;
+        Movie::from_bytes(&data)
+            .map_err(|e| format!("can't load the movie {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Movie;
+    use super::MovieStart;
+
+    fn movie(start: MovieStart) -> Movie {
+        let mut movie = Movie::new(0xBEEF, start);
+        movie.frames = vec![0x00, 0x01, 0x81, 0xFF];
+        movie
+    }
+
+    #[test]
+    fn movies_survive_the_round_trip() {
+        for start in vec![MovieStart::PowerOn, MovieStart::SaveState(vec![1, 2, 3])] {
+            let original = movie(start);
+            let bytes = original.to_bytes();
+            assert_eq!(&bytes[..4], b"RGBM");
+            let loaded = Movie::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.rom_checksum, 0xBEEF);
+            assert_eq!(loaded.frames, original.frames);
+            match (loaded.start, original.start) {
+                (MovieStart::PowerOn, MovieStart::PowerOn) => {}
+                (MovieStart::SaveState(ref a), MovieStart::SaveState(ref b)) => assert_eq!(a, b),
+                _ => panic!("the start changed")
+            }
+        }
+    }
+
+    #[test]
+    fn broken_movies_are_rejected() {
+        let bytes = movie(MovieStart::PowerOn).to_bytes();
+        let error = |bytes: &[u8]| Movie::from_bytes(bytes).err().expect("the movie was accepted");
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(error(&magic), "not a RustikGB movie");
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(error(&version), "the movie has version 9, but only version 1 is supported");
+        let mut start = bytes.clone();
+        start[8] = 7;
+        assert_eq!(error(&start), "unknown movie start 7");
+        error(&bytes[..bytes.len() - 1]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use gif;
+use gif::SetParameter;
+
+use hardware::video::screen::SCREEN_DIMS;
+use scheduler::frame_rate;
+
+// GIF delays are in hundredths of a second and most players show anything
+// shorter than 2 as 10, so no frame is kept on screen for less than this
+const MIN_DELAY : u64 = 2;
+
+#[derive(Clone, Copy)]
+pub struct RecorderConfig {
+    /// Only every Nth emulated frame is captured
+    pub frame_step: u32,
+    /// Consecutive identical frames are stored once, with a longer delay
+    pub deduplicate: bool,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            frame_step: 1,
+            deduplicate: true,
+        }
+    }
+}
+
+/// Writes emulated frames to an animated GIF, timed like the real hardware.
+/// The file is complete once the recorder is finished or dropped.
+pub struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    config: RecorderConfig,
+    // Emulated frames seen since the recording started
+    frame: u64,
+    // Last captured frame and the emulated frame it went on screen.
+    // It is written once we know how long it stays there.
+    pending: Option<(Vec<u8>, u64)>,
+    frames_written: u32,
+}
+
+impl GifRecorder {
+    pub fn create(path: &Path, config: RecorderConfig) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+        // Every frame brings its own palette
+        let encoder = gif::Encoder::new(BufWriter::new(file), SCREEN_DIMS[0] as u16, SCREEN_DIMS[1] as u16, &[])
+            .and_then(|mut encoder| encoder.set(gif::Repeat::Infinite).map(|_| encoder))
+            .map_err(|e| format!("can't write to {}: {}", path.display(), e))?;
+        Ok(GifRecorder {
+            encoder: encoder,
+            config: config,
+            frame: 0,
+            pending: None,
+            frames_written: 0,
+        })
+    }
+
+    /// Takes the RGBA framebuffer of the frame that was just emulated.
+    pub fn add_frame(&mut self, framebuffer: &[u8]) -> Result<(), String> {
+        let frame = self.frame;
+        self.frame += 1;
+        if frame % self.config.frame_step.max(1) as u64 != 0 {
+            return Ok(());
+        }
+
+        let mut write = None;
+        if let Some((ref mut pixels, start)) = self.pending {
+            if self.config.deduplicate && &pixels[..] == framebuffer {
+                return Ok(());
+            }
+            if centis(frame) - centis(start) < MIN_DELAY {
+                // Too soon for the previous frame to be seen, this one replaces it
+                pixels.copy_from_slice(framebuffer);
+                return Ok(());
+            }
+            write = Some(centis(frame) - centis(start));
+        }
+        if let Some(delay) = write {
+            self.write_pending(delay)?;
+        }
+        self.pending = Some((framebuffer.to_vec(), frame));
+        Ok(())
+    }
+
+    /// Writes the last frame and returns how many frames the GIF has.
+    pub fn finish(mut self) -> Result<u32, String> {
+        let delay = match self.pending {
+            Some((_, start)) => (centis(self.frame) - centis(start)).max(MIN_DELAY),
+            None => return Ok(0)
+        };
+        self.write_pending(delay)?;
+        Ok(self.frames_written)
+    }
+
+    fn write_pending(&mut self, delay: u64) -> Result<(), String> {
+        let pixels = match self.pending.take() {
+            Some((pixels, _)) => pixels,
+            None => return Ok(())
+        };
+        let (palette, indices) = to_indexed(&pixels);
+        let mut frame = gif::Frame::default();
+        frame.width = SCREEN_DIMS[0] as u16;
+        frame.height = SCREEN_DIMS[1] as u16;
+        frame.delay = delay.min(u16::max_value() as u64) as u16;
+        frame.palette = Some(palette);
+        frame.buffer = indices.into();
+        self.encoder.write_frame(&frame)
+            .map_err(|e| format!("can't write the GIF frame: {}", e))?;
+        self.frames_written += 1;
+        Ok(())
+    }
+}
+
+// Time a frame goes on screen, in hundredths of a second since the recording started
+fn centis(frame: u64) -> u64 {
+    (frame as f64 * 100.0 / frame_rate()).round() as u64
+}
+
+// The DMG only ever shows 4 colors, so a palette built from the frame itself always fits
+fn to_indexed(rgba: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut colors: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+    for pixel in rgba.chunks(4) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        let index = match colors.iter().position(|c| *c == color) {
+            Some(index) => index,
+            None if colors.len() < 256 => {
+                colors.push(color);
+                colors.len() - 1
+            }
+            None => 0
+        };
+        indices.push(index as u8);
+    }
+    let palette = colors.iter().flat_map(|c| c.iter().cloned()).collect();
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::process;
+
+    use gif;
+    use super::centis;
+    use super::to_indexed;
+    use super::GifRecorder;
+    use super::RecorderConfig;
+    use hardware::video::screen::FRAMEBUFFER_SIZE;
+
+    fn frame(shade: u8) -> Vec<u8> {
+        vec![shade; FRAMEBUFFER_SIZE]
+    }
+
+    // Records frames of the given shades and returns the delays of the GIF frames
+    fn record(name: &str, config: RecorderConfig, shades: &[u8]) -> Vec<u16> {
+        let path: PathBuf = env::temp_dir().join(format!("rustikgb-{}-{}.gif", name, process::id()));
+        let mut recorder = GifRecorder::create(&path, config).unwrap();
+        for shade in shades {
+            recorder.add_frame(&frame(*shade)).unwrap();
+        }
+        let written = recorder.finish().unwrap();
+
+        let mut reader = gif::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(delays.len(), written as usize);
+        delays
+    }
+
+    #[test]
+    fn frames_are_placed_on_the_hardware_clock() {
+        assert_eq!(centis(0), 0);
+        assert_eq!(centis(1), 2);
+        assert_eq!(centis(60), 100);
+        // 59.73 frames per second drift away from 60
+        assert_eq!(centis(3600), 6027);
+    }
+
+    #[test]
+    fn delays_add_up_to_the_recorded_time() {
+        let shades: Vec<u8> = (0..60).map(|frame| frame as u8).collect();
+        let delays = record("every", RecorderConfig { frame_step: 1, deduplicate: true }, &shades);
+        // Frames shorter than 2 hundredths are merged into the next one
+        assert!(delays.iter().all(|delay| *delay >= 2));
+        // The last frame has nothing after it to merge into, so it may be stretched to 2
+        let total = delays.iter().map(|delay| *delay as u64).sum::<u64>();
+        assert!(total == centis(60) || total == centis(60) + 1, "{}", total);
+        assert!(delays.len() < 60);
+    }
+
+    #[test]
+    fn repeated_frames_lengthen_the_previous_one() {
+        let shades = [0, 0, 0, 0, 0, 0, 9, 9, 9, 9];
+        assert_eq!(record("dedup", RecorderConfig { frame_step: 1, deduplicate: true }, &shades), vec![10, 7]);
+        assert_eq!(record("keep", RecorderConfig { frame_step: 1, deduplicate: false }, &shades).len(), 7);
+        // With a step only every other frame is looked at
+        let shades = [0, 1, 2, 3, 4, 5];
+        assert_eq!(record("step", RecorderConfig { frame_step: 2, deduplicate: true }, &shades), vec![3, 4, 3]);
+    }
+
+    #[test]
+    fn palettes_come_from_the_frame() {
+        let pixels = [255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255, 8, 8, 8, 255];
+        let (palette, indices) = to_indexed(&pixels);
+        assert_eq!(palette, vec![255, 255, 255, 0, 0, 0, 8, 8, 8]);
+        assert_eq!(indices, vec![0, 1, 0, 2]);
+    }
+}
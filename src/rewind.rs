@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+
+pub struct RewindConfig {
+    /// How many frames back we can go. 3600 frames are about a minute
+    pub max_frames: usize,
+    /// Upper bound for the memory used by the history, in bytes
+    pub memory_budget: usize,
+    /// Every this many frames a full snapshot is stored, the rest are deltas against it
+    pub keyframe_interval: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig {
+            max_frames: 3600,
+            memory_budget: 64 * 1024 * 1024,
+            keyframe_interval: 60,
+        }
+    }
+}
+
+// A full snapshot and the frames that came right after it,
+// stored as XOR deltas against it
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+/// History of save states, newest last.
+pub struct RewindBuffer {
+    config: RewindConfig,
+    groups: VecDeque<Group>,
+    frames: usize,
+    used: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        RewindBuffer {
+            config: config,
+            groups: VecDeque::new(),
+            frames: 0,
+            used: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.frames = 0;
+        self.used = 0;
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        let needs_keyframe = match self.groups.back() {
+            Some(group) => group.deltas.len() + 1 >= self.config.keyframe_interval
+                || group.keyframe.len() != state.len(),
+            None => true
+        };
+
+        if needs_keyframe {
+            self.used += state.len();
+            self.groups.push_back(Group { keyframe: state, deltas: Vec::new() });
+        } else {
+            let group = self.groups.back_mut().unwrap();
+            let delta = encode_delta(&group.keyframe, &state);
+            self.used += delta.len();
+            group.deltas.push(delta);
+        }
+        self.frames += 1;
+
+        // Deltas are useless without their keyframe, so history is dropped a group at a time.
+        // The newest group is always kept, even if it alone goes over budget.
+        while self.groups.len() > 1
+            && (self.used > self.config.memory_budget || self.frames - self.groups[0].deltas.len() - 1 >= self.config.max_frames) {
+            let oldest = self.groups.pop_front().unwrap();
+            self.used -= oldest.size();
+            self.frames -= oldest.deltas.len() + 1;
+        }
+    }
+
+    /// Takes the newest state out of the history.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = {
+            let group = match self.groups.back_mut() {
+                Some(group) => group,
+                None => return None
+            };
+            match group.deltas.pop() {
+                Some(delta) => {
+                    self.used -= delta.len();
+                    Some(decode_delta(&group.keyframe, &delta))
+                }
+                None => None
+            }
+        };
+        self.frames -= 1;
+        match state {
+            Some(state) => Some(state),
+            None => {
+                let group = self.groups.pop_back().unwrap();
+                self.used -= group.keyframe.len();
+                Some(group.keyframe)
+            }
+        }
+    }
+}
+
+// Deltas are the XOR of the state against the keyframe, run length encoded:
+// a series of (zero run length, literal length, literal bytes), lengths as LEB128.
+// Consecutive frames share most of their state, so the XOR is mostly zeros.
+fn encode_delta(base: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let zeros_start = i;
+        while i < state.len() && base[i] == state[i] {
+            i += 1;
+        }
+        let literal_start = i;
+        // A single matching byte is cheaper to store as a literal than to start a new run
+        while i < state.len() && (base[i] != state[i] || (i + 1 < state.len() && base[i + 1] != state[i + 1])) {
+            i += 1;
+        }
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, i - literal_start);
+        for j in literal_start..i {
+            out.push(base[j] ^ state[j]);
+        }
+    }
+    out
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = base.to_vec();
+    let mut pos = 0;
+    let mut i = 0;
+    while i < delta.len() {
+        pos += read_varint(delta, &mut i);
+        let literals = read_varint(delta, &mut i);
+        for _ in 0..literals {
+            state[pos] ^= delta[i];
+            pos += 1;
+            i += 1;
+        }
+    }
+    state
+}
+
+fn write_varint(out: &mut Vec<u8>, val: usize) {
+    let mut val = val;
+    while val >= 0x80 {
+        out.push((val as u8 & 0x7F) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_delta;
+    use super::encode_delta;
+    use super::RewindBuffer;
+    use super::RewindConfig;
+
+    // A state whose bytes depend on the frame in a few places, like RAM would
+    fn state(frame: usize) -> Vec<u8> {
+        let mut state: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        state[0] = frame as u8;
+        state[500] = (frame >> 8) as u8;
+        state[998] ^= frame as u8;
+        state
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let base = state(0);
+        let mut far = base.clone();
+        far[1] ^= 0xFF;
+        far[3] ^= 0x01;
+        for byte in far[200..400].iter_mut() {
+            *byte = !*byte;
+        }
+        let cases = vec![base.clone(), state(1), state(0x1234), far, vec![0; 1000]];
+        for case in cases {
+            let delta = encode_delta(&base, &case);
+            assert_eq!(decode_delta(&base, &delta), case);
+        }
+        // A frame that changed nothing is one run of 1000 zeros
+        assert_eq!(encode_delta(&base, &base), vec![0xE8, 0x07, 0x00]);
+        // Two one byte changes: (0, 1, xor), (997 as two varint bytes, 1, xor) and the
+        // (1, 0) of the matching byte at the end
+        assert_eq!(encode_delta(&base, &state(1)).len(), 9);
+    }
+
+    #[test]
+    fn states_come_back_newest_first() {
+        let mut history = RewindBuffer::new(RewindConfig { keyframe_interval: 4, ..RewindConfig::default() });
+        for frame in 0..10 {
+            history.push(state(frame));
+        }
+        assert_eq!(history.len(), 10);
+        for frame in (0..10).rev() {
+            assert_eq!(history.pop(), Some(state(frame)));
+        }
+        assert!(history.is_empty());
+        assert_eq!(history.memory_used(), 0);
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn old_groups_go_past_max_frames() {
+        let mut history = RewindBuffer::new(RewindConfig { max_frames: 10, keyframe_interval: 4, ..RewindConfig::default() });
+        for frame in 0..30 {
+            history.push(state(frame));
+            // A group only goes when the ones after it still hold max_frames
+            assert!(history.len() < 10 + 4, "{} frames", history.len());
+        }
+        assert_eq!(history.len(), 10);
+        let mut oldest = None;
+        while let Some(state) = history.pop() {
+            oldest = Some(state);
+        }
+        assert_eq!(oldest, Some(state(20)));
+    }
+
+    #[test]
+    fn old_groups_go_past_the_budget() {
+        let mut history = RewindBuffer::new(RewindConfig { memory_budget: 2500, keyframe_interval: 2, ..RewindConfig::default() });
+        for frame in 0..20 {
+            history.push(state(frame));
+            assert!(history.memory_used() <= 2500, "{} bytes", history.memory_used());
+        }
+        assert_eq!(history.len(), 4);
+        // The newest group stays even when it alone is over budget
+        let mut tiny = RewindBuffer::new(RewindConfig { memory_budget: 10, ..RewindConfig::default() });
+        tiny.push(state(0));
+        tiny.push(state(1));
+        assert_eq!(tiny.len(), 2);
+        assert_eq!(tiny.pop(), Some(state(1)));
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use hardware::cpu::CLOCK_SPEED;
+use hardware::cpu::CYCLES_PER_FRAME;
+
+pub const MIN_SPEED           : f64 = 0.125;
+pub const MAX_SPEED           : f64 = 16.0;
+pub const FAST_FORWARD_SPEED  : f64 = 4.0;
+
+// If the host falls further behind than this the missed frames are dropped
+// instead of being run in one burst
+const MAX_FRAMES_BEHIND : f64 = 4.0;
+
+/// Frames per second of the real hardware, about 59.73
+pub fn frame_rate() -> f64 {
+    CLOCK_SPEED as f64 / CYCLES_PER_FRAME as f64
+}
+
+/// Decides how many frames to emulate so that the emulation follows the
+/// wall clock, independently of how often the frontend asks.
+pub struct FrameScheduler {
+    speed: f64,
+    fast_forward: bool,
+    paused: bool,
+    pending_steps: u32,
+    last_tick: Option<Instant>,
+    // Frames owed to the emulation, carried over between ticks
+    owed: f64,
+}
+
+impl FrameScheduler {
+    pub fn new() -> Self {
+        FrameScheduler {
+            speed: 1.0,
+            fast_forward: false,
+            paused: false,
+            pending_steps: 0,
+            last_tick: None,
+            owed: 0.0,
+        }
+    }
+
+    /// Speed multiplier in use, 1.0 is real time.
+    pub fn speed(&self) -> f64 {
+        if self.fast_forward {FAST_FORWARD_SPEED} else {self.speed}
+    }
+
+    /// Sets the multiplier, below 1.0 is slow motion.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+    }
+
+    /// Runs at `FAST_FORWARD_SPEED` while on, ignoring the normal speed.
+    pub fn set_fast_forward(&mut self, on: bool) {
+        self.fast_forward = on;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        let paused = !self.paused;
+        self.set_paused(paused);
+    }
+
+    /// Pauses and runs exactly one frame on the next tick.
+    pub fn advance_frame(&mut self) {
+        self.paused = true;
+        self.pending_steps += 1;
+    }
+
+    /// Number of frames to emulate now to keep up with the clock.
+    pub fn tick(&mut self, now: Instant) -> u32 {
+        let elapsed = match self.last_tick {
+            Some(last) => now.duration_since(last),
+            None => Duration::from_secs(0)
+        };
+        self.last_tick = Some(now);
+
+        if self.paused {
+            self.owed = 0.0;
+            let steps = self.pending_steps;
+            self.pending_steps = 0;
+            return steps;
+        }
+
+        let speed = self.speed();
+        self.owed += seconds(elapsed) * frame_rate() * speed;
+        let max_owed = MAX_FRAMES_BEHIND * speed.max(1.0);
+        if self.owed > max_owed {
+            self.owed = max_owed;
+        }
+        let frames = self.owed.floor();
+        self.owed -= frames;
+        frames as u32
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use super::frame_rate;
+    use super::FrameScheduler;
+    use super::FAST_FORWARD_SPEED;
+    use super::MAX_SPEED;
+    use super::MIN_SPEED;
+
+    // Ticks every millisecond for `seconds` and adds up the frames
+    fn run_for(scheduler: &mut FrameScheduler, start: Instant, seconds: u64) -> u32 {
+        (1..seconds * 1000 + 1).map(|ms| scheduler.tick(start + Duration::from_millis(ms))).sum()
+    }
+
+    #[test]
+    fn real_time_follows_the_hardware_frame_rate() {
+        assert!((frame_rate() - 59.7275).abs() < 0.001);
+        let mut scheduler = FrameScheduler::new();
+        let start = Instant::now();
+        assert_eq!(scheduler.tick(start), 0);
+        assert_eq!(run_for(&mut scheduler, start, 10), 597);
+    }
+
+    #[test]
+    fn speed_and_fast_forward_scale_the_frames() {
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new();
+        scheduler.set_speed(0.5);
+        scheduler.tick(start);
+        assert_eq!(run_for(&mut scheduler, start, 10), 298);
+
+        let mut scheduler = FrameScheduler::new();
+        scheduler.set_speed(0.5);
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.speed(), FAST_FORWARD_SPEED);
+        scheduler.tick(start);
+        assert_eq!(run_for(&mut scheduler, start, 10), 2389);
+        scheduler.set_fast_forward(false);
+        assert_eq!(scheduler.speed(), 0.5);
+
+        scheduler.set_speed(100.0);
+        assert_eq!(scheduler.speed(), MAX_SPEED);
+        scheduler.set_speed(0.0);
+        assert_eq!(scheduler.speed(), MIN_SPEED);
+    }
+
+    #[test]
+    fn a_stalled_host_drops_frames() {
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new();
+        scheduler.tick(start);
+        assert_eq!(scheduler.tick(start + Duration::from_secs(2)), 4);
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.tick(start + Duration::from_secs(4)), 16);
+    }
+
+    #[test]
+    fn pause_and_frame_advance() {
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new();
+        scheduler.tick(start);
+        scheduler.toggle_pause();
+        assert!(scheduler.is_paused());
+        assert_eq!(run_for(&mut scheduler, start, 1), 0);
+
+        scheduler.advance_frame();
+        scheduler.advance_frame();
+        assert_eq!(scheduler.tick(start + Duration::from_millis(1001)), 2);
+        assert_eq!(scheduler.tick(start + Duration::from_millis(1002)), 0);
+        assert!(scheduler.is_paused());
+
+        // Advancing pauses a running game, and the time spent paused isn't owed after
+        scheduler.set_paused(false);
+        scheduler.advance_frame();
+        assert!(scheduler.is_paused());
+        assert_eq!(scheduler.tick(start + Duration::from_secs(5)), 1);
+        scheduler.toggle_pause();
+        assert_eq!(scheduler.tick(start + Duration::from_millis(5010)), 0);
+        assert_eq!(scheduler.tick(start + Duration::from_millis(5030)), 1);
+    }
+}
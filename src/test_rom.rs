@@ -0,0 +1,33 @@
+// Cartridges built on the fly for the tests, the repository ships no ROMs
+
+use gameboy::GameBoy;
+
+pub const PROGRAM_START : u16 = 0x0150;
+
+// Keeps changing the first tile, which fills the background, so every frame differs
+pub const ANIMATION : &'static [u8] = &[
+    0x21, 0x00, 0x80,   // LD HL,0x8000
+    0x34,               // INC (HL)
+    0x2C,               // INC L
+    0x18, 0xFC,         // JR -4
+];
+
+/// A 32KB ROM only cartridge that jumps to `program` at PROGRAM_START.
+/// `game_id` goes in the global checksum, which tells games apart.
+pub fn rom(program: &[u8], game_id: u16) -> Box<[u8]> {
+    let mut rom = vec![0u8; 0x8000];
+    // JP PROGRAM_START
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, PROGRAM_START as u8, (PROGRAM_START >> 8) as u8]);
+    let start = PROGRAM_START as usize;
+    rom[start..start + program.len()].copy_from_slice(program);
+    // Header checksum of an empty header
+    rom[0x014D] = 0xE7;
+    rom[0x014E] = (game_id >> 8) as u8;
+    rom[0x014F] = game_id as u8;
+    rom.into_boxed_slice()
+}
+
+/// A machine past the boot ROM, about to run `program`.
+pub fn gameboy(program: &[u8]) -> GameBoy {
+    GameBoy::new(None, &rom(program, 0)).unwrap()
+}